@@ -1,45 +1,278 @@
 use base64::{engine::general_purpose::STANDARD, Engine};
 use image::imageops::FilterType;
-use image::{DynamicImage, GenericImageView, ImageBuffer, Rgba};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb, RgbImage, Rgba, RgbaImage};
 use psd::Psd;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
 use std::hash::{Hash, Hasher};
-use std::io::{Cursor, Write};
-use std::panic;
+use std::io::{Cursor, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tauri::{Manager, State};
 
 const JSON_FOLDER_BASE_PATH: &str = r"G:\共有ドライブ\CLLENN\編集部フォルダ\編集企画部\編集企画_C班(AT業務推進)\DTP制作部\JSONフォルダ";
 const JSON_ACCESS_LOG_BASE_PATH: &str =
     r"G:\共有ドライブ\CLLENN\編集部フォルダ\編集企画部\編集企画_C班(AT業務推進)\DTP制作部\JSON_Log";
 
+// ============== エラー型 ==============
+
+// コマンドがフロントエンドへ返すエラー。{ code, message } としてシリアライズされる
+// code: "io" | "decode" | "unsupported_format" | "encode" | "not_found" | "size_mismatch" | "timeout"
+//       | "invalid_crop" | "empty_image" | "truncated" | "other"
+// size_mismatch は sizeA / sizeB ([幅, 高さ])、truncated は bytesRead / expected も含む
+#[derive(Debug)]
+enum AppError {
+    Io(String),          // ファイルの読み書き失敗・存在しない等
+    Decode(String),      // 画像 / PSD / PDF のデコード失敗
+    Unsupported(String), // 未対応の形式・設定
+    Encode(String),      // プレビュー画像の書き出し失敗
+    NotFound(String),    // 指定したレイヤー等が存在しない
+    SizeMismatch {
+        // mismatch_policy = "error" で2画像のサイズが異なる
+        message: String,
+        size_a: (u32, u32),
+        size_b: (u32, u32),
+    },
+    Timeout(String), // デコードが制限時間内に終わらなかった（ファイル破損の可能性）
+    InvalidCrop(String), // クロップ範囲が空・反転・画像外
+    EmptyImage(String), // デコード結果の幅または高さが 0（破損・0バイトのファイル等）
+    Truncated {
+        // ファイルが途中までしかない（コピー中など。時間をおいて再試行できる）
+        message: String,
+        bytes_read: u64,
+        expected: u64,
+    },
+    Other(String),
+}
+
+impl AppError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::Decode(_) => "decode",
+            Self::Unsupported(_) => "unsupported_format",
+            Self::Encode(_) => "encode",
+            Self::NotFound(_) => "not_found",
+            Self::SizeMismatch { .. } => "size_mismatch",
+            Self::Timeout(_) => "timeout",
+            Self::InvalidCrop(_) => "invalid_crop",
+            Self::EmptyImage(_) => "empty_image",
+            Self::Truncated { .. } => "truncated",
+            Self::Other(_) => "other",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Self::Io(m)
+            | Self::Decode(m)
+            | Self::Unsupported(m)
+            | Self::Encode(m)
+            | Self::NotFound(m)
+            | Self::SizeMismatch { message: m, .. }
+            | Self::Timeout(m)
+            | Self::InvalidCrop(m)
+            | Self::EmptyImage(m)
+            | Self::Truncated { message: m, .. }
+            | Self::Other(m) => m,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl Serialize for AppError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let len = match self {
+            Self::SizeMismatch { .. } | Self::Truncated { .. } => 4,
+            _ => 2,
+        };
+        let mut s = serializer.serialize_struct("AppError", len)?;
+        s.serialize_field("code", self.code())?;
+        s.serialize_field("message", self.message())?;
+        match self {
+            Self::SizeMismatch { size_a, size_b, .. } => {
+                s.serialize_field("sizeA", &[size_a.0, size_a.1])?;
+                s.serialize_field("sizeB", &[size_b.0, size_b.1])?;
+            }
+            Self::Truncated {
+                bytes_read,
+                expected,
+                ..
+            } => {
+                s.serialize_field("bytesRead", bytes_read)?;
+                s.serialize_field("expected", expected)?;
+            }
+            _ => {}
+        }
+        s.end()
+    }
+}
+
+// 内部ヘルパーの String エラーは分類なしとして扱う
+impl From<String> for AppError {
+    fn from(message: String) -> Self {
+        Self::Other(message)
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e.to_string())
+    }
+}
+
+impl AppError {
+    // image crate のエラーを種別ごとに振り分ける（context はメッセージの接頭辞）
+    fn from_image(context: &str, e: image::ImageError) -> Self {
+        let message = format!("{}: {}", context, e);
+        match e {
+            image::ImageError::IoError(_) => Self::Io(message),
+            image::ImageError::Unsupported(_) => Self::Unsupported(message),
+            image::ImageError::Encoding(_) => Self::Encode(message),
+            _ => Self::Decode(message),
+        }
+    }
+}
+
+// psd crate によるデコードの制限時間（秒）。set_psd_decode_timeout で変更
+const DEFAULT_PSD_DECODE_TIMEOUT_SECS: u64 = 30;
+static PSD_DECODE_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_PSD_DECODE_TIMEOUT_SECS);
+// 制限時間を過ぎても終わっていない psd crate のスレッド数（止められないので、これ以上は新たに起動しない）
+const MAX_STRANDED_PSD_THREADS: usize = 2;
+static STRANDED_PSD_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+// 書き出す PNG に sRGB チャンクを入れるか（既定 true。set_embed_srgb で変更・保存）
+// 入れないとカラーマネジメントする閲覧ソフトがモニタープロファイル等で解釈し直し、差分の色がずれて見える
+static EMBED_SRGB: AtomicBool = AtomicBool::new(true);
+
+// 先読み（バックグラウンド）のデコード・リサイズ専用のrayonプール（既定は最大4スレッド。set_decode_threads で変更）
+// 全コアで巨大PSDを同時にデコードするとメモリ帯域を食い合い、かえって遅くなる / OOM になるため
+// 差分コマンドは State を持たない経路（非同期版・レポート生成）からも呼ばれるので、操作用プールと共にグローバルに置く
+const DEFAULT_DECODE_THREADS: usize = 4;
+static DECODE_POOL: Mutex<Option<Arc<rayon::ThreadPool>>> = Mutex::new(None);
+
+fn build_decode_pool(threads: usize) -> Result<rayon::ThreadPool, String> {
+    build_named_pool("kenban-decode", threads)
+}
+
+fn build_named_pool(name: &'static str, threads: usize) -> Result<rayon::ThreadPool, String> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.max(1))
+        .thread_name(move |i| format!("{}-{}", name, i))
+        .build()
+        .map_err(|e| format!("Failed to create thread pool: {}", e))
+}
+
+fn default_decode_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(DEFAULT_DECODE_THREADS)
+}
+
+fn decode_pool() -> Arc<rayon::ThreadPool> {
+    let mut pool = DECODE_POOL.lock().unwrap_or_else(|e| e.into_inner());
+    pool.get_or_insert_with(|| {
+        // 作成に失敗したら1スレッドで再試行
+        Arc::new(
+            build_decode_pool(default_decode_threads())
+                .or_else(|_| build_decode_pool(1))
+                .expect("failed to create decode thread pool"),
+        )
+    })
+    .clone()
+}
+
+// ユーザー操作による差分のデコード専用プール（2スレッド = 比較ペアの A / B を1本ずつ）
+// 先読み（decode_pool）が詰まっていても、その後ろに並ばずにすぐデコードを始められるよう分けている
+// スレッドの割り当て: 先読み = decode_pool（既定最大4）、操作中の差分のデコード = このプール（2）、
+// デコード後の画素単位の処理（差分・リサイズ等の par_iter）= rayon のグローバルプール（全コア）
+const INTERACTIVE_DECODE_THREADS: usize = 2;
+static INTERACTIVE_POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+
+fn interactive_pool() -> &'static rayon::ThreadPool {
+    INTERACTIVE_POOL.get_or_init(|| {
+        build_named_pool("kenban-interactive", INTERACTIVE_DECODE_THREADS)
+            .or_else(|_| build_named_pool("kenban-interactive", 1))
+            .expect("failed to create interactive thread pool")
+    })
+}
+
+// 2ファイルを pool で並列に読み込む（ユーザー操作 = interactive_pool()、一括チェック = batch_pool()）
+// a / b にはデコードの呼び出しだけを渡す。後続の画素単位の処理はプールの外（グローバルプール）で行う
+fn decode_pair<RA, RB>(
+    pool: &rayon::ThreadPool,
+    a: impl FnOnce() -> RA + Send,
+    b: impl FnOnce() -> RB + Send,
+) -> (RA, RB)
+where
+    RA: Send,
+    RB: Send,
+{
+    pool.install(|| rayon::join(a, b))
+}
+
 // ============== 画像キャッシュ ==============
+const DEFAULT_CACHE_MAX_BYTES: u64 = 512 * 1024 * 1024; // 既定のメモリ上限 512MB
+const DEFAULT_CACHE_MAX_ENTRIES: usize = 100; // 既定の最大件数（set_cache_max_entries で変更・保存）
+
+#[derive(Clone)]
 struct CachedImage {
     file_path: String, // temp JPEG ファイルパス
     width: u32,
     height: u32,
     original_width: u32,
     original_height: u32,
+    byte_size: u64, // tempファイルのバイト数（メモリ上限の計算用）
+    // JPEG にする前のプレビュー。小さいサイズはこれを縮小して作る（同じファイルで最大のエントリだけが持つ）
+    bitmap: Option<Arc<DynamicImage>>,
+}
+
+impl CachedImage {
+    // メモリ上限の計算に使うバイト数（保持しているビットマップ分を含む）
+    fn memory_bytes(&self) -> u64 {
+        self.byte_size
+            + self
+                .bitmap
+                .as_ref()
+                .map_or(0, |b| b.as_bytes().len() as u64)
+    }
 }
 
 struct ImageCache {
     cache: HashMap<String, CachedImage>,
     order: VecDeque<String>,
+    largest: HashMap<String, String>, // ファイル（versioned_path_key）→ 最大サイズのエントリのキー
     max_size: usize,
+    max_bytes: u64,
+    total_bytes: u64,
+}
+
+// キャッシュキー "versioned_path_key:WxH" からファイル部分を取り出す
+fn cache_key_source(key: &str) -> &str {
+    key.rsplit_once(':').map_or(key, |(source, _)| source)
 }
 
 impl ImageCache {
-    fn new(max_size: usize) -> Self {
+    fn new(max_size: usize, max_bytes: u64) -> Self {
         Self {
             cache: HashMap::new(),
             order: VecDeque::new(),
+            largest: HashMap::new(),
             max_size,
+            max_bytes,
+            total_bytes: 0,
         }
     }
 
@@ -47,37 +280,359 @@ impl ImageCache {
         self.cache.get(key)
     }
 
-    fn insert(&mut self, key: String, image: CachedImage) {
-        // LRUキャッシュ: 古いものを削除
-        if self.cache.len() >= self.max_size {
-            if let Some(oldest) = self.order.pop_front() {
-                self.cache.remove(&oldest);
+    /// 同じファイル（versioned_path_key）で最もサイズの大きいエントリ
+    fn largest_for(&self, source: &str) -> Option<&CachedImage> {
+        self.largest.get(source).and_then(|key| self.cache.get(key))
+    }
+
+    fn remove_entry(&mut self, key: &str) {
+        if let Some(old) = self.cache.remove(key) {
+            self.total_bytes -= old.memory_bytes();
+            let source = cache_key_source(key);
+            if self.largest.get(source).is_some_and(|k| k == key) {
+                self.largest.remove(source);
+            }
+        }
+    }
+
+    fn drop_largest_bitmap(&mut self, source: &str) {
+        let Some(current) = self.largest.get(source).and_then(|k| self.cache.get_mut(k)) else {
+            return;
+        };
+        if let Some(bitmap) = current.bitmap.take() {
+            self.total_bytes -= bitmap.as_bytes().len() as u64;
+        }
+    }
+
+    fn insert(&mut self, key: String, mut image: CachedImage) {
+        // 同じキーの再登録は古いエントリを外してから数え直す
+        if self.cache.contains_key(&key) {
+            self.remove_entry(&key);
+            self.order.retain(|k| k != &key);
+        }
+        let source = cache_key_source(&key).to_string();
+        let is_largest = match self.largest_for(&source) {
+            Some(current) => {
+                image.width as u64 * image.height as u64
+                    > current.width as u64 * current.height as u64
             }
+            None => true,
+        };
+        // ビットマップは最大のエントリにだけ残す（縮小の元になるのはそれだけ）
+        if is_largest {
+            self.drop_largest_bitmap(&source);
+        } else {
+            image.bitmap = None;
+        }
+        // LRUキャッシュ: 新しい画像が収まるまで古いものから削除（大きな画像なら複数件）
+        self.evict_until(
+            self.max_size.saturating_sub(1),
+            self.max_bytes.saturating_sub(image.memory_bytes()),
+        );
+        self.total_bytes += image.memory_bytes();
+        if is_largest || !self.largest.contains_key(&source) {
+            self.largest.insert(source, key.clone());
         }
         self.order.push_back(key.clone());
         self.cache.insert(key, image);
     }
 
+    /// 件数・バイト数が指定値以下になるまで古い順に削除
+    fn evict_until(&mut self, max_entries: usize, max_bytes: u64) {
+        while self.cache.len() > max_entries || self.total_bytes > max_bytes {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.remove_entry(&oldest);
+        }
+    }
+
+    fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+        self.evict_until(self.max_size, self.max_bytes);
+    }
+
+    // 件数上限の変更（バイト上限はそのまま。両方を満たすまで古い順に削除）
+    fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_size = max_entries;
+        self.evict_until(self.max_size, self.max_bytes);
+    }
+
     fn clear(&mut self) {
         self.cache.clear();
         self.order.clear();
+        self.largest.clear();
+        self.total_bytes = 0;
     }
 }
 
-// グローバルキャッシュ（Mutexで保護）
+// グローバルキャッシュ（RwLockで保護）
+// キャッシュヒットは読み取りロックのみ。ファイル確認・デコード等は必ずロックの外で行い、
+// 書き込みロックは insert / 削除の間だけ持つ（先読みの一括登録中もヒットが止まらないように）
 struct AppState {
-    image_cache: Mutex<ImageCache>,
+    image_cache: Arc<RwLock<ImageCache>>,
+    disk_cache_enabled: AtomicBool, // 永続ディスクキャッシュの有効/無効
+    preload_cancel: Arc<AtomicU64>, // cancel_preload のたびに進める世代番号（CancelToken）
+    preload_in_flight: Arc<InFlightSet>, // preload_images がデコード中のキャッシュキー
+    image_chunks: ChunkStore,       // open_image_chunks で読み込んだ分割転送用のバイト列
+    settings: Mutex<AppSettings>,
     cli_args: Vec<String>,
 }
 
+// 先読み・一括チェックのキャンセル判定。呼び出し開始時の世代番号を控え、その後 cancel_preload されたら中断する
+// 共有フラグを呼び出しのたびに下ろす方式だと、直前のキャンセルや実行中の別の呼び出しへのキャンセルを打ち消してしまう
+#[derive(Clone)]
+struct CancelToken {
+    generation: Arc<AtomicU64>,
+    start: u64,
+}
+
+impl CancelToken {
+    fn new(generation: &Arc<AtomicU64>) -> Self {
+        Self {
+            generation: generation.clone(),
+            start: generation.load(Ordering::Relaxed),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.generation.load(Ordering::Relaxed) != self.start
+    }
+}
+
+// 実行中のキャッシュキーの集合。同時に呼ばれた preload_images が同じ画像を重複デコードしないようにする
+#[derive(Default)]
+struct InFlightSet {
+    keys: Mutex<HashSet<String>>,
+    done: Condvar,
+}
+
+// 先読みの完了待ちの上限（相手の呼び出しが戻らなくても永久には待たない）
+const PRELOAD_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+
+impl InFlightSet {
+    // (パス, キー) のうち未登録のものを登録して自分の担当にし、他の呼び出しが処理中のものを返す
+    fn claim(&self, items: Vec<(String, String)>) -> (InFlightClaim<'_>, Vec<(String, String)>) {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        let (mine, busy) = items
+            .into_iter()
+            .partition(|(_, key)| keys.insert(key.clone()));
+        (
+            InFlightClaim {
+                set: self,
+                items: mine,
+            },
+            busy,
+        )
+    }
+
+    // 指定キーがすべて処理済みになるまで待つ
+    fn wait_for(&self, keys: &[String]) {
+        let guard = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = self
+            .done
+            .wait_timeout_while(guard, PRELOAD_WAIT_TIMEOUT, |busy| {
+                keys.iter().any(|k| busy.contains(k))
+            });
+    }
+}
+
+// claim したキーを drop 時に解放する（エラー・パニックで抜けても待っている呼び出しを起こす）
+struct InFlightClaim<'a> {
+    set: &'a InFlightSet,
+    items: Vec<(String, String)>, // (パス, キャッシュキー)
+}
+
+impl Drop for InFlightClaim<'_> {
+    fn drop(&mut self) {
+        let mut keys = self.set.keys.lock().unwrap_or_else(|e| e.into_inner());
+        for (_, key) in &self.items {
+            keys.remove(key);
+        }
+        self.set.done.notify_all();
+    }
+}
+
+// 分割転送中のバイト列（ハンドル → 中身）。release されないまま溜まった分は古い順に捨てる
+#[derive(Default)]
+struct ChunkStore {
+    next_handle: AtomicU64,
+    buffers: Mutex<HashMap<u64, Arc<Vec<u8>>>>,
+}
+
+// 同時に保持するハンドル数の上限
+const MAX_CHUNK_HANDLES: usize = 16;
+
+// get_image_chunk 1回で返す最大バイト数
+const MAX_CHUNK_LEN: u32 = 8 * 1024 * 1024;
+
+// ============== 設定ファイル ==============
+
+// 再起動後も保持するユーザー設定（config_dir/KENBAN/settings.json）
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct AppSettings {
+    mojiq_path: Option<String>,       // MojiQ.exe の手動指定パス
+    cache_max_entries: Option<usize>, // メモリキャッシュの最大件数（未設定なら既定の100件）
+    embed_srgb: Option<bool>,         // PNG に sRGB チャンクを入れるか（未設定なら true）
+}
+
+// config_dir/KENBAN/<name>（フォルダがなければ作成）
+fn get_config_file_path(name: &str) -> Result<PathBuf, String> {
+    let dir = dirs::config_dir()
+        .ok_or_else(|| "Failed to get config dir".to_string())?
+        .join("KENBAN");
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create config dir: {}", e))?;
+    Ok(dir.join(name))
+}
+
+fn get_settings_path() -> Result<PathBuf, String> {
+    get_config_file_path("settings.json")
+}
+
+fn load_settings() -> AppSettings {
+    get_settings_path()
+        .ok()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save_settings(settings: &AppSettings) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(settings)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+    write_file_atomic(&get_settings_path()?, &json)
+}
+
+// 作業状態（開いていたフォルダ・比較ペア・しきい値等）を保存。内容はフロントエンドが決める任意の JSON
+// 書き込み途中で落ちても前回の状態が壊れないようアトミックに書く
+#[tauri::command]
+fn save_session_state(json: serde_json::Value) -> Result<(), AppError> {
+    let data = serde_json::to_vec_pretty(&json)
+        .map_err(|e| AppError::Encode(format!("Failed to serialize session: {}", e)))?;
+    let path = get_config_file_path("session.json").map_err(AppError::Io)?;
+    write_file_atomic(&path, &data).map_err(AppError::Io)
+}
+
+// 保存した作業状態を読み込む（未保存なら None）
+#[tauri::command]
+fn load_session_state() -> Result<Option<serde_json::Value>, AppError> {
+    let path = get_config_file_path("session.json").map_err(AppError::Io)?;
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(AppError::Io(format!("Failed to read session: {}", e))),
+    };
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| AppError::Decode(format!("Failed to parse session: {}", e)))
+}
+
+// アトミック書き込み（一時ファイル→リネーム）
+fn write_file_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp_path = path.with_file_name(format!("{}.tmp", file_name));
+    fs::write(&tmp_path, data).map_err(|e| format!("Failed to write file: {}", e))?;
+    fs::rename(&tmp_path, path).map_err(|e| format!("Failed to rename file: {}", e))
+}
+
 // ============== tempファイルヘルパー ==============
 
 /// キャッシュキーからハッシュベースのファイル名を生成
 fn cache_key_to_filename(cache_key: &str) -> String {
+    cache_key_to_filename_with_ext(cache_key, "jpg")
+}
+
+// sRGB チャンクの有無で PNG のファイルを分ける（設定を切り替えたとき古い方を再利用しない）
+fn png_variant_key(cache_key: &str) -> String {
+    if EMBED_SRGB.load(Ordering::Relaxed) {
+        cache_key.to_string()
+    } else {
+        format!("{}_nosrgb", cache_key)
+    }
+}
+
+// 8bit のバッファを PNG で書き出す（EMBED_SRGB なら sRGB チャンク付き）
+fn write_png<W: Write>(
+    w: W,
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: image::ExtendedColorType,
+) -> Result<(), String> {
+    let color_type = match color {
+        image::ExtendedColorType::L8 => png::ColorType::Grayscale,
+        image::ExtendedColorType::La8 => png::ColorType::GrayscaleAlpha,
+        image::ExtendedColorType::Rgb8 => png::ColorType::Rgb,
+        image::ExtendedColorType::Rgba8 => png::ColorType::Rgba,
+        other => return Err(format!("Unsupported PNG color type: {:?}", other)),
+    };
+    let mut encoder = png::Encoder::new(w, width, height);
+    encoder.set_color(color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    if EMBED_SRGB.load(Ordering::Relaxed) {
+        encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    }
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| format!("PNG encode error: {}", e))?;
+    writer
+        .write_image_data(buf)
+        .map_err(|e| format!("PNG encode error: {}", e))?;
+    writer
+        .finish()
+        .map_err(|e| format!("PNG encode error: {}", e))
+}
+
+fn cache_key_to_filename_with_ext(cache_key: &str, ext: &str) -> String {
     let mut hasher = DefaultHasher::new();
     cache_key.hash(&mut hasher);
     let hash = hasher.finish();
-    format!("kenban_preview_{:016x}.jpg", hash)
+    format!("kenban_preview_{:016x}.{}", hash, ext)
+}
+
+// プレビュー JPEG の既定品質
+const PREVIEW_JPEG_QUALITY: u8 = 85;
+
+// プレビュー画像の出力形式
+#[derive(Clone, Copy, PartialEq)]
+enum PreviewFormat {
+    Jpeg, // 非可逆 85%（写真向け・既定）
+    Webp, // 可逆（文字の多いページ向け）
+    Png,  // 可逆
+}
+
+impl PreviewFormat {
+    fn parse(format: Option<&str>) -> Result<Self, String> {
+        match format.map(|f| f.to_lowercase()).as_deref() {
+            None | Some("jpeg") | Some("jpg") => Ok(Self::Jpeg),
+            Some("webp") => Ok(Self::Webp),
+            Some("png") => Ok(Self::Png),
+            Some(other) => Err(format!(
+                "未対応の出力形式です: {} (jpeg / webp / png)",
+                other
+            )),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+            Self::Png => "png",
+        }
+    }
+
+    fn mime_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+            Self::Png => "image/png",
+        }
+    }
 }
 
 fn versioned_path_key(path: &str) -> String {
@@ -96,6 +651,48 @@ fn versioned_path_key(path: &str) -> String {
     }
 }
 
+// ファイル内容のハッシュ（versioned_path_key → blake3 の16進）。同じ版のファイルは一度だけ読む
+static CONTENT_HASHES: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+const CONTENT_HASHES_MAX: usize = 4096; // 超えたら作り直す（キーはファイルの版ごとに増えるため）
+
+// ファイルのバイト列の blake3 ハッシュ。フロントが差分結果を (hash_a, hash_b, threshold) でキャッシュするための識別子
+// パスや更新日時が変わっても中身が同じなら同じ値になる。読めなければ None
+// ファイル全体を読むので、デコードのために読んだバイト列があれば content_hash_of_bytes を使う
+fn content_hash(path: &str) -> Option<String> {
+    if let Some(hash) = known_content_hash(path) {
+        return Some(hash);
+    }
+    let file = fs::File::open(path).ok()?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(std::io::BufReader::new(file)).ok()?;
+    remember_content_hash(path, hasher.finalize().to_hex().to_string())
+}
+
+// 計算済みのハッシュだけを返す（ファイルは読まない。キャッシュヒット時の応答用）
+fn known_content_hash(path: &str) -> Option<String> {
+    CONTENT_HASHES
+        .lock()
+        .ok()?
+        .as_ref()
+        .and_then(|m| m.get(&versioned_path_key(path)).cloned())
+}
+
+// 読み込み済みのファイル内容からハッシュを求めて記録する
+fn content_hash_of_bytes(path: &str, bytes: &[u8]) -> Option<String> {
+    remember_content_hash(path, blake3::hash(bytes).to_hex().to_string())
+}
+
+fn remember_content_hash(path: &str, hash: String) -> Option<String> {
+    let key = versioned_path_key(path);
+    let mut guard = CONTENT_HASHES.lock().ok()?;
+    let hashes = guard.get_or_insert_with(HashMap::new);
+    if hashes.len() >= CONTENT_HASHES_MAX {
+        hashes.clear();
+    }
+    hashes.insert(key, hash.clone());
+    Some(hash)
+}
+
 /// temp ディレクトリ内の kenban_preview サブフォルダを取得（なければ作成）
 fn get_kenban_temp_dir() -> Result<PathBuf, String> {
     let temp = std::env::temp_dir().join("kenban_preview");
@@ -108,8 +705,23 @@ fn get_kenban_temp_dir() -> Result<PathBuf, String> {
 /// DynamicImage を JPEG 85% で temp ファイルに書き出し、パスを返す
 /// 既にファイルが存在すればスキップ（ディスクキャッシュヒット）
 fn write_image_to_temp(img: &DynamicImage, cache_key: &str) -> Result<(String, u32, u32), String> {
+    write_image_to_temp_as(img, cache_key, PreviewFormat::Jpeg, PREVIEW_JPEG_QUALITY)
+}
+
+/// DynamicImage を指定形式で temp ファイルに書き出し、パスを返す
+/// jpeg_quality は format = Jpeg のときのみ使用（image crate の JPEG は常に 4:4:4 = 色差間引きなし）
+fn write_image_to_temp_as(
+    img: &DynamicImage,
+    cache_key: &str,
+    format: PreviewFormat,
+    jpeg_quality: u8,
+) -> Result<(String, u32, u32), String> {
     let temp_dir = get_kenban_temp_dir()?;
-    let filename = cache_key_to_filename(cache_key);
+    let filename = if format == PreviewFormat::Png {
+        cache_key_to_filename_with_ext(&png_variant_key(cache_key), format.extension())
+    } else {
+        cache_key_to_filename_with_ext(cache_key, format.extension())
+    };
     let file_path = temp_dir.join(&filename);
 
     let (w, h) = img.dimensions();
@@ -119,22 +731,174 @@ fn write_image_to_temp(img: &DynamicImage, cache_key: &str) -> Result<(String, u
         return Ok((file_path.to_string_lossy().to_string(), w, h));
     }
 
-    // RGBA → RGB 変換して JPEG エンコード
-    let rgb_img = DynamicImage::ImageRgb8(img.to_rgb8());
-    let mut jpeg_data = Cursor::new(Vec::new());
-    rgb_img
-        .write_to(&mut jpeg_data, image::ImageFormat::Jpeg)
-        .map_err(|e| format!("Failed to encode JPEG: {}", e))?;
+    let encoded = encode_image_as(img, format, jpeg_quality)?;
 
     // アトミック書き込み（一時ファイル→リネーム）
     let tmp_path = temp_dir.join(format!("{}.tmp", filename));
-    fs::write(&tmp_path, jpeg_data.get_ref())
-        .map_err(|e| format!("Failed to write temp file: {}", e))?;
+    fs::write(&tmp_path, &encoded).map_err(|e| format!("Failed to write temp file: {}", e))?;
     fs::rename(&tmp_path, &file_path).map_err(|e| format!("Failed to rename temp file: {}", e))?;
 
     Ok((file_path.to_string_lossy().to_string(), w, h))
 }
 
+/// DynamicImage を指定形式でメモリ上にエンコードする
+fn encode_image_as(
+    img: &DynamicImage,
+    format: PreviewFormat,
+    jpeg_quality: u8,
+) -> Result<Vec<u8>, String> {
+    let (w, h) = img.dimensions();
+    let mut encoded = Cursor::new(Vec::new());
+    match format {
+        PreviewFormat::Jpeg => {
+            // RGBA → RGB 変換して JPEG エンコード
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(
+                &mut encoded,
+                jpeg_quality.clamp(1, 100),
+            );
+            img.to_rgb8()
+                .write_with_encoder(encoder)
+                .map_err(|e| format!("Failed to encode JPEG: {}", e))?;
+        }
+        PreviewFormat::Webp => {
+            // image crate の WebP エンコーダーは可逆のみ
+            DynamicImage::ImageRgba8(img.to_rgba8())
+                .write_to(&mut encoded, image::ImageFormat::WebP)
+                .map_err(|e| format!("Failed to encode WebP: {}", e))?;
+        }
+        PreviewFormat::Png => {
+            if img.color().has_alpha() {
+                let rgba = img.to_rgba8();
+                write_png(&mut encoded, &rgba, w, h, image::ExtendedColorType::Rgba8)?;
+            } else {
+                let rgb = img.to_rgb8();
+                write_png(&mut encoded, &rgb, w, h, image::ExtendedColorType::Rgb8)?;
+            }
+        }
+    }
+    Ok(encoded.into_inner())
+}
+
+fn file_size(path: &str) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+// コマンドが返した temp ファイルのパスを検証する（temp フォルダ外の任意ファイルは読ませない）
+fn resolve_temp_file(src: &str) -> Result<PathBuf, AppError> {
+    let temp_dir = get_kenban_temp_dir().map_err(AppError::Io)?;
+    let src_path = fs::canonicalize(src)
+        .map_err(|e| AppError::NotFound(format!("ファイルが見つかりません: {} ({})", src, e)))?;
+    let temp_dir = fs::canonicalize(&temp_dir).unwrap_or(temp_dir);
+    if !src_path.starts_with(&temp_dir) {
+        return Err(AppError::Other(format!(
+            "KENBAN の一時ファイルを指定してください: {}",
+            src
+        )));
+    }
+    Ok(src_path)
+}
+
+// ============== 永続ディスクキャッシュ ==============
+// tempのプレビューは cleanup_preview_cache で1時間後に消えるため、
+// アプリ再起動後も使えるようにキャッシュディレクトリへ控えを保存する。
+// assetProtocolのスコープは $TEMP のみなので、ヒット時はtempへ書き戻して返す。
+
+#[derive(Serialize, Deserialize)]
+struct PersistentCacheMeta {
+    source_key: String, // versioned_path_key（サイズ+更新日時）
+    width: u32,
+    height: u32,
+    original_width: u32,
+    original_height: u32,
+}
+
+/// キャッシュディレクトリ内の KENBAN/preview_cache を取得（なければ作成）
+fn get_persistent_cache_dir() -> Result<PathBuf, String> {
+    let dir = dirs::cache_dir()
+        .ok_or_else(|| "Failed to get cache dir".to_string())?
+        .join("KENBAN")
+        .join("preview_cache");
+    if !dir.exists() {
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create cache dir: {}", e))?;
+    }
+    Ok(dir)
+}
+
+/// パス+表示サイズ単位のファイル名（更新日時はメタ側で照合する）
+fn persistent_cache_stem(path: &str, max_width: u32, max_height: u32) -> String {
+    let mut hasher = DefaultHasher::new();
+    format!("{}:{}x{}", path, max_width, max_height).hash(&mut hasher);
+    format!("kenban_persist_{:016x}", hasher.finish())
+}
+
+/// 永続キャッシュから復元。ソースのサイズ/更新日時が変わっていればエントリを破棄する
+fn load_persistent_preview(
+    path: &str,
+    max_width: u32,
+    max_height: u32,
+    cache_key: &str,
+) -> Option<CachedImage> {
+    let dir = get_persistent_cache_dir().ok()?;
+    let stem = persistent_cache_stem(path, max_width, max_height);
+    let meta_path = dir.join(format!("{}.json", stem));
+    let data_path = dir.join(format!("{}.jpg", stem));
+
+    let meta: PersistentCacheMeta = serde_json::from_slice(&fs::read(&meta_path).ok()?).ok()?;
+    if meta.source_key != versioned_path_key(path) || !data_path.exists() {
+        let _ = fs::remove_file(&meta_path);
+        let _ = fs::remove_file(&data_path);
+        return None;
+    }
+
+    // tempへ書き戻し（アトミックにリネーム）
+    let temp_dir = get_kenban_temp_dir().ok()?;
+    let filename = cache_key_to_filename(cache_key);
+    let temp_path = temp_dir.join(&filename);
+    if !temp_path.exists() {
+        let tmp_path = temp_dir.join(format!("{}.tmp", filename));
+        fs::copy(&data_path, &tmp_path).ok()?;
+        fs::rename(&tmp_path, &temp_path).ok()?;
+    }
+
+    let file_path = temp_path.to_string_lossy().to_string();
+    Some(CachedImage {
+        byte_size: file_size(&file_path),
+        file_path,
+        width: meta.width,
+        height: meta.height,
+        original_width: meta.original_width,
+        original_height: meta.original_height,
+        bitmap: None,
+    })
+}
+
+/// 生成したプレビューを永続キャッシュへ保存（失敗しても本処理には影響させない）
+fn store_persistent_preview(path: &str, max_width: u32, max_height: u32, image: &CachedImage) {
+    let Ok(dir) = get_persistent_cache_dir() else {
+        return;
+    };
+    let stem = persistent_cache_stem(path, max_width, max_height);
+    let meta = PersistentCacheMeta {
+        source_key: versioned_path_key(path),
+        width: image.width,
+        height: image.height,
+        original_width: image.original_width,
+        original_height: image.original_height,
+    };
+    let Ok(meta_json) = serde_json::to_vec(&meta) else {
+        return;
+    };
+
+    let data_path = dir.join(format!("{}.jpg", stem));
+    let tmp_path = dir.join(format!("{}.jpg.tmp", stem));
+    if fs::copy(&image.file_path, &tmp_path).is_err() || fs::rename(&tmp_path, &data_path).is_err()
+    {
+        let _ = fs::remove_file(&tmp_path);
+        return;
+    }
+    let _ = fs::write(dir.join(format!("{}.json", stem)), meta_json);
+}
+
 // ============== 画像処理結果 ==============
 #[derive(Serialize)]
 struct ImageResult {
@@ -143,54 +907,430 @@ struct ImageResult {
     height: u32,
     original_width: u32,
     original_height: u32,
+    decoder_used: &'static str, // "image" | "tiff_fallback" | "cache"（デバッグ用）
+    content_hash: Option<String>, // 元ファイルの内容の blake3（差分結果のキャッシュキー用。キャッシュヒットで未計算なら None）
 }
 
 // PSD解析結果
 #[derive(Serialize)]
 struct PsdImageResult {
-    file_url: String, // temp JPEG ファイルパス
+    file_url: String,  // temp 画像ファイルパス
+    mime_type: String, // file_url の形式 (image/jpeg | image/webp | image/png)
     width: u32,
     height: u32,
+    // "fallback": 合成済み画像 (Image Data Section) をそのまま表示 / "psd_crate": psd crate でレイヤー合成
+    // fallback はPSD保存時の合成結果なので、互換性を優先しない保存だと簡易表示になりうる
+    decoder_used: &'static str,
 }
 
-// PSDファイルをパースしてtemp JPEGに書き出し、パスを返す
+// PSDファイルをパースしてtemp画像に書き出し、パスを返す
 // フォールバックパーサー（Image Data Section直接読み取り）を優先し、
 // 失敗時のみpsd crateにフォールオーバー
+// format: "jpeg"（既定）| "webp"（可逆・文字照合向け）| "png"
+// quality: JPEG 品質 1〜100（範囲外は丸める、既定 85）。文字照合では 95 前後で細い文字のにじみを抑えられる
+#[tauri::command]
+fn parse_psd(
+    path: String,
+    format: Option<String>,
+    quality: Option<u8>,
+) -> Result<PsdImageResult, AppError> {
+    let format = PreviewFormat::parse(format.as_deref()).map_err(AppError::Unsupported)?;
+    let quality = quality.unwrap_or(PREVIEW_JPEG_QUALITY).clamp(1, 100);
+    psd_preview_cached(&versioned_path_key(&path), format, quality, || {
+        fs::read(&path).map_err(|e| AppError::Io(format!("Failed to read file: {}", e)))
+    })
+}
+
+// Base64 で受け取ったPSD（Webからのドロップ等）を temp ファイルを経由せずにパースする
+// data_base64: 素の Base64 または data URL（data:...;base64,）。format / quality は parse_psd と同じ
 #[tauri::command]
-fn parse_psd(path: String) -> Result<PsdImageResult, String> {
-    let cache_key = format!("psd_v2:{}", versioned_path_key(&path));
+fn parse_psd_bytes(
+    data_base64: String,
+    format: Option<String>,
+    quality: Option<u8>,
+) -> Result<PsdImageResult, AppError> {
+    let format = PreviewFormat::parse(format.as_deref()).map_err(AppError::Unsupported)?;
+    let quality = quality.unwrap_or(PREVIEW_JPEG_QUALITY).clamp(1, 100);
+    let payload = match data_base64.strip_prefix("data:") {
+        Some(rest) => rest.split_once(',').map_or(rest, |(_, data)| data),
+        None => data_base64.as_str(),
+    };
+    let bytes = STANDARD
+        .decode(payload.trim())
+        .map_err(|e| AppError::Decode(format!("Failed to decode base64: {}", e)))?;
+    drop(data_base64);
 
-    // ディスクキャッシュチェック
-    let temp_dir = get_kenban_temp_dir()?;
-    let filename = cache_key_to_filename(&cache_key);
-    let file_path = temp_dir.join(&filename);
-    if file_path.exists() {
-        let (w, h) = image::image_dimensions(&file_path)
-            .map_err(|e| format!("Failed to read image dimensions: {}", e))?;
-        return Ok(PsdImageResult {
-            file_url: file_path.to_string_lossy().to_string(),
-            width: w,
-            height: h,
-        });
+    // パスの代わりに内容のハッシュでキャッシュする
+    let source_key = {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        format!("bytes:{:016x}:{}", hasher.finish(), bytes.len())
+    };
+    psd_preview_cached(&source_key, format, quality, move || Ok(bytes))
+}
+
+// 透明部分の確認用プレビュー（PNG、アルファを保持）
+// checker: true なら灰色の市松模様の上に合成する（透明な範囲に模様が見える。既定 false）
+#[tauri::command]
+fn parse_psd_with_transparency(
+    path: String,
+    checker: Option<bool>,
+) -> Result<PsdImageResult, AppError> {
+    let checker = checker.unwrap_or(false);
+    let base_key = format!(
+        "psd_alpha:{}{}",
+        versioned_path_key(&path),
+        if checker { ":checker" } else { "" }
+    );
+    let key_for = |decoder: &str| format!("{}:{}", base_key, decoder);
+
+    let temp_dir = get_kenban_temp_dir().map_err(AppError::Io)?;
+    for decoder in [PSD_DECODER_FALLBACK, PSD_DECODER_CRATE] {
+        let filename = cache_key_to_filename_with_ext(
+            &png_variant_key(&key_for(decoder)),
+            PreviewFormat::Png.extension(),
+        );
+        let file_path = temp_dir.join(&filename);
+        if file_path.exists() {
+            let (w, h) = image::image_dimensions(&file_path)
+                .map_err(|e| AppError::from_image("Failed to read image dimensions", e))?;
+            return Ok(PsdImageResult {
+                file_url: file_path.to_string_lossy().to_string(),
+                mime_type: PreviewFormat::Png.mime_type().to_string(),
+                width: w,
+                height: h,
+                decoder_used: decoder,
+            });
+        }
     }
 
-    let bytes = fs::read(&path).map_err(|e| format!("Failed to read file: {}", e))?;
+    let bytes = with_io_retry(&path, || {
+        fs::read(&path).map_err(|e| AppError::Io(format!("Failed to read file: {}", e)))
+    })?;
+    let (img, decoder_used) = decode_psd_bytes(bytes)?;
 
-    let img = decode_psd_robust(&bytes)?;
-    drop(bytes);
+    let mut rgba = img.into_rgba8();
+    // 合成画像 (Image Data Section) は半透明部分が白と混ぜて保存されている
+    if decoder_used == PSD_DECODER_FALLBACK {
+        unmatte_white(&mut rgba);
+    }
+    let img = if checker {
+        composite_over_checker(&mut rgba);
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+    } else {
+        DynamicImage::ImageRgba8(rgba)
+    };
 
-    let (file_path_str, w, h) = write_image_to_temp(&img, &cache_key)?;
+    let (file_path_str, w, h) = write_image_to_temp_as(
+        &img,
+        &key_for(decoder_used),
+        PreviewFormat::Png,
+        PREVIEW_JPEG_QUALITY,
+    )
+    .map_err(AppError::Encode)?;
     Ok(PsdImageResult {
         file_url: file_path_str,
+        mime_type: PreviewFormat::Png.mime_type().to_string(),
         width: w,
         height: h,
+        decoder_used,
     })
 }
 
-// ファイルをシステムのデフォルトアプリで開く
+// 市松模様のマス幅 (px) と2色（Photoshop の透明部分の表示に合わせた白 / 明るいグレー）
+const CHECKER_CELL: u32 = 16;
+const CHECKER_COLORS: [u8; 2] = [255, 204];
+
+// 白マット済みの色を元に戻す: 保存値 = 色 × α + 白 × (1 − α)
+fn unmatte_white(img: &mut RgbaImage) {
+    img.par_chunks_exact_mut(4).for_each(|px| {
+        let a = px[3] as u32;
+        if a == 0 || a == 255 {
+            return;
+        }
+        for c in &mut px[..3] {
+            let v = (*c as u32).saturating_sub(255 - a);
+            *c = ((v * 255 + a / 2) / a).min(255) as u8;
+        }
+    });
+}
+
+// 市松模様の上に合成し、不透明にする
+fn composite_over_checker(img: &mut RgbaImage) {
+    let width = img.width() as usize;
+    img.par_chunks_exact_mut(width * 4)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                let cell = (x as u32 / CHECKER_CELL + y as u32 / CHECKER_CELL) % 2;
+                let bg = CHECKER_COLORS[cell as usize] as u32;
+                let a = px[3] as u32;
+                for c in &mut px[..3] {
+                    *c = ((*c as u32 * a + bg * (255 - a) + 127) / 255) as u8;
+                }
+                px[3] = 255;
+            }
+        });
+}
+
+// parse_psd / parse_psd_bytes 共通: ディスクキャッシュを確認し、なければ読み込んでデコード
+// source_key: 入力の識別子（パス+更新日時 or 内容ハッシュ）。read はキャッシュミス時のみ呼ぶ
+fn psd_preview_cached(
+    source_key: &str,
+    format: PreviewFormat,
+    quality: u8,
+    read: impl FnOnce() -> Result<Vec<u8>, AppError>,
+) -> Result<PsdImageResult, AppError> {
+    let base_key = if format == PreviewFormat::Jpeg && quality != PREVIEW_JPEG_QUALITY {
+        format!("psd_v2:{}:q{}", source_key, quality)
+    } else {
+        format!("psd_v2:{}", source_key)
+    };
+    // デコーダーごとに別キー（キャッシュヒット時もどちらで作ったか分かるように）
+    let key_for = |decoder: &str| {
+        if decoder == PSD_DECODER_CRATE {
+            format!("{}:crate", base_key)
+        } else {
+            base_key.clone()
+        }
+    };
+
+    // ディスクキャッシュチェック
+    let temp_dir = get_kenban_temp_dir().map_err(AppError::Io)?;
+    for decoder in [PSD_DECODER_FALLBACK, PSD_DECODER_CRATE] {
+        let filename = cache_key_to_filename_with_ext(&key_for(decoder), format.extension());
+        let file_path = temp_dir.join(&filename);
+        if file_path.exists() {
+            let (w, h) = image::image_dimensions(&file_path)
+                .map_err(|e| AppError::from_image("Failed to read image dimensions", e))?;
+            return Ok(PsdImageResult {
+                file_url: file_path.to_string_lossy().to_string(),
+                mime_type: format.mime_type().to_string(),
+                width: w,
+                height: h,
+                decoder_used: decoder,
+            });
+        }
+    }
+
+    let bytes = read()?;
+
+    let (img, decoder_used) = decode_psd_bytes(bytes)?;
+
+    let (file_path_str, w, h) =
+        write_image_to_temp_as(&img, &key_for(decoder_used), format, quality)
+            .map_err(AppError::Encode)?;
+    Ok(PsdImageResult {
+        file_url: file_path_str,
+        mime_type: format.mime_type().to_string(),
+        width: w,
+        height: h,
+        decoder_used,
+    })
+}
+
+// PSDのレイヤー / グループ情報
+#[derive(Serialize)]
+struct LayerInfo {
+    name: String,
+    kind: &'static str, // "layer" | "group"
+    visible: bool,
+    left: i32, // グループはクロップ前の所属レイヤー全体の外接矩形
+    top: i32,
+    right: i32,
+    bottom: i32,
+    parent: Option<String>, // 親グループ名
+}
+
+// psd crate でレイヤー構造を読む（破損データでの panic は専用スレッド内で止める）
+fn with_psd_layers<T: Send>(
+    path: &str,
+    f: impl FnOnce(&Psd) -> Result<T, AppError> + Send,
+) -> Result<T, AppError> {
+    let bytes = fs::read(path).map_err(|e| AppError::Io(format!("Failed to read PSD: {}", e)))?;
+    run_psd_isolated(|| {
+        let psd = Psd::from_bytes(&bytes)
+            .map_err(|e| AppError::Decode(format!("Failed to parse PSD: {}", e)))?;
+        f(&psd)
+    })
+}
+
+// psd crate の呼び出しを専用スレッドで実行し、終わるまで待つ
+// panic してもそのスレッドが終わるだけで、呼び出し元（コマンドのスレッド）には波及しない
+fn run_psd_isolated<T: Send>(
+    f: impl FnOnce() -> Result<T, AppError> + Send,
+) -> Result<T, AppError> {
+    std::thread::scope(|s| {
+        std::thread::Builder::new()
+            .name("psd-crate".to_string())
+            .spawn_scoped(s, f)
+            .map_err(|e| AppError::Other(format!("Failed to spawn PSD thread: {}", e)))?
+            .join()
+            .unwrap_or_else(|payload| {
+                Err(AppError::Decode(format!(
+                    "PSD解析中にエラーが発生しました: {}",
+                    extract_panic_message(&payload)
+                )))
+            })
+    })
+}
+
+// レイヤーが group_id のグループ（入れ子含む）に属するか
+fn layer_in_group(
+    mut parent: Option<u32>,
+    group_id: u32,
+    group_parents: &HashMap<u32, Option<u32>>,
+) -> bool {
+    while let Some(id) = parent {
+        if id == group_id {
+            return true;
+        }
+        parent = group_parents.get(&id).copied().flatten();
+    }
+    false
+}
+
+// PSDのレイヤー / グループ一覧を取得（parse_psd_layer で指定する名前の確認用）
+#[tauri::command]
+fn list_psd_layers(path: String) -> Result<Vec<LayerInfo>, AppError> {
+    with_psd_layers(&path, |psd| {
+        // groups() は id → グループの HashMap なので、出力順が毎回変わらないよう id 順に並べる
+        let mut groups: Vec<&psd::PsdGroup> = psd.groups().values().collect();
+        groups.sort_by_key(|g| g.id());
+        let group_names: HashMap<u32, &str> = groups.iter().map(|g| (g.id(), g.name())).collect();
+        let group_parents: HashMap<u32, Option<u32>> =
+            groups.iter().map(|g| (g.id(), g.parent_id())).collect();
+        let parent_name = |id: Option<u32>| {
+            id.and_then(|id| group_names.get(&id))
+                .map(|n| n.to_string())
+        };
+
+        let mut infos: Vec<LayerInfo> = groups
+            .iter()
+            .map(|g| {
+                let members = psd
+                    .layers()
+                    .iter()
+                    .filter(|l| layer_in_group(l.parent_id(), g.id(), &group_parents));
+                let (left, top, right, bottom) = members.fold(
+                    (i32::MAX, i32::MAX, i32::MIN, i32::MIN),
+                    |(l, t, r, b), layer| {
+                        (
+                            l.min(layer.layer_left()),
+                            t.min(layer.layer_top()),
+                            r.max(layer.layer_right()),
+                            b.max(layer.layer_bottom()),
+                        )
+                    },
+                );
+                let empty = left > right;
+                LayerInfo {
+                    name: g.name().to_string(),
+                    kind: "group",
+                    visible: g.visible(),
+                    left: if empty { 0 } else { left },
+                    top: if empty { 0 } else { top },
+                    right: if empty { 0 } else { right },
+                    bottom: if empty { 0 } else { bottom },
+                    parent: parent_name(g.parent_id()),
+                }
+            })
+            .collect();
+        infos.extend(psd.layers().iter().map(|l| LayerInfo {
+            name: l.name().to_string(),
+            kind: "layer",
+            visible: l.visible(),
+            left: l.layer_left(),
+            top: l.layer_top(),
+            right: l.layer_right(),
+            bottom: l.layer_bottom(),
+            parent: parent_name(l.parent_id()),
+        }));
+        Ok(infos)
+    })
+}
+
+// 指定レイヤー（またはグループ内の全レイヤー）だけを合成して temp 画像に書き出す
+// 同名のグループがあればグループを優先。見つからなければ code "not_found" のエラーを返すので、
+// 呼び出し側は parse_psd（合成画像）にフォールバックできる
+#[tauri::command]
+fn parse_psd_layer(
+    path: String,
+    layer_name: String,
+    format: Option<String>,
+) -> Result<PsdImageResult, AppError> {
+    let format = PreviewFormat::parse(format.as_deref()).map_err(AppError::Unsupported)?;
+    let cache_key = format!("psd_layer:{}:{}", versioned_path_key(&path), layer_name);
+
+    let temp_dir = get_kenban_temp_dir().map_err(AppError::Io)?;
+    let file_path = temp_dir.join(cache_key_to_filename_with_ext(
+        &cache_key,
+        format.extension(),
+    ));
+    if file_path.exists() {
+        let (w, h) = image::image_dimensions(&file_path)
+            .map_err(|e| AppError::from_image("Failed to read image dimensions", e))?;
+        return Ok(PsdImageResult {
+            file_url: file_path.to_string_lossy().to_string(),
+            mime_type: format.mime_type().to_string(),
+            width: w,
+            height: h,
+            decoder_used: PSD_DECODER_CRATE,
+        });
+    }
+
+    let img = with_psd_layers(&path, |psd| {
+        let group_parents: HashMap<u32, Option<u32>> = psd
+            .groups()
+            .values()
+            .map(|g| (g.id(), g.parent_id()))
+            .collect();
+        // 同名のグループが複数あれば id の小さい方
+        let group_id = psd
+            .groups()
+            .values()
+            .filter(|g| g.name() == layer_name)
+            .map(|g| g.id())
+            .min();
+
+        let rgba = match group_id {
+            Some(group_id) => psd.flatten_layers_rgba(&|(_, layer)| {
+                layer_in_group(layer.parent_id(), group_id, &group_parents)
+            }),
+            None => {
+                if !psd.layers().iter().any(|l| l.name() == layer_name) {
+                    return Err(AppError::NotFound(format!(
+                        "レイヤーが見つかりません: {}",
+                        layer_name
+                    )));
+                }
+                psd.flatten_layers_rgba(&|(_, layer)| layer.name() == layer_name)
+            }
+        }
+        .map_err(|e| AppError::Decode(format!("Failed to composite layers: {}", e)))?;
+
+        let img_buf: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_raw(psd.width(), psd.height(), rgba)
+                .ok_or_else(|| AppError::Decode("Failed to create image buffer".to_string()))?;
+        Ok(DynamicImage::ImageRgba8(img_buf))
+    })?;
+
+    let (file_path_str, w, h) =
+        write_image_to_temp_as(&img, &cache_key, format, PREVIEW_JPEG_QUALITY)
+            .map_err(AppError::Encode)?;
+    Ok(PsdImageResult {
+        file_url: file_path_str,
+        mime_type: format.mime_type().to_string(),
+        width: w,
+        height: h,
+        decoder_used: PSD_DECODER_CRATE,
+    })
+}
+
+// ファイルをシステムのデフォルトアプリで開く
 #[tauri::command]
-fn open_file_with_default_app(path: String) -> Result<(), String> {
-    open::that(&path).map_err(|e| format!("Failed to open file: {}", e))
+fn open_file_with_default_app(path: String) -> Result<(), AppError> {
+    open::that(&path).map_err(|e| AppError::Io(format!("Failed to open file: {}", e)))
 }
 
 fn find_photoshop_path() -> Option<PathBuf> {
@@ -241,27 +1381,29 @@ fn find_photoshop_path() -> Option<PathBuf> {
 }
 
 #[tauri::command]
-fn open_file_in_photoshop(path: String, photoshop_path: Option<String>) -> Result<(), String> {
+fn open_file_in_photoshop(path: String, photoshop_path: Option<String>) -> Result<(), AppError> {
     let photoshop_path = photoshop_path
         .filter(|p| !p.trim().is_empty())
         .map(PathBuf::from)
         .or_else(find_photoshop_path)
         .ok_or_else(|| {
-            "Photoshop.exe が見つかりません。設定から Photoshop.exe を選択してください。"
-                .to_string()
+            AppError::Io(
+                "Photoshop.exe が見つかりません。設定から Photoshop.exe を選択してください。"
+                    .to_string(),
+            )
         })?;
 
     if !photoshop_path.exists() {
-        return Err(format!(
+        return Err(AppError::Io(format!(
             "指定された Photoshop.exe が存在しません: {}",
             photoshop_path.display()
-        ));
+        )));
     }
 
     std::process::Command::new(&photoshop_path)
         .arg(&path)
         .spawn()
-        .map_err(|e| format!("Failed to launch Photoshop: {}", e))?;
+        .map_err(|e| AppError::Io(format!("Failed to launch Photoshop: {}", e)))?;
 
     Ok(())
 }
@@ -271,19 +1413,64 @@ fn open_file_in_photoshop(path: String, photoshop_path: Option<String>) -> Resul
 struct SaveScreenshotResult {
     file_path: String,
     folder_path: String,
+    mime_type: &'static str, // 実際に書き出した形式 (image/png | image/jpeg | image/webp)
+}
+
+// Base64デコード（data:image/png;base64, プレフィックスを除去）
+fn decode_png_data_url(image_data: &str) -> Result<Vec<u8>, String> {
+    let base64_data = image_data
+        .strip_prefix("data:image/png;base64,")
+        .unwrap_or(image_data);
+    STANDARD
+        .decode(base64_data)
+        .map_err(|e| format!("Failed to decode base64: {}", e))
+}
+
+// スクリーンショットをクリップボードにコピー（チャット等へ直接貼り付け用）
+#[tauri::command]
+fn copy_screenshot_to_clipboard(image_data: String) -> Result<(), AppError> {
+    let image_bytes = decode_png_data_url(&image_data).map_err(AppError::Decode)?;
+    let rgba = image::load_from_memory(&image_bytes)
+        .map_err(|e| AppError::Decode(format!("Failed to decode image: {}", e)))?
+        .to_rgba8();
+    let (width, height) = rgba.dimensions();
+
+    let mut clipboard =
+        arboard::Clipboard::new().map_err(|e| format!("Failed to access clipboard: {}", e))?;
+    clipboard
+        .set_image(arboard::ImageData {
+            width: width as usize,
+            height: height as usize,
+            bytes: std::borrow::Cow::Owned(rgba.into_raw()),
+        })
+        .map_err(|e| AppError::Other(format!("Failed to copy image to clipboard: {}", e)))
 }
 
 // スクリーンショットを保存
+// format: "png"（既定。受け取った PNG をそのまま書き出す）| "jpeg" | "webp"（可逆）
+// quality: JPEG の品質 1〜100（既定 85）
 #[tauri::command]
-fn save_screenshot(image_data: String, file_name: String) -> Result<SaveScreenshotResult, String> {
+fn save_screenshot(
+    image_data: String,
+    file_name: String,
+    format: Option<String>,
+    quality: Option<u8>,
+) -> Result<SaveScreenshotResult, AppError> {
+    let format = match format.as_deref() {
+        None => PreviewFormat::Png,
+        f => PreviewFormat::parse(f).map_err(AppError::Unsupported)?,
+    };
+
     // デスクトップパスを取得
-    let desktop = dirs::desktop_dir().ok_or_else(|| "Failed to get desktop path".to_string())?;
+    let desktop = dirs::desktop_dir()
+        .ok_or_else(|| AppError::Io("Failed to get desktop path".to_string()))?;
 
     // 保存先フォルダを作成
     let folder_path = desktop.join("Script_Output").join("検版ツール");
-    fs::create_dir_all(&folder_path).map_err(|e| format!("Failed to create folder: {}", e))?;
+    fs::create_dir_all(&folder_path)
+        .map_err(|e| AppError::Io(format!("Failed to create folder: {}", e)))?;
 
-    // ファイル名を生成（拡張子を.pngに変更）
+    // ファイル名を生成（拡張子を出力形式に合わせる）
     let base_name = PathBuf::from(&file_name)
         .file_stem()
         .map(|s| s.to_string_lossy().to_string())
@@ -294,30 +1481,207 @@ fn save_screenshot(image_data: String, file_name: String) -> Result<SaveScreensh
         .duration_since(std::time::UNIX_EPOCH)
         .map(|d| d.as_secs())
         .unwrap_or(0);
-    let final_name = format!("{}_{}.png", base_name, timestamp);
+    let final_name = format!("{}_{}.{}", base_name, timestamp, format.extension());
     let file_path = folder_path.join(&final_name);
 
-    // Base64デコード（data:image/png;base64, プレフィックスを除去）
-    let base64_data = image_data
-        .strip_prefix("data:image/png;base64,")
-        .unwrap_or(&image_data);
-    let image_bytes = STANDARD
-        .decode(base64_data)
-        .map_err(|e| format!("Failed to decode base64: {}", e))?;
+    let png_bytes = decode_png_data_url(&image_data).map_err(AppError::Decode)?;
+    let image_bytes = if format == PreviewFormat::Png {
+        png_bytes
+    } else {
+        let img = image::load_from_memory_with_format(&png_bytes, image::ImageFormat::Png)
+            .map_err(|e| AppError::from_image("Failed to decode screenshot", e))?;
+        encode_image_as(&img, format, quality.unwrap_or(PREVIEW_JPEG_QUALITY))
+            .map_err(AppError::Encode)?
+    };
 
     // ファイルに保存
-    fs::write(&file_path, image_bytes).map_err(|e| format!("Failed to write file: {}", e))?;
+    fs::write(&file_path, image_bytes)
+        .map_err(|e| AppError::Io(format!("Failed to write file: {}", e)))?;
 
     Ok(SaveScreenshotResult {
         file_path: file_path.to_string_lossy().to_string(),
         folder_path: folder_path.to_string_lossy().to_string(),
+        mime_type: format.mime_type(),
+    })
+}
+
+// save_diff_image で差分を計算し直すときのパラメータ（compute_diff_simple と同じ意味）
+#[derive(Deserialize)]
+struct DiffImageRecompute {
+    path_a: String,
+    path_b: String,
+    threshold: DiffThreshold,
+    #[serde(default)]
+    options: Option<SimpleDiffOptions>,
+    #[serde(default)]
+    ignore_regions: Option<Vec<CropBounds>>,
+    #[serde(default)]
+    page: Option<usize>,
+    #[serde(default)]
+    roi: Option<CropBounds>,
+}
+
+// 差分画像を PNG としてそのまま保存（Base64 でフロントエンドを往復させない）
+// diff_src: 計算済み結果の diff_src（kenban の temp ファイル）/ recompute: 指定時は差分を計算して保存
+// どちらか一方を指定する。保存先のパスを返す
+#[tauri::command]
+fn save_diff_image(
+    diff_src: Option<String>,
+    recompute: Option<DiffImageRecompute>,
+    output_path: String,
+) -> Result<String, AppError> {
+    let source = match (diff_src, recompute) {
+        // temp フォルダ外の任意ファイルはコピーしない
+        (Some(src), None) => resolve_temp_file(&src)?,
+        (None, Some(r)) => PathBuf::from(
+            compute_diff_simple(
+                r.path_a,
+                r.path_b,
+                r.threshold,
+                r.options,
+                r.ignore_regions,
+                r.page,
+                r.roi,
+            )?
+            .diff_src,
+        ),
+        _ => {
+            return Err(AppError::Other(
+                "diff_src と recompute のどちらか一方を指定してください".to_string(),
+            ))
+        }
+    };
+
+    let is_png = source
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    if is_png {
+        fs::copy(&source, &output_path)
+            .map_err(|e| AppError::Io(format!("Failed to write file: {}", e)))?;
+    } else {
+        image::open(&source)
+            .map_err(|e| AppError::from_image("Failed to open diff image", e))?
+            .save_with_format(&output_path, image::ImageFormat::Png)
+            .map_err(|e| AppError::from_image("Failed to save diff image", e))?;
+    }
+    Ok(output_path)
+}
+
+#[derive(Serialize)]
+struct TransformImageResult {
+    file_path: String, // output_path 指定時はそのパス、未指定なら temp PNG
+    width: u32,
+    height: u32,
+}
+
+// 上下逆・横倒しのスキャン画像を比較前に直す。時計回りに rotate_degrees 回転してから反転する
+// rotate_degrees: 0 / 90 / 180 / 270（負の値・360以上は 360 で割った余り）
+// output_path: 指定時はそこへ保存（形式は拡張子から）、未指定なら temp の PNG に書き出す
+#[tauri::command]
+fn transform_image(
+    path: String,
+    rotate_degrees: i32,
+    flip_h: Option<bool>,
+    flip_v: Option<bool>,
+    output_path: Option<String>,
+) -> Result<TransformImageResult, AppError> {
+    let rotate = rotate_degrees.rem_euclid(360);
+    if rotate % 90 != 0 {
+        return Err(AppError::Unsupported(format!(
+            "回転は 90 度単位で指定してください: {}",
+            rotate_degrees
+        )));
+    }
+    let (flip_h, flip_v) = (flip_h.unwrap_or(false), flip_v.unwrap_or(false));
+
+    let img = decode_image_file(&path, 0)?;
+    let img = match rotate {
+        90 => img.rotate90(),
+        180 => img.rotate180(),
+        270 => img.rotate270(),
+        _ => img,
+    };
+    let img = if flip_h { img.fliph() } else { img };
+    let img = if flip_v { img.flipv() } else { img };
+    let (width, height) = img.dimensions();
+
+    let file_path = match output_path {
+        Some(output) => {
+            let format = image::ImageFormat::from_path(&output)
+                .map_err(|e| AppError::from_image("未対応の出力形式です", e))?;
+            // JPEG はアルファを持てないので RGB にしてから保存
+            let img = if format == image::ImageFormat::Jpeg {
+                DynamicImage::ImageRgb8(img.to_rgb8())
+            } else {
+                img
+            };
+            img.save_with_format(&output, format)
+                .map_err(|e| AppError::from_image("Failed to save image", e))?;
+            output
+        }
+        None => {
+            let cache_key = format!(
+                "transform:{}:r{}{}{}",
+                versioned_path_key(&path),
+                rotate,
+                if flip_h { "h" } else { "" },
+                if flip_v { "v" } else { "" }
+            );
+            write_image_to_temp_as(&img, &cache_key, PreviewFormat::Png, PREVIEW_JPEG_QUALITY)
+                .map_err(AppError::Encode)?
+                .0
+        }
+    };
+    Ok(TransformImageResult {
+        file_path,
+        width,
+        height,
+    })
+}
+
+// マーカーで示した範囲だけを PNG で書き出す（指摘箇所の証跡用）
+// crop_bounds: 元画像のピクセル座標。画像からはみ出す分は切り詰め、完全に外側ならエラー
+// 戻り値の width / height は実際に書き出したサイズ
+#[tauri::command]
+fn export_region(
+    path: String,
+    crop_bounds: CropBounds,
+    output_path: String,
+) -> Result<TransformImageResult, AppError> {
+    let img = decode_image_file(&path, 0)?;
+    let bounds = crop_bounds.clamp_to(img.width(), img.height())?;
+    let (width, height) = (bounds.right - bounds.left, bounds.bottom - bounds.top);
+    let region = img.crop_imm(bounds.left, bounds.top, width, height);
+
+    let file = fs::File::create(&output_path)
+        .map_err(|e| AppError::Io(format!("Failed to create file: {}", e)))?;
+    let writer = std::io::BufWriter::new(file);
+    if region.color().has_alpha() {
+        let rgba = region.to_rgba8();
+        write_png(
+            writer,
+            &rgba,
+            width,
+            height,
+            image::ExtendedColorType::Rgba8,
+        )
+    } else {
+        let rgb = region.to_rgb8();
+        write_png(writer, &rgb, width, height, image::ExtendedColorType::Rgb8)
+    }
+    .map_err(AppError::Encode)?;
+
+    Ok(TransformImageResult {
+        file_path: output_path,
+        width,
+        height,
     })
 }
 
 // フォルダをエクスプローラーで開く
 #[tauri::command]
-fn open_folder(path: String) -> Result<(), String> {
-    open::that(&path).map_err(|e| format!("Failed to open folder: {}", e))
+fn open_folder(path: String) -> Result<(), AppError> {
+    open::that(&path).map_err(|e| AppError::Io(format!("Failed to open folder: {}", e)))
 }
 
 // MojiQのパスを探す
@@ -396,141 +1760,473 @@ fn find_mojiq_path() -> Option<PathBuf> {
     None
 }
 
-// MojiQでPDFを開く（ページ指定付き）
+// 実行ファイルとして起動できるパスか検証
+fn validate_executable(path: &Path) -> Result<(), String> {
+    if !path.is_file() {
+        return Err(format!(
+            "指定されたファイルが存在しません: {}",
+            path.display()
+        ));
+    }
+
+    #[cfg(windows)]
+    {
+        let is_exe = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.eq_ignore_ascii_case("exe"))
+            .unwrap_or(false);
+        if !is_exe {
+            return Err(format!(
+                "実行ファイル(.exe)を指定してください: {}",
+                path.display()
+            ));
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)
+            .map_err(|e| format!("Failed to read metadata: {}", e))?
+            .permissions()
+            .mode();
+        if mode & 0o111 == 0 {
+            return Err(format!("実行権限がありません: {}", path.display()));
+        }
+    }
+
+    Ok(())
+}
+
+// MojiQ.exe のパスを手動指定（None / 空文字で自動検出に戻す）
 #[tauri::command]
-fn open_pdf_in_mojiq(pdf_path: String, page: Option<u32>) -> Result<(), String> {
-    println!(
-        "[MojiQ] open_pdf_in_mojiq called: pdf_path={}, page={:?}",
-        pdf_path, page
-    );
+fn set_mojiq_path(state: State<'_, AppState>, path: Option<String>) -> Result<(), AppError> {
+    let path = path.filter(|p| !p.trim().is_empty());
+    if let Some(ref p) = path {
+        validate_executable(Path::new(p)).map_err(AppError::Io)?;
+    }
 
-    // MojiQ.exeのパスを探す
-    let mojiq_path = find_mojiq_path().ok_or_else(|| {
-        "MojiQ.exe が見つかりません。MojiQをインストールしてください。".to_string()
-    })?;
+    let mut settings = state.settings.lock().map_err(|e| e.to_string())?;
+    settings.mojiq_path = path;
+    save_settings(&settings).map_err(AppError::Io)
+}
+
+// SumatraPDFのパスを探す
+fn find_sumatra_path() -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+
+    for var in ["ProgramFiles", "ProgramFiles(x86)"] {
+        if let Ok(program_files) = std::env::var(var) {
+            candidates.push(
+                PathBuf::from(&program_files)
+                    .join("SumatraPDF")
+                    .join("SumatraPDF.exe"),
+            );
+        }
+    }
+
+    // ユーザー単位インストール
+    if let Some(local_app_data) = dirs::data_local_dir() {
+        candidates.push(local_app_data.join("SumatraPDF").join("SumatraPDF.exe"));
+    }
+
+    candidates.into_iter().find(|path| path.exists())
+}
 
-    println!("[MojiQ] Found MojiQ at: {:?}", mojiq_path);
+// Adobe Acrobat / Acrobat Reader のパスを探す
+fn find_acrobat_path() -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+
+    for var in ["ProgramFiles", "ProgramFiles(x86)"] {
+        if let Ok(program_files) = std::env::var(var) {
+            let adobe = PathBuf::from(&program_files).join("Adobe");
+            candidates.push(adobe.join("Acrobat DC").join("Acrobat").join("Acrobat.exe"));
+            candidates.push(
+                adobe
+                    .join("Acrobat Reader DC")
+                    .join("Reader")
+                    .join("AcroRd32.exe"),
+            );
+            candidates.push(
+                adobe
+                    .join("Acrobat Reader")
+                    .join("Reader")
+                    .join("AcroRd32.exe"),
+            );
+        }
+    }
+
+    candidates.into_iter().find(|path| path.exists())
+}
+
+// 外部PDFビューアーの定義（ページ指定引数の形式はアプリごとに異なる）
+struct PdfViewer {
+    id: &'static str,
+    name: &'static str,
+    find: fn() -> Option<PathBuf>,
+    page_args: fn(u32) -> Vec<String>,
+}
+
+const PDF_VIEWERS: &[PdfViewer] = &[
+    PdfViewer {
+        id: "mojiq",
+        name: "MojiQ",
+        find: find_mojiq_path,
+        page_args: |page| vec!["--page".to_string(), page.to_string()],
+    },
+    PdfViewer {
+        id: "sumatra",
+        name: "SumatraPDF",
+        find: find_sumatra_path,
+        page_args: |page| vec!["-page".to_string(), page.to_string()],
+    },
+    PdfViewer {
+        id: "acrobat",
+        name: "Adobe Acrobat",
+        find: find_acrobat_path,
+        page_args: |page| vec!["/A".to_string(), format!("page={}", page)],
+    },
+];
+
+// 指定ビューアーでPDFを開く（MojiQは set_mojiq_path の指定パスを優先）
+fn launch_pdf_viewer(
+    viewer: &PdfViewer,
+    settings: &AppSettings,
+    pdf_path: &str,
+    page: Option<u32>,
+) -> Result<(), String> {
+    let override_path = if viewer.id == "mojiq" {
+        settings.mojiq_path.clone()
+    } else {
+        None
+    };
+    let exe_path = match override_path {
+        Some(p) => {
+            let p = PathBuf::from(p);
+            validate_executable(&p)?;
+            p
+        }
+        None => (viewer.find)().ok_or_else(|| {
+            format!(
+                "{} が見つかりません。{}をインストールしてください。",
+                viewer.name, viewer.name
+            )
+        })?,
+    };
 
-    let mut cmd = std::process::Command::new(&mojiq_path);
+    let mut cmd = std::process::Command::new(&exe_path);
 
     if let Some(p) = page {
-        cmd.arg("--page");
-        cmd.arg(p.to_string());
+        cmd.args((viewer.page_args)(p));
     }
-    cmd.arg(&pdf_path);
+    cmd.arg(pdf_path);
 
     cmd.spawn()
-        .map_err(|e| format!("Failed to launch MojiQ: {}", e))?;
+        .map_err(|e| format!("Failed to launch {}: {}", viewer.name, e))?;
 
     Ok(())
 }
 
+// 外部PDFビューアーでPDFを開く（viewer_id: mojiq | sumatra | acrobat）
+#[tauri::command]
+fn open_pdf_in_viewer(
+    state: State<'_, AppState>,
+    pdf_path: String,
+    page: Option<u32>,
+    viewer_id: String,
+) -> Result<(), AppError> {
+    let viewer = PDF_VIEWERS
+        .iter()
+        .find(|v| v.id.eq_ignore_ascii_case(&viewer_id))
+        .ok_or_else(|| {
+            let ids: Vec<&str> = PDF_VIEWERS.iter().map(|v| v.id).collect();
+            AppError::Unsupported(format!(
+                "未対応のビューアーです: {} (利用可能: {})",
+                viewer_id,
+                ids.join(", ")
+            ))
+        })?;
+
+    let settings = state.settings.lock().map_err(|e| e.to_string())?;
+    launch_pdf_viewer(viewer, &settings, &pdf_path, page).map_err(AppError::Io)
+}
+
+// MojiQでPDFを開く（ページ指定付き）
+// set_mojiq_path で指定されたパスを優先し、未指定なら既定のインストール先を探す
+#[tauri::command]
+fn open_pdf_in_mojiq(
+    state: State<'_, AppState>,
+    pdf_path: String,
+    page: Option<u32>,
+) -> Result<(), AppError> {
+    open_pdf_in_viewer(state, pdf_path, page, "mojiq".to_string())
+}
+
 // ============== 並列ビューモード用の高速画像処理 ==============
 
+// プレビュー縮小のフィルタ（速い順。既定の triangle は従来どおり）
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ResizeFilter {
+    Nearest,
+    #[default]
+    Triangle,
+    CatmullRom,
+    Lanczos3, // 最も高画質（書き出し用）
+}
+
+impl ResizeFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            Self::Nearest => FilterType::Nearest,
+            Self::Triangle => FilterType::Triangle,
+            Self::CatmullRom => FilterType::CatmullRom,
+            Self::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+
+    // キャッシュキーに付ける識別子（既定は従来のキーのまま）
+    fn cache_tag(self) -> &'static str {
+        match self {
+            Self::Nearest => "_nearest",
+            Self::Triangle => "",
+            Self::CatmullRom => "_catmullrom",
+            Self::Lanczos3 => "_lanczos3",
+        }
+    }
+}
+
 // 画像をリサイズして temp JPEG に書き出し、パスを返す（内部ヘルパー）
 fn resize_and_write_to_temp(
     img: &DynamicImage,
     max_width: u32,
     max_height: u32,
     cache_key: &str,
+    filter: FilterType,
 ) -> Result<(String, u32, u32), String> {
     let (orig_w, orig_h) = img.dimensions();
+    let (new_w, new_h) = fit_within(orig_w, orig_h, max_width, max_height);
+
+    if (new_w, new_h) != (orig_w, orig_h) {
+        let resized = img.resize(new_w, new_h, filter);
+        write_image_to_temp(&resized, cache_key)
+    } else {
+        write_image_to_temp(img, cache_key)
+    }
+}
 
-    // アスペクト比を保ちながらリサイズ
+// アスペクト比を保ったまま max_width x max_height に収まるサイズ（拡大はしない）
+fn fit_within(orig_w: u32, orig_h: u32, max_width: u32, max_height: u32) -> (u32, u32) {
     let scale_w = max_width as f64 / orig_w as f64;
     let scale_h = max_height as f64 / orig_h as f64;
-    let scale = scale_w.min(scale_h).min(1.0); // 拡大はしない
-
+    let scale = scale_w.min(scale_h).min(1.0);
     if scale < 1.0 {
-        let new_w = (orig_w as f64 * scale).round() as u32;
-        let new_h = (orig_h as f64 * scale).round() as u32;
-        let resized = img.resize(new_w, new_h, FilterType::Triangle);
-        write_image_to_temp(&resized, cache_key)
+        // 極端に細長い画像でも 0px にはしない（resize_exact が空画像を作らないように）
+        (
+            ((orig_w as f64 * scale).round() as u32).max(1),
+            ((orig_h as f64 * scale).round() as u32).max(1),
+        )
     } else {
-        write_image_to_temp(img, cache_key)
+        (orig_w, orig_h)
     }
 }
 
-// TIFF/PNG/JPG画像をデコード+リサイズして返す（3層キャッシュ: メモリ→ディスク→生成）
+// TIFF/PNG/JPG画像をデコード+リサイズして返す（4層キャッシュ: メモリ→temp→永続ディスク→生成）
+// 生成時、同じファイルのより大きいプレビューがメモリキャッシュにあれば元画像ではなくそれを縮小する
+// resize_filter: "nearest" | "triangle"（既定）| "catmullrom" | "lanczos3"
+//   既定以外は元画像から縮小し、永続ディスクキャッシュは使わない
 #[tauri::command]
 fn decode_and_resize_image(
     state: State<'_, AppState>,
     path: String,
     max_width: u32,
     max_height: u32,
-) -> Result<ImageResult, String> {
-    let cache_key = format!("{}:{}x{}", versioned_path_key(&path), max_width, max_height);
+    resize_filter: Option<ResizeFilter>,
+) -> Result<ImageResult, AppError> {
+    resize_preview(
+        &state.image_cache,
+        state.disk_cache_enabled.load(Ordering::Relaxed),
+        &path,
+        max_width,
+        max_height,
+        resize_filter.unwrap_or_default(),
+    )
+}
+
+// decode_and_resize_image の本体（AppState の代わりにメモリキャッシュと永続キャッシュの設定を受け取る）
+fn resize_preview(
+    image_cache: &RwLock<ImageCache>,
+    disk_cache_enabled: bool,
+    path: &str,
+    max_width: u32,
+    max_height: u32,
+    filter: ResizeFilter,
+) -> Result<ImageResult, AppError> {
+    let cache_key = format!(
+        "{}:{}x{}{}",
+        versioned_path_key(path),
+        max_width,
+        max_height,
+        filter.cache_tag()
+    );
 
-    // 1. メモリキャッシュチェック
-    {
-        let cache = state.image_cache.lock().map_err(|e| e.to_string())?;
-        if let Some(cached) = cache.get(&cache_key) {
-            // ファイルがまだ存在するか確認
-            if PathBuf::from(&cached.file_path).exists() {
-                return Ok(ImageResult {
-                    file_url: cached.file_path.clone(),
-                    width: cached.width,
-                    height: cached.height,
-                    original_width: cached.original_width,
-                    original_height: cached.original_height,
-                });
-            }
-            // ファイルが消えていたらキャッシュを無効化（下で再生成）
+    // 1. メモリキャッシュチェック（読み取りロックで複製し、ファイル確認はロックの外）
+    let cached = image_cache
+        .read()
+        .map_err(|e| e.to_string())?
+        .get(&cache_key)
+        .cloned();
+    if let Some(cached) = cached {
+        // ファイルがまだ存在するか確認
+        if PathBuf::from(&cached.file_path).exists() {
+            return Ok(ImageResult {
+                file_url: cached.file_path,
+                width: cached.width,
+                height: cached.height,
+                original_width: cached.original_width,
+                original_height: cached.original_height,
+                decoder_used: "cache",
+                content_hash: known_content_hash(path),
+            });
         }
+        // ファイルが消えていたらキャッシュを無効化（下で再生成）
     }
 
     // 2. ディスクキャッシュチェック（tempファイル存在確認）
-    let temp_dir = get_kenban_temp_dir()?;
+    let temp_dir = get_kenban_temp_dir().map_err(AppError::Io)?;
     let filename = cache_key_to_filename(&cache_key);
     let file_path = temp_dir.join(&filename);
-    if file_path.exists() {
+    // 元画像のヘッダーが読めない場合はリサイズ後のサイズを元サイズとして返さないよう、再生成に回す
+    let temp_hit = if file_path.exists() {
+        image::image_dimensions(&file_path)
+            .ok()
+            .zip(image_dimensions_oriented(path).ok())
+    } else {
+        None
+    };
+    if let Some(((w, h), (orig_w, orig_h))) = temp_hit {
         // ディスクにあるがメモリにない → 画像サイズだけ取得してメモリキャッシュ登録
-        // サイズ情報は元画像から取得する必要があるが、軽量化のためJPEGヘッダから取得
-        let (w, h) = image::image_dimensions(&file_path)
-            .map_err(|e| format!("Failed to read image dimensions: {}", e))?;
+        // サイズはデコードせず、tempファイルと元画像のヘッダから取得
         let file_path_str = file_path.to_string_lossy().to_string();
+        let cached = CachedImage {
+            byte_size: file_size(&file_path_str),
+            file_path: file_path_str.clone(),
+            width: w,
+            height: h,
+            original_width: orig_w,
+            original_height: orig_h,
+            bitmap: None,
+        };
 
-        // 元画像サイズも取得
-        let (orig_w, orig_h) = image::image_dimensions(&path).unwrap_or((w, h));
-
-        let mut cache = state.image_cache.lock().map_err(|e| e.to_string())?;
-        cache.insert(
-            cache_key.clone(),
-            CachedImage {
-                file_path: file_path_str.clone(),
-                width: w,
-                height: h,
-                original_width: orig_w,
-                original_height: orig_h,
-            },
-        );
+        image_cache
+            .write()
+            .map_err(|e| e.to_string())?
+            .insert(cache_key.clone(), cached);
         return Ok(ImageResult {
             file_url: file_path_str,
             width: w,
             height: h,
             original_width: orig_w,
             original_height: orig_h,
+            decoder_used: "cache",
+            content_hash: known_content_hash(path),
         });
     }
 
-    // 3. フルデコード → temp書き出し → キャッシュ登録
-    let img = image::open(&path).map_err(|e| format!("Failed to open image: {}", e))?;
-    let (orig_w, orig_h) = img.dimensions();
+    // 3. 永続ディスクキャッシュチェック（再起動後・tempクリーンアップ後）
+    let disk_enabled = disk_cache_enabled && filter == ResizeFilter::Triangle;
+    if disk_enabled {
+        if let Some(restored) = load_persistent_preview(path, max_width, max_height, &cache_key) {
+            let result = ImageResult {
+                file_url: restored.file_path.clone(),
+                width: restored.width,
+                height: restored.height,
+                original_width: restored.original_width,
+                original_height: restored.original_height,
+                decoder_used: "cache",
+                content_hash: known_content_hash(path),
+            };
+            let mut cache = image_cache.write().map_err(|e| e.to_string())?;
+            cache.insert(cache_key, restored);
+            return Ok(result);
+        }
+    }
 
-    let (file_path_str, new_w, new_h) =
-        resize_and_write_to_temp(&img, max_width, max_height, &cache_key)?;
+    // 4. 大きいプレビューから縮小（ウィンドウ縮小時に元画像を再デコードしない）
+    let larger = image_cache
+        .read()
+        .map_err(|e| e.to_string())?
+        .largest_for(cache_key_source(&cache_key))
+        .cloned()
+        .filter(|_| filter == ResizeFilter::Triangle);
+    // 縮小の元はメモリに残したビットマップ（劣化した temp JPEG を再デコードしない）。なければフルデコード
+    let larger = larger.and_then(|c| {
+        let (tw, th) = fit_within(c.original_width, c.original_height, max_width, max_height);
+        let bitmap = c.bitmap?;
+        (c.width >= tw && c.height >= th).then_some((
+            bitmap,
+            tw,
+            th,
+            c.original_width,
+            c.original_height,
+        ))
+    });
+    let derived = larger.and_then(|(src, tw, th, ow, oh)| {
+        let (file_path_str, w, h) = if src.dimensions() == (tw, th) {
+            write_image_to_temp(&src, &cache_key).ok()?
+        } else {
+            write_image_to_temp(&src.resize_exact(tw, th, FilterType::Triangle), &cache_key).ok()?
+        };
+        Some((file_path_str, w, h, ow, oh, "cache", None))
+    });
 
-    let mut cache = state.image_cache.lock().map_err(|e| e.to_string())?;
-    cache.insert(
-        cache_key,
-        CachedImage {
-            file_path: file_path_str.clone(),
-            width: new_w,
-            height: new_h,
-            original_width: orig_w,
-            original_height: orig_h,
-        },
-    );
+    // 5. フルデコード → temp書き出し → キャッシュ登録（ハッシュはデコード用に読んだバイト列から求める）
+    let mut hash = None;
+    let (file_path_str, new_w, new_h, orig_w, orig_h, decoder_used, bitmap) = match derived {
+        Some(d) => d,
+        None => {
+            let bytes = with_io_retry(path, || read_image_bytes(path))?;
+            hash = content_hash_of_bytes(path, &bytes);
+            let (img, decoder_used) = decode_raster_bytes(path, &bytes)?;
+            drop(bytes);
+            let (orig_w, orig_h) = img.dimensions();
+            let (new_w, new_h) = fit_within(orig_w, orig_h, max_width, max_height);
+            let resized = if (new_w, new_h) != (orig_w, orig_h) {
+                img.resize(new_w, new_h, filter.filter_type())
+            } else {
+                img
+            };
+            let (file_path_str, new_w, new_h) =
+                write_image_to_temp(&resized, &cache_key).map_err(AppError::Encode)?;
+            let bitmap = Some(Arc::new(resized));
+            (
+                file_path_str,
+                new_w,
+                new_h,
+                orig_w,
+                orig_h,
+                decoder_used,
+                bitmap,
+            )
+        }
+    };
+
+    let cached = CachedImage {
+        byte_size: file_size(&file_path_str),
+        file_path: file_path_str.clone(),
+        width: new_w,
+        height: new_h,
+        original_width: orig_w,
+        original_height: orig_h,
+        bitmap,
+    };
+    if disk_enabled {
+        store_persistent_preview(path, max_width, max_height, &cached);
+    }
+
+    let mut cache = image_cache.write().map_err(|e| e.to_string())?;
+    cache.insert(cache_key, cached);
 
     Ok(ImageResult {
         file_url: file_path_str,
@@ -538,114 +2234,432 @@ fn decode_and_resize_image(
         height: new_h,
         original_width: orig_w,
         original_height: orig_h,
+        decoder_used,
+        content_hash: hash.or_else(|| known_content_hash(path)),
     })
 }
 
+// decode_and_resize_image のバイト列版: プレビュー JPEG の中身を生のバイナリで返す（JSON / base64 を経由しない）
+// フロントは new Blob([bytes], { type: 'image/jpeg' }) から Blob URL を作る。キャッシュは decode_and_resize_image と共通
+#[tauri::command]
+fn decode_and_resize_image_bytes(
+    state: State<'_, AppState>,
+    path: String,
+    max_width: u32,
+    max_height: u32,
+    resize_filter: Option<ResizeFilter>,
+) -> Result<tauri::ipc::Response, AppError> {
+    let result = decode_and_resize_image(state, path, max_width, max_height, resize_filter)?;
+    let bytes = fs::read(&result.file_url)
+        .map_err(|e| AppError::Io(format!("Failed to read preview: {}", e)))?;
+    Ok(tauri::ipc::Response::new(bytes))
+}
+
+#[derive(Serialize)]
+struct ImageChunkHandle {
+    handle: u64,
+    total_len: u64,
+}
+
+// 巨大な結果画像（フル解像度の合成画像など）を分割して受け取るためにメモリへ読み込み、ハンドルを返す
+// path: 各コマンドが返した temp ファイル。フロントは get_image_chunk で順に取り出し、最後に release_image_chunk を呼ぶ
+#[tauri::command]
+fn open_image_chunks(
+    state: State<'_, AppState>,
+    path: String,
+) -> Result<ImageChunkHandle, AppError> {
+    let path = resolve_temp_file(&path)?;
+    let bytes = fs::read(&path).map_err(|e| AppError::Io(format!("Failed to read file: {}", e)))?;
+    let total_len = bytes.len() as u64;
+    let store = &state.image_chunks;
+    let handle = store.next_handle.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut buffers = store.buffers.lock().map_err(|e| e.to_string())?;
+    while buffers.len() >= MAX_CHUNK_HANDLES {
+        let oldest = *buffers.keys().min().expect("non-empty");
+        buffers.remove(&oldest);
+    }
+    buffers.insert(handle, Arc::new(bytes));
+    Ok(ImageChunkHandle { handle, total_len })
+}
+
+// offset から最大 len バイト（上限 MAX_CHUNK_LEN）を生のバイナリで返す。末尾を越える分は切り詰める
+#[tauri::command]
+fn get_image_chunk(
+    state: State<'_, AppState>,
+    handle: u64,
+    offset: u64,
+    len: u32,
+) -> Result<tauri::ipc::Response, AppError> {
+    let buffer = state
+        .image_chunks
+        .buffers
+        .lock()
+        .map_err(|e| e.to_string())?
+        .get(&handle)
+        .cloned()
+        .ok_or_else(|| AppError::NotFound(format!("Unknown chunk handle: {}", handle)))?;
+    let total = buffer.len() as u64;
+    if offset > total {
+        return Err(AppError::Other(format!(
+            "offset がデータ長 ({}) を超えています: {}",
+            total, offset
+        )));
+    }
+    let end = (offset + len.min(MAX_CHUNK_LEN) as u64).min(total);
+    Ok(tauri::ipc::Response::new(
+        buffer[offset as usize..end as usize].to_vec(),
+    ))
+}
+
+// ハンドルのバイト列を解放する（既に解放済みなら false）
+#[tauri::command]
+fn release_image_chunk(state: State<'_, AppState>, handle: u64) -> Result<bool, AppError> {
+    let mut buffers = state
+        .image_chunks
+        .buffers
+        .lock()
+        .map_err(|e| e.to_string())?;
+    Ok(buffers.remove(&handle).is_some())
+}
+
+// キャンセルされた先読みエントリの識別用
+const PRELOAD_CANCELLED: &str = "cancelled";
+
+// preload_images の1パスごとの結果
+#[derive(Serialize, Clone)]
+struct PreloadOutcome {
+    path: String,
+    status: &'static str, // "loaded" | "cached"（既にメモリキャッシュにあった）| "cancelled" | "error"
+    error: Option<String>, // status = "error" のときの理由
+}
+
+impl PreloadOutcome {
+    fn new(path: String, status: &'static str) -> Self {
+        Self {
+            path,
+            status,
+            error: None,
+        }
+    }
+
+    fn error(path: String, message: String) -> Self {
+        Self {
+            path,
+            status: "error",
+            error: Some(message),
+        }
+    }
+}
+
 // 複数画像を先読み（バックグラウンドでキャッシュ）- rayon並列化版
+// 結果は paths と同じ順に1件ずつ返す（失敗したものだけ再試行できる）
+// cancel_preload で中断された場合は完了済み分のみ登録し、残りは status = "cancelled" で返す
+// 別の呼び出しがデコード中のパスは重複してデコードせず、その完了を待ってキャッシュの結果を返す
 #[tauri::command]
 async fn preload_images(
     state: State<'_, AppState>,
     paths: Vec<String>,
     max_width: u32,
     max_height: u32,
-) -> Result<Vec<String>, String> {
-    // 既にメモリキャッシュにあるパスを除外
-    let paths_to_load: Vec<String> = {
-        let cache = state.image_cache.lock().map_err(|e| e.to_string())?;
-        paths
-            .into_iter()
-            .filter(|path| {
+) -> Result<Vec<PreloadOutcome>, AppError> {
+    // デコード・他の呼び出しの完了待ちはブロッキングスレッドで行う（tokio のワーカーを塞がない）
+    let image_cache = state.image_cache.clone();
+    let in_flight = state.preload_in_flight.clone();
+    let cancel = CancelToken::new(&state.preload_cancel);
+    let disk_enabled = state.disk_cache_enabled.load(Ordering::Relaxed);
+    run_blocking(move || {
+        // 既にメモリキャッシュにあるパスを除外（更新日時の取得はロックの外で行う）
+        let keyed: Vec<(String, String)> = paths
+            .iter()
+            .map(|path| {
                 let cache_key =
                     format!("{}:{}x{}", versioned_path_key(path), max_width, max_height);
-                cache.get(&cache_key).is_none()
+                (path.clone(), cache_key)
             })
-            .collect()
-    };
+            .collect();
+        let mut outcomes: HashMap<String, PreloadOutcome> = HashMap::new();
+        let uncached: Vec<(String, String)> = {
+            let cache = image_cache.read().map_err(|e| e.to_string())?;
+            keyed
+                .into_iter()
+                .filter(|(path, cache_key)| {
+                    let cached = cache.get(cache_key).is_some();
+                    if cached {
+                        outcomes.insert(path.clone(), PreloadOutcome::new(path.clone(), "cached"));
+                    }
+                    !cached
+                })
+                .collect()
+        };
 
-    if paths_to_load.is_empty() {
-        return Ok(vec!["all cached".to_string()]);
-    }
+        if uncached.is_empty() {
+            return Ok(outcomes_in_order(paths, outcomes));
+        }
 
-    // rayonで並列に画像を読み込み・リサイズ → tempファイルに書き出し
-    let loaded: Vec<(String, Result<(String, u32, u32, u32, u32), String>)> = paths_to_load
-        .par_iter()
-        .map(|path| {
-            let cache_key = format!("{}:{}x{}", versioned_path_key(path), max_width, max_height);
-
-            // ディスクキャッシュチェック
-            if let Ok(temp_dir) = get_kenban_temp_dir() {
-                let filename = cache_key_to_filename(&cache_key);
-                let file_path = temp_dir.join(&filename);
-                if file_path.exists() {
-                    if let Ok((w, h)) = image::image_dimensions(&file_path) {
-                        let (orig_w, orig_h) =
-                            image::image_dimensions(path.as_str()).unwrap_or((w, h));
-                        return (
-                            path.clone(),
-                            Ok((
-                                file_path.to_string_lossy().to_string(),
-                                w,
-                                h,
-                                orig_w,
-                                orig_h,
-                            )),
-                        );
-                    }
-                }
-            }
+        // 他の呼び出しがデコード中のものは待つだけにする
+        let (claim, busy) = in_flight.claim(uncached);
+        let paths_to_load: Vec<String> = claim.items.iter().map(|(path, _)| path.clone()).collect();
+
+        // デコード用プールで並列に画像を読み込み・リサイズ → tempファイルに書き出し
+        let loaded: Vec<(String, Result<(String, u32, u32, u32, u32), String>)> = decode_pool()
+            .install(|| {
+                paths_to_load
+                    .par_iter()
+                    .map(|path| {
+                        // キャンセル済みなら未着手のパスはスキップ
+                        if cancel.is_cancelled() {
+                            return (path.clone(), Err(PRELOAD_CANCELLED.to_string()));
+                        }
 
-            let result = image::open(path)
-                .map_err(|e| format!("open error: {}", e))
-                .and_then(|img| {
-                    let (orig_w, orig_h) = img.dimensions();
-                    let (file_path_str, new_w, new_h) =
-                        resize_and_write_to_temp(&img, max_width, max_height, &cache_key)?;
-                    Ok((file_path_str, new_w, new_h, orig_w, orig_h))
-                });
-            (path.clone(), result)
-        })
-        .collect();
+                        let cache_key =
+                            format!("{}:{}x{}", versioned_path_key(path), max_width, max_height);
+
+                        // ディスクキャッシュチェック
+                        if let Ok(temp_dir) = get_kenban_temp_dir() {
+                            let filename = cache_key_to_filename(&cache_key);
+                            let file_path = temp_dir.join(&filename);
+                            if file_path.exists() {
+                                if let (Ok((w, h)), Ok((orig_w, orig_h))) = (
+                                    image::image_dimensions(&file_path),
+                                    image_dimensions_oriented(path.as_str()),
+                                ) {
+                                    return (
+                                        path.clone(),
+                                        Ok((
+                                            file_path.to_string_lossy().to_string(),
+                                            w,
+                                            h,
+                                            orig_w,
+                                            orig_h,
+                                        )),
+                                    );
+                                }
+                            }
+                        }
 
-    // キャッシュに一括登録
-    let mut results = Vec::new();
-    {
-        let mut cache = state.image_cache.lock().map_err(|e| e.to_string())?;
+                        // 永続ディスクキャッシュチェック
+                        if disk_enabled {
+                            if let Some(restored) =
+                                load_persistent_preview(path, max_width, max_height, &cache_key)
+                            {
+                                return (
+                                    path.clone(),
+                                    Ok((
+                                        restored.file_path,
+                                        restored.width,
+                                        restored.height,
+                                        restored.original_width,
+                                        restored.original_height,
+                                    )),
+                                );
+                            }
+                        }
+
+                        let result = open_raster_image(path)
+                            .map_err(|e| format!("open error: {}", e))
+                            .and_then(|(img, _)| {
+                                let (orig_w, orig_h) = img.dimensions();
+                                let (file_path_str, new_w, new_h) = resize_and_write_to_temp(
+                                    &img,
+                                    max_width,
+                                    max_height,
+                                    &cache_key,
+                                    FilterType::Triangle,
+                                )?;
+                                if disk_enabled {
+                                    store_persistent_preview(
+                                        path,
+                                        max_width,
+                                        max_height,
+                                        &CachedImage {
+                                            byte_size: 0,
+                                            file_path: file_path_str.clone(),
+                                            width: new_w,
+                                            height: new_h,
+                                            original_width: orig_w,
+                                            original_height: orig_h,
+                                            bitmap: None,
+                                        },
+                                    );
+                                }
+                                Ok((file_path_str, new_w, new_h, orig_w, orig_h))
+                            });
+                        (path.clone(), result)
+                    })
+                    .collect()
+            });
+
+        // キャッシュに一括登録（キー・ファイルサイズの取得はロックの外で済ませ、書き込みロックは挿入のみ）
+        let mut entries = Vec::new();
         for (path, result) in loaded {
-            let cache_key = format!("{}:{}x{}", versioned_path_key(&path), max_width, max_height);
             match result {
                 Ok((file_path_str, new_w, new_h, orig_w, orig_h)) => {
-                    cache.insert(
+                    let cache_key =
+                        format!("{}:{}x{}", versioned_path_key(&path), max_width, max_height);
+                    entries.push((
                         cache_key,
                         CachedImage {
+                            byte_size: file_size(&file_path_str),
                             file_path: file_path_str,
                             width: new_w,
                             height: new_h,
                             original_width: orig_w,
                             original_height: orig_h,
+                            bitmap: None,
                         },
-                    );
-                    results.push(format!("loaded:{}", path));
+                    ));
+                    outcomes.insert(path.clone(), PreloadOutcome::new(path, "loaded"));
+                }
+                Err(e) if e == PRELOAD_CANCELLED => {
+                    outcomes.insert(path.clone(), PreloadOutcome::new(path, PRELOAD_CANCELLED));
+                }
+                Err(e) => {
+                    outcomes.insert(path.clone(), PreloadOutcome::error(path, e));
                 }
-                Err(e) => results.push(format!("error:{}:{}", path, e)),
             }
         }
-    }
+        {
+            let mut cache = image_cache.write().map_err(|e| e.to_string())?;
+            for (cache_key, image) in entries {
+                cache.insert(cache_key, image);
+            }
+        }
+        drop(claim);
+
+        // 他の呼び出しが処理していたパスは、その完了後のキャッシュの有無で結果を決める
+        if !busy.is_empty() {
+            let busy_keys: Vec<String> = busy.iter().map(|(_, key)| key.clone()).collect();
+            in_flight.wait_for(&busy_keys);
+            let cache = image_cache.read().map_err(|e| e.to_string())?;
+            for (path, cache_key) in busy {
+                let outcome = if cache.get(&cache_key).is_some() {
+                    PreloadOutcome::new(path.clone(), "loaded")
+                } else if cancel.is_cancelled() {
+                    PreloadOutcome::new(path.clone(), PRELOAD_CANCELLED)
+                } else {
+                    PreloadOutcome::error(path.clone(), "concurrent preload failed".to_string())
+                };
+                outcomes.insert(path, outcome);
+            }
+        }
+
+        Ok(outcomes_in_order(paths, outcomes))
+    })
+    .await
+}
+
+// パスごとの結果を入力順に並べる（同じパスが複数あれば同じ結果）
+fn outcomes_in_order(
+    paths: Vec<String>,
+    outcomes: HashMap<String, PreloadOutcome>,
+) -> Vec<PreloadOutcome> {
+    paths
+        .into_iter()
+        .map(|path| {
+            outcomes.get(&path).cloned().unwrap_or_else(|| {
+                PreloadOutcome::error(path, "preload result missing".to_string())
+            })
+        })
+        .collect()
+}
+
+// 実行中の先読み・一括差分チェックを中断（未着手のパス / ペアはスキップされる）
+// この後に始まった呼び出しには影響しない
+#[tauri::command]
+fn cancel_preload(state: State<'_, AppState>) {
+    state.preload_cancel.fetch_add(1, Ordering::Relaxed);
+}
+
+// キャッシュ方針を設定（メモリ上限MB / 永続ディスクキャッシュの有効化）
+#[tauri::command]
+fn set_cache_policy(
+    state: State<'_, AppState>,
+    max_memory_mb: u64,
+    disk_enabled: bool,
+) -> Result<(), AppError> {
+    let mut cache = state.image_cache.write().map_err(|e| e.to_string())?;
+    cache.set_max_bytes(max_memory_mb.saturating_mul(1024 * 1024));
+    state
+        .disk_cache_enabled
+        .store(disk_enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+// メモリキャッシュの最大件数を変更して設定ファイルに保存（減らした場合は古い順に削除）
+// set_cache_policy のメモリ上限MBとは独立で、両方の上限を満たすように保たれる
+#[tauri::command]
+fn set_cache_max_entries(state: State<'_, AppState>, n: usize) -> Result<(), AppError> {
+    let n = n.max(1);
+    state
+        .image_cache
+        .write()
+        .map_err(|e| e.to_string())?
+        .set_max_entries(n);
+
+    let mut settings = state.settings.lock().map_err(|e| e.to_string())?;
+    settings.cache_max_entries = Some(n);
+    save_settings(&settings).map_err(AppError::Io)
+}
+
+// 画像デコード用プールのスレッド数を設定（None で既定の min(コア数, 4) に戻す）
+// 実行中の処理は古いプールのまま終わり、次の呼び出しから新しいスレッド数になる
+#[tauri::command]
+fn set_decode_threads(threads: Option<usize>) -> Result<(), AppError> {
+    let pool = build_decode_pool(threads.unwrap_or_else(default_decode_threads))?;
+    *DECODE_POOL.lock().map_err(|e| e.to_string())? = Some(Arc::new(pool));
+    Ok(())
+}
+
+// 書き出す PNG（差分画像・PNG プレビュー）に sRGB チャンクを入れるかを設定して保存する
+#[tauri::command]
+fn set_embed_srgb(state: State<'_, AppState>, enabled: bool) -> Result<(), AppError> {
+    EMBED_SRGB.store(enabled, Ordering::Relaxed);
+    let mut settings = state.settings.lock().map_err(|e| e.to_string())?;
+    settings.embed_srgb = Some(enabled);
+    save_settings(&settings).map_err(AppError::Io)
+}
+
+// psd crate デコードの制限時間を設定（None で既定の30秒に戻す）
+#[tauri::command]
+fn set_psd_decode_timeout(seconds: Option<u64>) {
+    PSD_DECODE_TIMEOUT_SECS.store(
+        seconds.unwrap_or(DEFAULT_PSD_DECODE_TIMEOUT_SECS).max(1),
+        Ordering::Relaxed,
+    );
+}
 
-    Ok(results)
+#[derive(Serialize)]
+struct CacheStats {
+    entry_count: usize,
+    total_bytes: u64,
+    max_entries: usize,
+    max_bytes: u64,
+}
+
+// 現在のキャッシュ使用量を取得
+#[tauri::command]
+fn cache_stats(state: State<'_, AppState>) -> Result<CacheStats, AppError> {
+    let cache = state.image_cache.read().map_err(|e| e.to_string())?;
+    Ok(CacheStats {
+        entry_count: cache.cache.len(),
+        total_bytes: cache.total_bytes,
+        max_entries: cache.max_size,
+        max_bytes: cache.max_bytes,
+    })
 }
 
 // キャッシュクリア
 #[tauri::command]
-fn clear_image_cache(state: State<'_, AppState>) -> Result<(), String> {
-    let mut cache = state.image_cache.lock().map_err(|e| e.to_string())?;
+fn clear_image_cache(state: State<'_, AppState>) -> Result<(), AppError> {
+    let mut cache = state.image_cache.write().map_err(|e| e.to_string())?;
     cache.clear();
     Ok(())
 }
 
 // tempフォルダのプレビューファイルをクリーンアップ（1時間以上前のファイルを削除）
 #[tauri::command]
-fn cleanup_preview_cache() -> Result<u32, String> {
-    let temp_dir = get_kenban_temp_dir()?;
+fn cleanup_preview_cache() -> Result<u32, AppError> {
+    let temp_dir = get_kenban_temp_dir().map_err(AppError::Io)?;
     let now = std::time::SystemTime::now();
     let one_hour = std::time::Duration::from_secs(3600);
     let mut deleted = 0u32;
@@ -654,8 +2668,8 @@ fn cleanup_preview_cache() -> Result<u32, String> {
         for entry in entries.flatten() {
             let path = entry.path();
             let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
-            // jpg, png, tmp すべてを対象（diff画像のPNGや書き込み途中のtmpも含む）
-            if matches!(ext, "jpg" | "png" | "tmp") {
+            // jpg, png, webp, tmp すべてを対象（diff画像のPNGや書き込み途中のtmpも含む）
+            if matches!(ext, "jpg" | "png" | "webp" | "tmp") {
                 if let Ok(metadata) = path.metadata() {
                     if let Ok(modified) = metadata.modified() {
                         if let Ok(age) = now.duration_since(modified) {
@@ -674,49 +2688,322 @@ fn cleanup_preview_cache() -> Result<u32, String> {
 }
 
 // フォルダ内のファイル一覧を取得
+// recursive: true でサブフォルダも走査（max_depth: 走査する階層数、未指定なら無制限）
 #[tauri::command]
-fn list_files_in_folder(path: String, extensions: Vec<String>) -> Result<Vec<String>, String> {
-    let dir = std::fs::read_dir(&path).map_err(|e| format!("Failed to read directory: {}", e))?;
+fn list_files_in_folder(
+    path: String,
+    extensions: Vec<String>,
+    recursive: Option<bool>,
+    max_depth: Option<usize>,
+) -> Result<Vec<String>, AppError> {
+    let depth = if recursive.unwrap_or(false) {
+        max_depth.unwrap_or(usize::MAX).max(1)
+    } else {
+        1
+    };
 
-    let mut files: Vec<String> = dir
-        .filter_map(|entry| entry.ok())
-        .filter_map(|entry| {
-            let path = entry.path();
-            if path.is_file() {
-                let ext = path
-                    .extension()
-                    .and_then(|e| e.to_str())
-                    .map(|e| e.to_lowercase())
-                    .unwrap_or_default();
-                if extensions.iter().any(|e| e.to_lowercase() == ext) {
-                    return path.to_str().map(|s| s.to_string());
+    let mut files: Vec<String> = Vec::new();
+    for entry in walkdir::WalkDir::new(&path).min_depth(1).max_depth(depth) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // 指定フォルダ自体が読めない場合のみエラー（サブフォルダの失敗はスキップ）
+            Err(e) if e.depth() == 0 => {
+                return Err(AppError::Io(format!("Failed to read directory: {}", e)));
+            }
+            Err(_) => continue,
+        };
+        let path = entry.path();
+        if path.is_file() {
+            let ext = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.to_lowercase())
+                .unwrap_or_default();
+            if extensions.iter().any(|e| e.to_lowercase() == ext) {
+                if let Some(s) = path.to_str() {
+                    files.push(s.to_string());
                 }
             }
-            None
+        }
+    }
+
+    sort_paths_natural(&mut files);
+    Ok(files)
+}
+
+// ページ画像の一覧を見開きの組にする（自然順に並べ直してから、1ページ目 = 表紙は単独）
+// 戻り値は画面に並べる順の (左ページ, 右ページ)。rtl（右綴じ）なら若いページが右側になる
+// 単独ページ（表紙・最後の余り）は (ページ, None)
+#[tauri::command]
+fn pair_files_for_spread(mut files: Vec<String>, rtl: bool) -> Vec<(String, Option<String>)> {
+    sort_paths_natural(&mut files);
+    let mut pages = files.into_iter();
+    let mut spreads: Vec<(String, Option<String>)> =
+        pages.next().map(|c| (c, None)).into_iter().collect();
+    while let Some(first) = pages.next() {
+        spreads.push(match pages.next() {
+            Some(second) if rtl => (second, Some(first)),
+            Some(second) => (first, Some(second)),
+            None => (first, None),
+        });
+    }
+    spreads
+}
+
+#[derive(Serialize)]
+struct FileEntry {
+    path: String,
+    size_bytes: u64,
+    modified_unix: u64, // 更新日時（UNIX秒）
+    extension: String,  // 小文字
+}
+
+// フォルダ内のファイル一覧をサイズ・更新日時付きで取得（並びは list_files_in_folder と同じ）
+#[tauri::command]
+fn list_files_with_metadata(
+    path: String,
+    extensions: Vec<String>,
+    recursive: Option<bool>,
+    max_depth: Option<usize>,
+) -> Result<Vec<FileEntry>, AppError> {
+    let files = list_files_in_folder(path, extensions, recursive, max_depth)?;
+    Ok(files
+        .into_iter()
+        .map(|file| {
+            let metadata = fs::metadata(&file).ok();
+            let modified_unix = metadata
+                .as_ref()
+                .and_then(|m| m.modified().ok())
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let extension = Path::new(&file)
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.to_lowercase())
+                .unwrap_or_default();
+            FileEntry {
+                size_bytes: metadata.map(|m| m.len()).unwrap_or(0),
+                modified_unix,
+                extension,
+                path: file,
+            }
         })
+        .collect())
+}
+
+#[derive(Serialize)]
+struct JobValidation {
+    total_files: usize,
+    counts: Vec<(String, usize)>, // 拡張子（小文字）ごとの件数（expected_extensions の順）
+    naming_patterns: Vec<(String, usize)>, // ファイル名の数字部分を # にした形と件数（2種類以上なら混在）
+    unpaired: Vec<UnpairedPage>,           // 一部の形式しかないページ
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct UnpairedPage {
+    stem: String,         // 拡張子を除いたファイル名
+    present: Vec<String>, // 存在する形式
+    missing: Vec<String>, // 見つからない形式
+}
+
+// ペアを組むときに同じ形式として扱う拡張子を揃える（tif / tiff, jpg / jpeg）
+fn canonical_extension(ext: &str) -> String {
+    match ext {
+        "tiff" => "tif".to_string(),
+        "jpeg" => "jpg".to_string(),
+        other => other.to_string(),
+    }
+}
+
+// ファイル名の命名規則（数字の並びを桁数分の # に置き換える。p001 → p###）
+fn naming_pattern(stem: &str) -> String {
+    normalize_for_natural_sort(stem)
+        .chars()
+        .map(|c| if c.is_ascii_digit() { '#' } else { c })
+        .collect()
+}
+
+// 検版を始める前にフォルダの中身を点検する（フォルダ直下のみ。並びは list_files_in_folder と同じ）
+// 形式ごとの件数、命名規則の混在、片方の形式しかないページ（PSD だけ・TIFF だけ等）を返す
+#[tauri::command]
+fn validate_job_folder(
+    path: String,
+    expected_extensions: Vec<String>,
+) -> Result<JobValidation, AppError> {
+    let expected: Vec<String> = expected_extensions
+        .iter()
+        .map(|e| e.trim_start_matches('.').to_lowercase())
         .collect();
+    let files = list_files_in_folder(path, expected.clone(), None, None)?;
+
+    let mut counts: Vec<(String, usize)> = expected.iter().map(|e| (e.clone(), 0)).collect();
+    let mut naming_patterns: Vec<(String, usize)> = Vec::new();
+    // ページ（拡張子を除いたファイル名）ごとの形式。自然順で最初に出てきた順に並べる
+    let mut pages: Vec<(String, Vec<String>)> = Vec::new();
+    let mut page_index: HashMap<String, usize> = HashMap::new();
+    for file in &files {
+        let file_path = Path::new(file);
+        let ext = file_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+        let stem = file_path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Some(count) = counts.iter_mut().find(|(e, _)| *e == ext) {
+            count.1 += 1;
+        }
+        let pattern = naming_pattern(&stem);
+        match naming_patterns.iter_mut().find(|(p, _)| *p == pattern) {
+            Some(entry) => entry.1 += 1,
+            None => naming_patterns.push((pattern, 1)),
+        }
+        let index = *page_index
+            .entry(normalize_for_natural_sort(&stem))
+            .or_insert_with(|| {
+                pages.push((stem, Vec::new()));
+                pages.len() - 1
+            });
+        let kind = canonical_extension(&ext);
+        if !pages[index].1.contains(&kind) {
+            pages[index].1.push(kind);
+        }
+    }
 
-    // 自然順ソート（ファイル名でソート）
-    files.sort_by(|a, b| {
-        let name_a = PathBuf::from(a)
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("")
-            .to_lowercase();
-        let name_b = PathBuf::from(b)
+    let mut kinds: Vec<String> = Vec::new();
+    for ext in &expected {
+        let kind = canonical_extension(ext);
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+    let unpaired: Vec<UnpairedPage> = if kinds.len() < 2 {
+        Vec::new()
+    } else {
+        pages
+            .into_iter()
+            .filter(|(_, present)| present.len() < kinds.len())
+            .map(|(stem, present)| UnpairedPage {
+                missing: kinds
+                    .iter()
+                    .filter(|k| !present.contains(k))
+                    .cloned()
+                    .collect(),
+                stem,
+                present,
+            })
+            .collect()
+    };
+
+    let mut warnings = Vec::new();
+    if files.is_empty() {
+        warnings.push(format!(
+            "対象の形式 ({}) のファイルがありません",
+            expected.join(" / ")
+        ));
+    } else {
+        for kind in &kinds {
+            let found = counts
+                .iter()
+                .any(|(e, n)| *n > 0 && canonical_extension(e) == *kind);
+            if !found {
+                warnings.push(format!("{} のファイルがありません", kind));
+            }
+        }
+    }
+    if naming_patterns.len() > 1 {
+        warnings.push(format!(
+            "ファイル名の付け方が {} 種類混在しています: {}",
+            naming_patterns.len(),
+            naming_patterns
+                .iter()
+                .map(|(p, n)| format!("{} ({}件)", p, n))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if !unpaired.is_empty() {
+        warnings.push(format!(
+            "対になるファイルがないページが {} 件あります",
+            unpaired.len()
+        ));
+    }
+
+    Ok(JobValidation {
+        total_files: files.len(),
+        counts,
+        naming_patterns,
+        unpaired,
+        warnings,
+    })
+}
+
+// 自然順比較用にファイル名を正規化
+// NFKC で全角数字（０-９）→半角、㌻→ページ 等を揃えてから小文字化する
+fn normalize_for_natural_sort(name: &str) -> String {
+    use unicode_normalization::UnicodeNormalization;
+    name.nfkc().collect::<String>().to_lowercase()
+}
+
+// 自然順ソート（フォルダ単位でまとめ、フォルダ内はファイル名でソート）
+// サブフォルダを走査した場合も章ごとのページが混ざらないようにする
+// 正規化したキーは1件につき1回だけ作る（比較のたびに NFKC 変換しない）
+fn sort_paths_natural(files: &mut [String]) {
+    let key = |p: &str| {
+        let path = Path::new(p);
+        let parent = path
+            .parent()
+            .map(|d| normalize_for_natural_sort(&d.to_string_lossy()))
+            .unwrap_or_default();
+        let name = path
             .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("")
-            .to_lowercase();
-        natord::compare(&name_a, &name_b)
+            .map(|n| normalize_for_natural_sort(&n.to_string_lossy()))
+            .unwrap_or_default();
+        (parent, name)
+    };
+    let mut keyed: Vec<((String, String), String)> = files
+        .iter_mut()
+        .map(|p| (key(p), std::mem::take(p)))
+        .collect();
+    keyed.sort_by(|((dir_a, name_a), _), ((dir_b, name_b), _)| {
+        natord::compare(dir_a, dir_b).then_with(|| natord::compare(name_a, name_b))
     });
-
-    Ok(files)
+    for (slot, (_, path)) in files.iter_mut().zip(keyed) {
+        *slot = path;
+    }
 }
 
 // ============== 差分計算 ==============
 
-#[derive(Deserialize)]
+// 差分のしきい値: 数値ならそのまま 0〜255、{ "percent": 0〜100 } なら 255 に対する割合
+// どちらもチャンネルごとの差の絶対値と比較する（percent はチャンネル単位で 0〜255 に換算してから比較）
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+enum DiffThreshold {
+    Raw(u8),
+    Percent { percent: f64 },
+}
+
+impl DiffThreshold {
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Raw(v) => v,
+            Self::Percent { percent } => (percent.clamp(0.0, 100.0) / 100.0 * 255.0).round() as u8,
+        }
+    }
+}
+
+impl From<u8> for DiffThreshold {
+    fn from(v: u8) -> Self {
+        Self::Raw(v)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
 struct CropBounds {
     left: u32,
     top: u32,
@@ -724,12 +3011,162 @@ struct CropBounds {
     bottom: u32,
 }
 
-#[derive(Serialize, Clone)]
+impl CropBounds {
+    fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.left && x < self.right && y >= self.top && y < self.bottom
+    }
+
+    // クロップ範囲を検証し、画像サイズ (width x height) に収める
+    // 反転・面積ゼロ・画像の完全な外側はエラー（u32 の引き算でパニックさせない）
+    fn clamp_to(&self, width: u32, height: u32) -> Result<CropBounds, AppError> {
+        if self.left >= self.right || self.top >= self.bottom {
+            return Err(AppError::InvalidCrop(format!(
+                "クロップ範囲が不正です (left={}, top={}, right={}, bottom={})",
+                self.left, self.top, self.right, self.bottom
+            )));
+        }
+        let clamped = CropBounds {
+            left: self.left,
+            top: self.top,
+            right: self.right.min(width),
+            bottom: self.bottom.min(height),
+        };
+        if clamped.left >= clamped.right || clamped.top >= clamped.bottom {
+            return Err(AppError::InvalidCrop(format!(
+                "クロップ範囲が画像 ({}x{}) の外側です (left={}, top={}, right={}, bottom={})",
+                width, height, self.left, self.top, self.right, self.bottom
+            )));
+        }
+        Ok(clamped)
+    }
+}
+
+// 除外領域（日付・ジョブ番号スタンプ等）の判定
+fn in_ignore_region(x: u32, y: u32, regions: &[CropBounds]) -> bool {
+    regions.iter().any(|r| r.contains(x, y))
+}
+
+// 除外領域をキャッシュキーに含めるための識別子
+fn ignore_regions_tag(regions: &[CropBounds]) -> String {
+    regions
+        .iter()
+        .map(|r| format!("_ign{}-{}-{}-{}", r.left, r.top, r.right, r.bottom))
+        .collect()
+}
+
+// width x height の画像のうち除外領域に含まれるピクセル数（重なりは1回だけ数える）
+fn ignored_pixel_count(regions: &[CropBounds], width: u32, height: u32) -> u64 {
+    let clipped: Vec<CropBounds> = regions
+        .iter()
+        .map(|r| CropBounds {
+            left: r.left.min(width),
+            top: r.top.min(height),
+            right: r.right.min(width),
+            bottom: r.bottom.min(height),
+        })
+        .filter(|r| r.left < r.right && r.top < r.bottom)
+        .collect();
+    // 上端・下端で横長の帯に分け、帯ごとに x 区間の和集合の長さを足す
+    let mut ys: Vec<u32> = clipped.iter().flat_map(|r| [r.top, r.bottom]).collect();
+    ys.sort_unstable();
+    ys.dedup();
+    let mut total = 0u64;
+    for band in ys.windows(2) {
+        let (y0, y1) = (band[0], band[1]);
+        let mut spans: Vec<(u32, u32)> = clipped
+            .iter()
+            .filter(|r| r.top <= y0 && r.bottom >= y1)
+            .map(|r| (r.left, r.right))
+            .collect();
+        spans.sort_unstable();
+        let mut covered = 0u64;
+        let mut end = 0u32;
+        for (l, r) in spans {
+            let l = l.max(end);
+            if r > l {
+                covered += (r - l) as u64;
+                end = r;
+            }
+        }
+        total += covered * (y1 - y0) as u64;
+    }
+    total
+}
+
+// 差分ピクセルの割合 (0..1)。分母は除外領域を除いた比較対象のピクセル数
+fn diff_ratio(diff_count: u32, width: u32, height: u32, regions: &[CropBounds]) -> f64 {
+    let comparable = width as u64 * height as u64 - ignored_pixel_count(regions, width, height);
+    if comparable == 0 {
+        0.0
+    } else {
+        (diff_count as f64 / comparable as f64).min(1.0)
+    }
+}
+
+// 円全体が除外領域に収まるマーカーを取り除き、残りに id を振り直す（番号に欠けを作らない）
+fn drop_ignored_markers(markers: Vec<DiffMarker>, regions: &[CropBounds]) -> Vec<DiffMarker> {
+    if regions.is_empty() {
+        return markers;
+    }
+    let mut kept: Vec<DiffMarker> = markers
+        .into_iter()
+        .filter(|m| {
+            !regions.iter().any(|r| {
+                m.x - m.radius >= r.left as f64
+                    && m.x + m.radius <= r.right as f64
+                    && m.y - m.radius >= r.top as f64
+                    && m.y + m.radius <= r.bottom as f64
+            })
+        })
+        .collect();
+    sort_and_number_markers(&mut kept);
+    kept
+}
+
+// 除外領域の塗り色（差分なしの黒背景と区別できる暗いグレー）
+const IGNORE_FILL: [u8; 4] = [48, 48, 48, 255];
+
+#[derive(Serialize, Deserialize, Clone)]
 struct DiffMarker {
     x: f64,
     y: f64,
     radius: f64,
     count: u32,
+    #[serde(default)]
+    bbox: MarkerBounds, // クラスタ内の差分ピクセルの外接矩形（円より狭い範囲を示せる）
+    #[serde(default)]
+    id: u32, // 並び順に振る1始まりの番号（同じ入力なら毎回同じ。レポートの番号と一致）
+    #[serde(default)]
+    kind: MarkerKind,
+}
+
+// 差分の種類（A = 修正前、B = 修正後として判定）
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MarkerKind {
+    Added,   // A は背景で B にだけ描かれている
+    Removed, // A にあって B では背景になっている（要素の欠落）
+    #[default]
+    Changed, // 両方に何か描かれている（色・形の変化、移動）
+}
+
+impl MarkerKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Changed => "changed",
+        }
+    }
+}
+
+// マーカーの外接矩形（right / bottom は含まない）
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+struct MarkerBounds {
+    left: f64,
+    top: f64,
+    right: f64,
+    bottom: f64,
 }
 
 #[derive(Serialize)]
@@ -739,9 +3176,33 @@ struct DiffSimpleResult {
     diff_src: String,
     has_diff: bool,
     diff_count: u32,
+    diff_ratio: f64, // diff_count / 比較対象のピクセル数（除外領域を除く）。0..1
     markers: Vec<DiffMarker>,
     image_width: u32,
     image_height: u32,
+    composite_src: Option<String>, // include_composite 時のみ: A | diff | B を横に並べた JPEG
+    max_delta: u8, // 差分ピクセルの最大チャンネル差（style.intensity の明るさの目盛り）。mode = "deltae" なら最大 ΔE
+    worst: Option<WorstDiff>, // max_delta を持つ画素の位置（差分なしなら None）
+    roi: Option<CropBounds>, // roi 指定時のみ: 比較した範囲（画像・マーカーはこの範囲のローカル座標）
+    display_scale: f64, // 出力画像の縮小率（display_max_dim 未指定・縮小不要なら 1.0）。マーカー × この値 = 表示座標
+    timings: Option<DiffTimings>, // options.profile 時のみ
+}
+
+// 差分コマンドの処理段階ごとの所要時間（ミリ秒）。profile 指定時のみ返す
+// resize_ms はサイズ合わせ・クロップ・縮小・位置合わせ、diff_ms はマーカー抽出まで含む
+#[derive(Serialize, Default, Clone, Copy)]
+struct DiffTimings {
+    decode_ms: u64,
+    resize_ms: u64,
+    diff_ms: u64,
+    encode_ms: u64,
+}
+
+// 前回の計測点からの経過ミリ秒を返し、計測点を現在に進める
+fn lap_ms(lap: &mut Instant) -> u64 {
+    let ms = lap.elapsed().as_millis() as u64;
+    *lap = Instant::now();
+    ms
 }
 
 // Phase1用: 画像エンコードなしの軽量チェック結果
@@ -749,9 +3210,11 @@ struct DiffSimpleResult {
 struct DiffCheckSimpleResult {
     has_diff: bool,
     diff_count: u32,
+    diff_ratio: f64, // scale < 1 のときは縮小後の画像での割合
     markers: Vec<DiffMarker>,
     image_width: u32,
     image_height: u32,
+    timings: Option<DiffTimings>, // options.profile 時のみ（encode_ms は常に 0）
 }
 
 #[derive(Serialize)]
@@ -759,12 +3222,27 @@ struct DiffCheckHeatmapResult {
     has_diff: bool,
     diff_probability: f64,
     high_density_count: u32,
-    markers: Vec<DiffMarker>,
+    markers: Vec<DiffMarker>, // TIFF（= image_width x image_height）のピクセル座標
     image_width: u32,
     image_height: u32,
+    align_offset: Option<AlignOffset>, // auto_align 時に検出したずれ量
+    psd_markers: Vec<DiffMarker>,      // markers をクロップ前の元PSD座標に変換したもの
+    crop_left: u32,
+    crop_top: u32,
+    scale_x: f64, // TIFF 1px あたりの PSD px（psd_x = crop_left + (x - dx) * scale_x）
+    scale_y: f64,
+    region_counts: Option<RegionDiffCounts>, // params.classify_regions 時のみ
+    timings: Option<DiffTimings>,            // params.profile 時のみ
 }
 
-#[derive(Serialize)]
+// 位置合わせで検出した平行移動量（A を (dx, dy) ずらすと B に重なる）
+#[derive(Serialize, Clone, Copy)]
+struct AlignOffset {
+    dx: i32,
+    dy: i32,
+}
+
+#[derive(Serialize)]
 struct DiffHeatmapResult {
     src_a: String,
     src_b: String,
@@ -773,13 +3251,29 @@ struct DiffHeatmapResult {
     has_diff: bool,
     diff_probability: f64,
     high_density_count: u32,
-    markers: Vec<DiffMarker>,
+    markers: Vec<DiffMarker>, // TIFF（= image_width x image_height）のピクセル座標
     image_width: u32,
     image_height: u32,
+    align_offset: Option<AlignOffset>, // auto_align 時に検出したずれ量
+    psd_markers: Vec<DiffMarker>,      // markers をクロップ前の元PSD座標に変換したもの
+    crop_left: u32,
+    crop_top: u32,
+    scale_x: f64, // TIFF 1px あたりの PSD px（psd_x = crop_left + (x - dx) * scale_x）
+    scale_y: f64,
+    region_counts: Option<RegionDiffCounts>, // params.classify_regions 時のみ
+    timings: Option<DiffTimings>,            // params.profile 時のみ
+}
+
+#[derive(Serialize)]
+struct DiffMaskResult {
+    mask_base64: String, // 1bpp・MSB first・各行はバイト境界まで0埋め（1行 = ceil(width / 8) バイト）
+    mask_src: String,    // 同じマスクのグレースケール PNG（差分 = 白, 一致 = 黒）
+    diff_count: u32,
+    width: u32,
+    height: u32,
 }
 
 // panicメッセージを文字列として抽出
-#[allow(dead_code)]
 fn extract_panic_message(panic_info: &Box<dyn std::any::Any + Send>) -> String {
     if let Some(s) = panic_info.downcast_ref::<&str>() {
         s.to_string()
@@ -814,37 +3308,199 @@ fn is_image_valid(img: &DynamicImage) -> bool {
     non_black * 100 / sampled > 3 // 3%以上が非黒なら有効
 }
 
-/// PSD解析の堅牢ラッパー: フォールバックパーサーを優先し、失敗時のみpsd crateを使用
-fn decode_psd_robust(bytes: &[u8]) -> Result<DynamicImage, String> {
+// decode_psd_bytes が実際に使ったデコーダー（PsdImageResult.decoder_used）
+const PSD_DECODER_FALLBACK: &str = "fallback";
+const PSD_DECODER_CRATE: &str = "psd_crate";
+
+/// PSDバイト列のデコード共通処理（parse_psd / parse_psd_bytes / decode_psd_to_image から呼ぶ）
+/// フォールバックパーサーを優先し、失敗時のみpsd crateを使用
+/// 戻り値の2番目は PSD_DECODER_FALLBACK / PSD_DECODER_CRATE
+/// bytes は psd crate のスレッドへそのまま渡す（タイムアウト時も複製せずにスレッド側で解放される）
+fn decode_psd_bytes(bytes: Vec<u8>) -> Result<(DynamicImage, &'static str), AppError> {
+    // 展開先を確保できないサイズは、どちらのデコーダーにも渡さない（psd crate は確保失敗で異常終了する）
+    if let Some(Ok(header)) = bytes.first_chunk::<26>().map(PsdHeader::parse) {
+        header.ensure_addressable()?;
+    }
+
     // 1. フォールバックパーサーを優先（Image Data Sectionを直接読む — 最も信頼性が高い）
-    if let Ok(img) = decode_psd_fallback(bytes) {
-        return Ok(img);
-    }
-
-    // 2. フォールバック失敗時はpsd crateを試行
-    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
-        let psd = Psd::from_bytes(bytes).map_err(|e| format!("Failed to parse PSD: {}", e))?;
-        let width = psd.width();
-        let height = psd.height();
-        let rgba = psd.rgba();
-        let img_buf: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, rgba)
-            .ok_or_else(|| "Failed to create image buffer".to_string())?;
-        Ok::<DynamicImage, String>(DynamicImage::ImageRgba8(img_buf))
-    }));
-
-    match result {
-        Ok(Ok(img)) if is_image_valid(&img) => Ok(img),
-        Ok(Ok(_)) => Err(
+    if let Ok(img) = decode_psd_fallback(&bytes) {
+        return Ok((img, PSD_DECODER_FALLBACK));
+    }
+
+    // ヘッダーは正しいがデータが足りない → コピー途中のファイル（psd crate も失敗するので試さない）
+    if let Some(expected) = psd_expected_len(&bytes) {
+        return Err(AppError::Truncated {
+            message: format!(
+                "PSDファイルが途中までしかありません（{} / {} bytes）。コピー中の可能性があります",
+                bytes.len(),
+                expected
+            ),
+            bytes_read: bytes.len() as u64,
+            expected: expected as u64,
+        });
+    }
+
+    // 2. フォールバック失敗時はpsd crateを試行（無限ループ対策で別スレッド + 制限時間付き）
+    // panic した場合は送信せずにスレッドが終わるので、受信側は Disconnected になる
+    let timeout_secs = PSD_DECODE_TIMEOUT_SECS.load(Ordering::Relaxed);
+    if STRANDED_PSD_THREADS.load(Ordering::Relaxed) >= MAX_STRANDED_PSD_THREADS {
+        return Err(AppError::Timeout(
+            "以前に制限時間を過ぎたPSDのデコードがまだ終わっていないため、psd crate での再試行を見送りました"
+                .to_string(),
+        ));
+    }
+    let state = Arc::new(AtomicU8::new(PSD_THREAD_RUNNING));
+    let thread_state = Arc::clone(&state);
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name("psd-crate".to_string())
+        .spawn(move || {
+            // panic で抜けた場合も数え直せるよう Drop で終了を記録する
+            let _finished = PsdThreadFinished(thread_state);
+            let result = Psd::from_bytes(&bytes)
+                .map_err(|e| format!("Failed to parse PSD: {}", e))
+                .and_then(|psd| {
+                    let rgba = psd.rgba();
+                    ImageBuffer::<Rgba<u8>, Vec<u8>>::from_raw(psd.width(), psd.height(), rgba)
+                        .map(DynamicImage::ImageRgba8)
+                        .ok_or_else(|| "Failed to create image buffer".to_string())
+                });
+            // 受信側がタイムアウト済みなら送信は失敗するが無視してよい
+            let _ = tx.send(result);
+        })
+        .map_err(|e| AppError::Other(format!("Failed to spawn PSD thread: {}", e)))?;
+
+    // タイムアウトしたスレッドは止められないため、そのまま終了を待たずに切り離す
+    match rx.recv_timeout(std::time::Duration::from_secs(timeout_secs)) {
+        Ok(Ok(img)) if is_image_valid(&img) => Ok((img, PSD_DECODER_CRATE)),
+        Ok(Ok(_)) => Err(AppError::Decode(
             "PSD画像のデコード結果が不正です（画像データが破損している可能性があります）"
                 .to_string(),
-        ),
-        Ok(Err(e)) => Err(e),
-        Err(_) => Err("PSD解析中にエラーが発生しました".to_string()),
+        )),
+        Ok(Err(e)) => Err(AppError::Decode(e)),
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            // 同時に終わっていた場合は数えない
+            if state
+                .compare_exchange(
+                    PSD_THREAD_RUNNING,
+                    PSD_THREAD_STRANDED,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                STRANDED_PSD_THREADS.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(AppError::Timeout(format!(
+                "PSDのデコードが{}秒以内に終わりませんでした（ファイルが破損している可能性があります）",
+                timeout_secs
+            )))
+        }
+        // psd crate が panic した（スレッドだけが終了している）
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Err(AppError::Decode(
+            "PSD解析中にエラーが発生しました".to_string(),
+        )),
+    }
+}
+
+const PSD_THREAD_RUNNING: u8 = 0;
+const PSD_THREAD_FINISHED: u8 = 1;
+const PSD_THREAD_STRANDED: u8 = 2; // 制限時間を過ぎて切り離された（STRANDED_PSD_THREADS に数えている）
+
+// psd crate のスレッドの終了を記録する。切り離し済みなら STRANDED_PSD_THREADS から外す
+struct PsdThreadFinished(Arc<AtomicU8>);
+
+impl Drop for PsdThreadFinished {
+    fn drop(&mut self) {
+        if self.0.swap(PSD_THREAD_FINISHED, Ordering::AcqRel) == PSD_THREAD_STRANDED {
+            STRANDED_PSD_THREADS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// ヘッダーとセクション長から必要なファイル長を求め、実際より長ければ返す（途中までのファイル検出用）
+// 8BPS ヘッダーが読めない場合と、長さが事前に分からない ZIP 圧縮の画像データは None
+fn psd_expected_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < 26 || &bytes[0..4] != b"8BPS" {
+        return None;
+    }
+    // 32bit ビルドで usize に収まらない値（PSB の 8 バイト長など）は usize::MAX に丸める
+    let be = |at: usize, n: usize| -> Option<usize> {
+        bytes
+            .get(at..at.checked_add(n)?)
+            .map(|b| b.iter().fold(0u64, |acc, &x| (acc << 8) | x as u64))
+            .map(|v| usize::try_from(v).unwrap_or(usize::MAX))
+    };
+    let is_psb = be(4, 2)? == 2;
+    let channels = be(12, 2)?;
+    let height = be(14, 4)?;
+    let width = be(18, 4)?;
+    let depth = be(22, 2)?;
+
+    // Color Mode Data / Image Resources / Layer and Mask Information
+    let mut offset = 26;
+    for section in 0..3 {
+        let len_size = if section == 2 && is_psb { 8 } else { 4 };
+        let Some(len) = be(offset, len_size) else {
+            return Some(offset.saturating_add(len_size));
+        };
+        offset = offset.saturating_add(len_size).saturating_add(len);
+    }
+
+    let Some(compression) = be(offset, 2) else {
+        return Some(offset.saturating_add(2));
+    };
+    offset += 2;
+    let row_bytes = if depth == 1 {
+        width.div_ceil(8)
+    } else {
+        width.saturating_mul(depth) / 8
+    };
+    let rows = channels.saturating_mul(height);
+    let expected = match compression {
+        0 => offset.saturating_add(rows.saturating_mul(row_bytes)),
+        1 => {
+            let count_size = if is_psb { 4 } else { 2 };
+            let counts_end = offset.saturating_add(rows.saturating_mul(count_size));
+            if counts_end > bytes.len() {
+                return Some(counts_end);
+            }
+            (0..rows)
+                .map(|r| be(offset + r * count_size, count_size).unwrap_or(0))
+                .fold(counts_end, usize::saturating_add)
+        }
+        _ => return None,
+    };
+    (expected > bytes.len()).then_some(expected)
+}
+
+// セクション長を offset に足す。usize に収まらなければ（32bit ビルドの巨大 PSB）エラー
+fn psd_offset_add(offset: usize, len: u64) -> Result<usize, String> {
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| offset.checked_add(len))
+        .ok_or_else(|| "PSD section length exceeds addressable memory".to_string())
+}
+
+// 1bit (Bitmap) のパック済み行 → 1画素1バイト（ビット 1 = 黒 0、0 = 白 255。MSB が左端）
+fn unpack_bitmap_rows(packed: &[u8], width: usize, height: usize, row_bytes: usize) -> Vec<u8> {
+    if width == 0 {
+        return Vec::new();
+    }
+    let mut out = vec![255u8; width * height];
+    for (row, src) in out.chunks_exact_mut(width).zip(packed.chunks(row_bytes)) {
+        for (x, px) in row.iter_mut().enumerate() {
+            if src.get(x / 8).is_some_and(|b| b & (0x80 >> (x % 8)) != 0) {
+                *px = 0;
+            }
+        }
     }
+    out
 }
 
 // PSDの合成画像(Image Data Section)のみを読み取る。レイヤー合成は行わない。
-// RLE圧縮・非圧縮・CMYK/RGBカラーモードに対応。
+// RLE圧縮・ZIP圧縮・非圧縮・CMYK/RGB/グレースケール/インデックスカラー/モノクロ2階調 (1bit) に対応。
+// 32bit (HDR) は非圧縮・ZIPのみ（RLEは8bit・1bit専用）。
 
 /// PSDバイト列からRGBA DynamicImageをデコード（フォールバック用）
 fn decode_psd_fallback(bytes: &[u8]) -> Result<DynamicImage, String> {
@@ -870,91 +3526,188 @@ fn decode_psd_fallback(bytes: &[u8]) -> Result<DynamicImage, String> {
     let depth = read_u16(bytes, &mut offset)?;
     let color_mode = read_u16(bytes, &mut offset)?;
 
-    if depth != 8 {
+    // 1bit はモノクロ2階調 (Bitmap, color_mode = 0) のみ
+    let is_bitmap = depth == 1 && color_mode == 0;
+    if depth != 8 && depth != 32 && !is_bitmap {
         return Err(format!(
             "フォールバックパーサーは{}bit深度に未対応です",
             depth
         ));
     }
+    let sample_bytes = (depth / 8).max(1) as usize;
+
+    // Color Mode Data セクション（インデックスカラーのみ 256色パレットを使う）
+    // パレットはプレーン形式: R×256, G×256, B×256
+    let color_data_len = read_u32(bytes, &mut offset)? as u64;
+    let palette = if color_mode == 2 {
+        if color_data_len < 768 || offset + 768 > bytes.len() {
+            return Err("Indexed PSD has no color table".to_string());
+        }
+        Some(&bytes[offset..offset + 768])
+    } else {
+        None
+    };
+    offset = psd_offset_add(offset, color_data_len)?;
+
+    // Image Resources セクション（CMYKのみ埋め込みICCプロファイルを使う）
+    let resource_len = read_u32(bytes, &mut offset)? as u64;
+    let resource_end = psd_offset_add(offset, resource_len)?;
+    let icc_profile = if color_mode == 4 {
+        bytes
+            .get(offset..resource_end)
+            .and_then(|section| find_image_resource(section, PSD_RESOURCE_ICC_PROFILE))
+    } else {
+        None
+    };
+    offset = resource_end;
 
-    // Color Mode Data セクションをスキップ
-    let color_data_len = read_u32(bytes, &mut offset)? as usize;
-    offset += color_data_len;
-
-    // Image Resources セクションをスキップ
-    let resource_len = read_u32(bytes, &mut offset)? as usize;
-    offset += resource_len;
-
-    // Layer and Mask Information セクションをスキップ
+    // Layer and Mask Information セクションをスキップ（PSB は長さが 8 バイト）
     let layer_len = if is_psb {
-        read_u64(bytes, &mut offset)? as usize
+        read_u64(bytes, &mut offset)?
     } else {
-        read_u32(bytes, &mut offset)? as usize
+        read_u32(bytes, &mut offset)? as u64
     };
-    offset += layer_len;
+    // Layer Info のレイヤー数が負なら、合成画像の最初の追加チャンネルが透明度（それ以外の追加チャンネルは
+    // 保存された選択範囲・スポットカラーなので使わない）
+    let merged_has_alpha = {
+        let mut p = offset;
+        let info_len = if is_psb {
+            read_u64(bytes, &mut p)
+        } else {
+            read_u32(bytes, &mut p).map(|v| v as u64)
+        };
+        layer_len >= (p - offset + 2) as u64
+            && info_len.is_ok_and(|len| len >= 2)
+            && read_u16(bytes, &mut p).is_ok_and(|count| (count as i16) < 0)
+    };
+    offset = psd_offset_add(offset, layer_len)?;
 
     // Image Data Section
     let compression = read_u16(bytes, &mut offset)?;
-    let ch_to_read = if color_mode == 4 {
+    let ch_to_read = if color_mode == 4 && merged_has_alpha {
+        channels.min(5) // CMYK + 透明度
+    } else if color_mode == 4 {
         channels.min(4) // CMYK: 4チャンネル
+    } else if color_mode == 1 && merged_has_alpha {
+        channels.min(2) // Grayscale + 透明度
+    } else if color_mode == 1 {
+        channels.min(1) // Grayscale: グレーのみ
+    } else if color_mode == 2 || is_bitmap {
+        channels.min(1) // Indexed: パレット番号のみ / Bitmap: 1bit の白黒
+    } else if color_mode == 3 && merged_has_alpha {
+        channels.min(4) // RGB + 透明度
     } else {
         channels.min(3) // RGB等: 3チャンネル
     };
-    let pixel_count = width * height;
+    // アルファとして扱うチャンネル番号（32bit のガンマ変換から除外する）
+    let alpha_channel = match color_mode {
+        1 if ch_to_read == 2 => Some(1),
+        3 if ch_to_read == 4 => Some(3),
+        4 if ch_to_read == 5 => Some(4),
+        _ => None,
+    };
+    // 展開後のサイズ（RGBA・全チャンネル分）が usize に収まることは decode_psd_bytes で確認済みだが、
+    // 単独で呼ばれても掛け算があふれないようにしておく
+    let too_large = || "PSD image is too large to decode".to_string();
+    let pixel_count = width
+        .checked_mul(height)
+        .filter(|n| n.checked_mul(4).is_some())
+        .ok_or_else(too_large)?;
+    // 1bit は各行がバイト境界まで0埋めされている
+    let row_bytes = if is_bitmap {
+        width.div_ceil(8)
+    } else {
+        width * sample_bytes
+    };
+    let channel_len = row_bytes.checked_mul(height).ok_or_else(too_large)?;
+    let all_channels_len = channels.checked_mul(channel_len).ok_or_else(too_large)?;
 
     let channel_data: Vec<Vec<u8>> = match compression {
         0 => {
             // 非圧縮 (Raw)
             let mut chs = Vec::with_capacity(ch_to_read);
-            for c in 0..channels {
-                if c < ch_to_read {
-                    if offset + pixel_count > bytes.len() {
-                        return Err("PSD data truncated (raw channel)".to_string());
-                    }
-                    chs.push(bytes[offset..offset + pixel_count].to_vec());
-                }
-                offset += pixel_count;
+            for c in 0..ch_to_read {
+                let start = offset + c * channel_len;
+                let data = start
+                    .checked_add(channel_len)
+                    .and_then(|end| bytes.get(start..end))
+                    .ok_or_else(|| "PSD data truncated (raw channel)".to_string())?;
+                chs.push(data.to_vec());
             }
             chs
         }
+        1 if depth == 32 => {
+            return Err(format!("{}bit深度のRLE圧縮には未対応です", depth));
+        }
         1 => {
             // RLE圧縮
-            // 各スキャンラインのバイト数を読み取り
+            // 各スキャンラインのバイト数を読み取り（PSB は 4 バイト、PSD は 2 バイト）
             let total_rows = channels * height;
-            if offset + total_rows * 2 > bytes.len() {
+            let count_size = if is_psb { 4 } else { 2 };
+            if total_rows
+                .checked_mul(count_size)
+                .and_then(|n| n.checked_add(offset))
+                .is_none_or(|end| end > bytes.len())
+            {
                 return Err("PSD data truncated (RLE row counts)".to_string());
             }
             let mut row_counts = Vec::with_capacity(total_rows);
             for _ in 0..total_rows {
-                row_counts.push(read_u16(bytes, &mut offset)? as usize);
+                let count = if is_psb {
+                    read_u32(bytes, &mut offset)? as usize
+                } else {
+                    read_u16(bytes, &mut offset)? as usize
+                };
+                row_counts.push(count);
             }
 
             let mut chs = Vec::with_capacity(ch_to_read);
             let mut row_idx = 0;
             for c in 0..channels {
                 if c < ch_to_read {
-                    let mut ch_data = vec![0u8; pixel_count];
+                    let mut ch_data = vec![0u8; channel_len];
                     let mut pixel_off = 0;
                     for _ in 0..height {
                         let row_len = row_counts[row_idx];
                         row_idx += 1;
-                        if offset + row_len > bytes.len() {
+                        if offset
+                            .checked_add(row_len)
+                            .is_none_or(|end| end > bytes.len())
+                        {
                             return Err("PSD data truncated (RLE data)".to_string());
                         }
-                        decode_packbits(bytes, offset, row_len, &mut ch_data, pixel_off, width);
+                        decode_packbits(bytes, offset, row_len, &mut ch_data, pixel_off, row_bytes);
                         offset += row_len;
-                        pixel_off += width;
+                        pixel_off += row_bytes;
                     }
                     chs.push(ch_data);
                 } else {
                     for _ in 0..height {
-                        offset += row_counts[row_idx];
+                        offset = offset.saturating_add(row_counts[row_idx]);
                         row_idx += 1;
                     }
                 }
             }
             chs
         }
+        2 | 3 => {
+            // ZIP圧縮（全チャンネルが1本のzlibストリームに連結されている）
+            // compression=3 は行ごとの水平差分（prediction）付き
+            let raw = inflate_zip(&bytes[offset..], all_channels_len)?;
+            let mut chs = Vec::with_capacity(ch_to_read);
+            for c in 0..ch_to_read {
+                let mut ch_data = raw[c * channel_len..(c + 1) * channel_len].to_vec();
+                if compression == 3 {
+                    if depth == 32 {
+                        unpredict_rows_f32(&mut ch_data, width);
+                    } else {
+                        unpredict_rows_u8(&mut ch_data, row_bytes);
+                    }
+                }
+                chs.push(ch_data);
+            }
+            chs
+        }
         _ => {
             return Err(format!(
                 "未対応の圧縮方式です (compression={})",
@@ -963,11 +3716,33 @@ fn decode_psd_fallback(bytes: &[u8]) -> Result<DynamicImage, String> {
         }
     };
 
+    // 32bit: リニアfloat → 8bit（アルファチャンネルにはガンマをかけない）
+    let channel_data: Vec<Vec<u8>> = if depth == 32 {
+        channel_data
+            .iter()
+            .enumerate()
+            .map(|(c, ch)| f32_samples_to_u8(ch, alpha_channel != Some(c)))
+            .collect()
+    } else if is_bitmap {
+        // 1bit → 1画素1バイトのグレー（Photoshop の Bitmap は 1 = 黒, 0 = 白）
+        channel_data
+            .iter()
+            .map(|ch| unpack_bitmap_rows(ch, width, height, row_bytes))
+            .collect()
+    } else {
+        channel_data
+    };
+
     // RGBA画像を組み立て
     let mut rgba = vec![0u8; pixel_count * 4];
 
-    if color_mode == 4 {
-        // CMYK → RGB変換
+    if color_mode == 4
+        && channel_data.len() >= 4
+        && icc_profile.is_some_and(|icc| cmyk_to_rgba_icc(icc, &channel_data, &mut rgba))
+    {
+        // CMYK → sRGB（埋め込みICCプロファイルで変換済み）
+    } else if color_mode == 4 {
+        // CMYK → RGB変換（ICCプロファイルなし: 簡易式）
         let c_ch = &channel_data[0];
         let m_ch = &channel_data[1.min(channel_data.len() - 1)];
         let y_ch = &channel_data[2.min(channel_data.len() - 1)];
@@ -989,8 +3764,28 @@ fn decode_psd_fallback(bytes: &[u8]) -> Result<DynamicImage, String> {
             rgba[j + 2] = 255 - ((y + k).min(255) as u8);
             rgba[j + 3] = 255;
         }
+    } else if let Some(palette) = palette {
+        // Indexed: パレット番号 → RGB
+        for (px, &idx) in rgba.chunks_exact_mut(4).zip(channel_data[0].iter()) {
+            let idx = idx as usize;
+            px[0] = palette[idx];
+            px[1] = palette[256 + idx];
+            px[2] = palette[512 + idx];
+            px[3] = 255;
+        }
+    } else if color_mode == 1 || is_bitmap {
+        // Grayscale: 1チャンネル目をRGBに複製、透明度があればアルファとして扱う
+        let gray = &channel_data[0];
+        let alpha = alpha_channel.and_then(|c| channel_data.get(c));
+        for i in 0..pixel_count {
+            let j = i * 4;
+            rgba[j] = gray[i];
+            rgba[j + 1] = gray[i];
+            rgba[j + 2] = gray[i];
+            rgba[j + 3] = alpha.map_or(255, |a| a[i]);
+        }
     } else {
-        // RGB / Grayscale
+        // RGB
         let r = &channel_data[0];
         let g = if channel_data.len() >= 2 {
             &channel_data[1]
@@ -1002,12 +3797,22 @@ fn decode_psd_fallback(bytes: &[u8]) -> Result<DynamicImage, String> {
         } else {
             r
         };
+        let alpha = alpha_channel.and_then(|c| channel_data.get(c));
         for i in 0..pixel_count {
             let j = i * 4;
             rgba[j] = r[i];
             rgba[j + 1] = g[i];
             rgba[j + 2] = b[i];
-            rgba[j + 3] = 255;
+            rgba[j + 3] = alpha.map_or(255, |a| a[i]);
+        }
+    }
+
+    // CMYK の透明度（変換処理はどちらも不透明で書くので、後から差し替える）
+    if color_mode == 4 {
+        if let Some(alpha) = alpha_channel.and_then(|c| channel_data.get(c)) {
+            for (px, &a) in rgba.chunks_exact_mut(4).zip(alpha) {
+                px[3] = a;
+            }
         }
     }
 
@@ -1017,6 +3822,257 @@ fn decode_psd_fallback(bytes: &[u8]) -> Result<DynamicImage, String> {
     Ok(DynamicImage::ImageRgba8(img_buf))
 }
 
+const PSD_RESOURCE_ICC_PROFILE: u16 = 1039;
+
+// Image Resources セクションから指定IDのリソースデータを探す
+// ブロック: "8BIM" + ID(u16) + Pascal文字列名(偶数長にパディング) + サイズ(u32) + データ(偶数長にパディング)
+fn find_image_resource(section: &[u8], id: u16) -> Option<&[u8]> {
+    let mut offset = 0;
+    while offset + 12 <= section.len() {
+        if &section[offset..offset + 4] != b"8BIM" {
+            return None;
+        }
+        let res_id = u16::from_be_bytes([section[offset + 4], section[offset + 5]]);
+        let name_len = section[offset + 6] as usize;
+        let mut pos = offset + 6 + ((name_len + 2) & !1);
+        let size = read_u32(section, &mut pos).ok()? as usize;
+        let data = section.get(pos..pos + size)?;
+        if res_id == id {
+            return Some(data);
+        }
+        offset = pos + ((size + 1) & !1);
+    }
+    None
+}
+
+// 埋め込みICCプロファイル (CMYK) → sRGB 変換。プロファイルが壊れていれば false（簡易式にフォールバック）
+fn cmyk_to_rgba_icc(icc: &[u8], channel_data: &[Vec<u8>], rgba: &mut [u8]) -> bool {
+    let Ok(input) = lcms2::Profile::new_icc(icc) else {
+        return false;
+    };
+    let output = lcms2::Profile::new_srgb();
+    let Ok(transform) = lcms2::Transform::<[u8; 4], [u8; 3]>::new(
+        &input,
+        lcms2::PixelFormat::CMYK_8,
+        &output,
+        lcms2::PixelFormat::RGB_8,
+        lcms2::Intent::Perceptual,
+    ) else {
+        return false;
+    };
+
+    // 行単位程度のチャンクでインターリーブ → 変換（全体のCMYKコピーを持たない）
+    const CHUNK: usize = 64 * 1024;
+    let (c_ch, m_ch, y_ch, k_ch) = (
+        &channel_data[0],
+        &channel_data[1],
+        &channel_data[2],
+        &channel_data[3],
+    );
+    let mut src = Vec::with_capacity(CHUNK);
+    let mut dst = vec![[0u8; 3]; CHUNK];
+    for (chunk_idx, out) in rgba.chunks_mut(CHUNK * 4).enumerate() {
+        let start = chunk_idx * CHUNK;
+        let n = out.len() / 4;
+        src.clear();
+        src.extend((start..start + n).map(|i| [c_ch[i], m_ch[i], y_ch[i], k_ch[i]]));
+        transform.transform_pixels(&src, &mut dst[..n]);
+        for (px, rgb) in out.chunks_exact_mut(4).zip(&dst[..n]) {
+            px[..3].copy_from_slice(rgb);
+            px[3] = 255;
+        }
+    }
+    true
+}
+
+// ============== フォールバックTIFFパーサー ==============
+// RIP ソフトが出力する一部の TIFF は image crate で開けないため、
+// 先頭IFDのストリップを直接読む。非圧縮 / PackBits・8bit・チャンキー配置のみ対応。
+
+const TIFF_TAG_WIDTH: u16 = 256;
+const TIFF_TAG_HEIGHT: u16 = 257;
+const TIFF_TAG_BITS_PER_SAMPLE: u16 = 258;
+const TIFF_TAG_COMPRESSION: u16 = 259;
+const TIFF_TAG_PHOTOMETRIC: u16 = 262;
+const TIFF_TAG_STRIP_OFFSETS: u16 = 273;
+const TIFF_TAG_SAMPLES_PER_PIXEL: u16 = 277;
+const TIFF_TAG_ROWS_PER_STRIP: u16 = 278;
+const TIFF_TAG_STRIP_BYTE_COUNTS: u16 = 279;
+const TIFF_TAG_PLANAR_CONFIG: u16 = 284;
+
+// フォールバックで展開する画素データの上限（バイト）
+const TIFF_FALLBACK_MAX_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// TIFFバイト列から DynamicImage をデコード（フォールバック用）
+fn decode_tiff_fallback(bytes: &[u8]) -> Result<DynamicImage, AppError> {
+    let little = match bytes.get(0..4) {
+        Some(b"II*\0") => true,
+        Some(b"MM\0*") => false,
+        _ => return Err(AppError::Decode("Not a TIFF file".to_string())),
+    };
+    let truncated = || AppError::Decode("TIFF data truncated".to_string());
+    let u16_at = |at: usize| -> Result<u16, AppError> {
+        let b = bytes.get(at..at + 2).ok_or_else(truncated)?;
+        Ok(if little {
+            u16::from_le_bytes([b[0], b[1]])
+        } else {
+            u16::from_be_bytes([b[0], b[1]])
+        })
+    };
+    let u32_at = |at: usize| -> Result<u32, AppError> {
+        let b = bytes.get(at..at + 4).ok_or_else(truncated)?;
+        Ok(if little {
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        } else {
+            u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+        })
+    };
+
+    // 先頭IFDのエントリを tag → 値の配列 に読む（SHORT / LONG のみ使う）
+    let ifd = u32_at(4)? as usize;
+    let entry_count = u16_at(ifd)? as usize;
+    let mut tags: HashMap<u16, Vec<u32>> = HashMap::new();
+    for e in 0..entry_count {
+        let at = ifd + 2 + e * 12;
+        let tag = u16_at(at)?;
+        let typ = u16_at(at + 2)?;
+        let count = u32_at(at + 4)? as usize;
+        let size = match typ {
+            3 => 2,
+            4 => 4,
+            _ => continue,
+        };
+        // 4バイト以下はエントリ内に直接格納、それ以外はオフセット先
+        let base = if size * count <= 4 {
+            at + 8
+        } else {
+            u32_at(at + 8)? as usize
+        };
+        let values = (0..count)
+            .map(|i| {
+                if size == 2 {
+                    u16_at(base + i * 2).map(u32::from)
+                } else {
+                    u32_at(base + i * 4)
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        tags.insert(tag, values);
+    }
+    let first = |tag: u16, default: Option<u32>| -> Result<u32, AppError> {
+        tags.get(&tag)
+            .and_then(|v| v.first().copied())
+            .or(default)
+            .ok_or_else(|| AppError::Decode(format!("TIFF tag {} is missing", tag)))
+    };
+
+    let width = first(TIFF_TAG_WIDTH, None)? as usize;
+    let height = first(TIFF_TAG_HEIGHT, None)? as usize;
+    let bits = first(TIFF_TAG_BITS_PER_SAMPLE, Some(1))?;
+    let compression = first(TIFF_TAG_COMPRESSION, Some(1))?;
+    let photometric = first(TIFF_TAG_PHOTOMETRIC, None)?;
+    let spp = first(TIFF_TAG_SAMPLES_PER_PIXEL, Some(1))? as usize;
+    let rows_per_strip = first(TIFF_TAG_ROWS_PER_STRIP, Some(u32::MAX))? as usize;
+    let planar = first(TIFF_TAG_PLANAR_CONFIG, Some(1))?;
+
+    if bits != 8 {
+        return Err(AppError::Decode(format!(
+            "フォールバックTIFFパーサーは{}bitに未対応です",
+            bits
+        )));
+    }
+    if planar != 1 {
+        return Err(AppError::Decode(
+            "フォールバックTIFFパーサーはプレーナー配置に未対応です".to_string(),
+        ));
+    }
+    if compression != 1 && compression != 32773 {
+        return Err(AppError::Decode(format!(
+            "フォールバックTIFFパーサーは圧縮形式 {} に未対応です",
+            compression
+        )));
+    }
+
+    let offsets = tags
+        .get(&TIFF_TAG_STRIP_OFFSETS)
+        .ok_or_else(|| AppError::Decode("TIFF has no strip offsets".to_string()))?;
+    let counts = tags
+        .get(&TIFF_TAG_STRIP_BYTE_COUNTS)
+        .ok_or_else(|| AppError::Decode("TIFF has no strip byte counts".to_string()))?;
+
+    // ストリップを展開して1枚のチャンキー配列にする（ヘッダーの幅・高さが壊れていても巨大な確保はしない）
+    let data_len = width
+        .checked_mul(spp)
+        .and_then(|n| n.checked_mul(height))
+        .filter(|&n| n as u64 <= TIFF_FALLBACK_MAX_BYTES)
+        .ok_or_else(|| {
+            AppError::Unsupported(format!(
+                "TIFFの画像サイズが大きすぎて展開できません ({}x{}, {}ch)",
+                width, height, spp
+            ))
+        })?;
+    let row_bytes = width * spp;
+    let mut data = vec![0u8; data_len];
+    let rows_per_strip = rows_per_strip.min(height).max(1);
+    let strip_len = rows_per_strip * row_bytes;
+    for (strip, (&offset, &count)) in offsets.iter().zip(counts.iter()).enumerate() {
+        let Some(dst_start) = strip.checked_mul(strip_len).filter(|&s| s < data.len()) else {
+            break;
+        };
+        let dst_len = strip_len.min(data.len() - dst_start);
+        let (offset, count) = (offset as usize, count as usize);
+        if offset
+            .checked_add(count)
+            .is_none_or(|end| end > bytes.len())
+        {
+            return Err(AppError::Decode("TIFF data truncated (strip)".to_string()));
+        }
+        if compression == 1 {
+            let n = count.min(dst_len);
+            data[dst_start..dst_start + n].copy_from_slice(&bytes[offset..offset + n]);
+        } else {
+            decode_packbits(bytes, offset, count, &mut data, dst_start, dst_len);
+        }
+    }
+
+    let (w, h) = (width as u32, height as u32);
+    let bad_size = || AppError::Decode("Failed to create image buffer".to_string());
+    let img = match (photometric, spp) {
+        // 0 = WhiteIsZero（反転グレー）、1 = BlackIsZero
+        (0 | 1, 1 | 2) => {
+            let gray: Vec<u8> = data
+                .chunks_exact(spp)
+                .map(|p| if photometric == 0 { 255 - p[0] } else { p[0] })
+                .collect();
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(w, h, gray).ok_or_else(bad_size)?)
+        }
+        (2, 3) => DynamicImage::ImageRgb8(ImageBuffer::from_raw(w, h, data).ok_or_else(bad_size)?),
+        (2, 4) => DynamicImage::ImageRgba8(ImageBuffer::from_raw(w, h, data).ok_or_else(bad_size)?),
+        // 5 = Separated (CMYK)。ICCは使わず簡易変換
+        (5, 4 | 5) => {
+            let rgb: Vec<u8> = data
+                .chunks_exact(spp)
+                .flat_map(|p| {
+                    let k = 255 - p[3] as u32;
+                    [
+                        ((255 - p[0] as u32) * k / 255) as u8,
+                        ((255 - p[1] as u32) * k / 255) as u8,
+                        ((255 - p[2] as u32) * k / 255) as u8,
+                    ]
+                })
+                .collect();
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(w, h, rgb).ok_or_else(bad_size)?)
+        }
+        _ => {
+            return Err(AppError::Decode(format!(
+                "フォールバックTIFFパーサーは Photometric={} / {}チャンネルに未対応です",
+                photometric, spp
+            )))
+        }
+    };
+    Ok(img)
+}
+
 // PackBits (RLE) デコード
 fn decode_packbits(
     src: &[u8],
@@ -1060,6 +4116,78 @@ fn decode_packbits(
     }
 }
 
+// zlibストリームを展開（expectedバイトを超える分は読まない）
+fn inflate_zip(src: &[u8], expected: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(expected);
+    flate2::read::ZlibDecoder::new(src)
+        .take(expected as u64)
+        .read_to_end(&mut out)
+        .map_err(|e| format!("ZIP展開に失敗しました: {}", e))?;
+    if out.len() < expected {
+        return Err("PSD data truncated (ZIP data)".to_string());
+    }
+    Ok(out)
+}
+
+// ZIP with prediction の復元: 各行で左隣との差分を累積加算（8bit）
+fn unpredict_rows_u8(data: &mut [u8], width: usize) {
+    if width == 0 {
+        return;
+    }
+    for row in data.chunks_mut(width) {
+        for x in 1..row.len() {
+            row[x] = row[x].wrapping_add(row[x - 1]);
+        }
+    }
+}
+
+// ZIP with prediction の復元（32bit）: 行ごとにバイト単位の差分を累積した後、
+// バイトプレーン順（全画素の1バイト目 → 2バイト目 → …）を画素ごとのビッグエンディアンに並べ直す
+fn unpredict_rows_f32(data: &mut [u8], width: usize) {
+    let row_len = width * 4;
+    if row_len == 0 {
+        return;
+    }
+    let mut tmp = vec![0u8; row_len];
+    for row in data.chunks_mut(row_len) {
+        for x in 1..row.len() {
+            row[x] = row[x].wrapping_add(row[x - 1]);
+        }
+        if row.len() < row_len {
+            continue;
+        }
+        for x in 0..width {
+            for b in 0..4 {
+                tmp[x * 4 + b] = row[b * width + x];
+            }
+        }
+        row.copy_from_slice(&tmp);
+    }
+}
+
+// 32bit float サンプル（ビッグエンディアン・リニア）→ 8bit
+// 0..1 にクランプし、カラーチャンネルは sRGB ガンマをかけてプレビュー用の明るさにする
+fn f32_samples_to_u8(data: &[u8], gamma: bool) -> Vec<u8> {
+    data.par_chunks_exact(4)
+        .map(|b| {
+            let v = f32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+            let v = if v.is_finite() {
+                v.clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let v = if !gamma {
+                v
+            } else if v <= 0.0031308 {
+                v * 12.92
+            } else {
+                1.055 * v.powf(1.0 / 2.4) - 0.055
+            };
+            (v * 255.0 + 0.5) as u8
+        })
+        .collect()
+}
+
 // バイト読み取りヘルパー
 fn read_u16(bytes: &[u8], offset: &mut usize) -> Result<u16, String> {
     if *offset + 2 > bytes.len() {
@@ -1103,164 +4231,1689 @@ fn read_u64(bytes: &[u8], offset: &mut usize) -> Result<u64, String> {
 }
 
 // 拡張子でPSD/TIFF/その他を自動判定してデコード
-fn decode_image_file(path: &str) -> Result<DynamicImage, String> {
+// page: マルチページ TIFF / アニメーション GIF のフレーム番号（0始まり）。その他の形式は 0 のみ有効
+fn decode_image_file(path: &str, page: usize) -> Result<DynamicImage, AppError> {
     let lower = path.to_lowercase();
-    if lower.ends_with(".psd") {
-        decode_psd_to_image(path)
+    let img = if page == 0 && lower.ends_with(".psd") {
+        decode_psd_to_image(path)?
     } else {
-        image::open(path).map_err(|e| format!("Failed to open image {}: {}", path, e))
+        with_io_retry(path, || {
+            if page == 0 {
+                open_raster_image(path).map(|(img, _)| img)
+            } else if lower.ends_with(".tif") || lower.ends_with(".tiff") {
+                decode_tiff_page(path, page)
+            } else if lower.ends_with(".gif") {
+                decode_gif_frame(path, page)
+            } else {
+                Err(page_out_of_range(path, page, 1))
+            }
+        })?
+    };
+    ensure_non_empty(img, path)
+}
+
+// ネットワークドライブ（UNC パスの SMB 共有など）の一時的な読み込み失敗の再試行
+const IO_RETRY_ATTEMPTS: u32 = 3;
+const IO_RETRY_BACKOFF: Duration = Duration::from_millis(200); // 2回目以降は回数分だけ延ばす
+
+// 読み込みが IO エラーで失敗したら、間隔を空けて再試行する
+// ファイルが存在しない・アクセス権がない場合は一時的な失敗ではないので、すぐにそのエラーを返す
+fn with_io_retry<T>(
+    path: &str,
+    mut read: impl FnMut() -> Result<T, AppError>,
+) -> Result<T, AppError> {
+    let mut attempt = 1;
+    loop {
+        match read() {
+            Err(AppError::Io(_))
+                if attempt < IO_RETRY_ATTEMPTS && is_transient_read_failure(path) =>
+            {
+                std::thread::sleep(IO_RETRY_BACKOFF * attempt);
+                attempt += 1;
+            }
+            result => return result,
+        }
     }
 }
 
-// PSDファイルをDynamicImageとしてデコード
-// フォールバックパーサー（Image Data Section直読み）を優先し、失敗時のみpsd crateを使用
-fn decode_psd_to_image(path: &str) -> Result<DynamicImage, String> {
-    let bytes = fs::read(path).map_err(|e| format!("Failed to read PSD: {}", e))?;
-    decode_psd_robust(&bytes)
+fn is_transient_read_failure(path: &str) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file(),
+        Err(e) => !matches!(
+            e.kind(),
+            std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+        ),
+    }
 }
 
-// DynamicImageをJPEG 85%でtempファイルに書き出し、パスを返す（高速エンコード＋IPC転送不要）
-fn encode_to_jpeg_temp(img: &DynamicImage, cache_key: &str) -> Result<String, String> {
-    let temp_dir = get_kenban_temp_dir()?;
-    let filename = {
-        let mut hasher = DefaultHasher::new();
-        cache_key.hash(&mut hasher);
-        format!("kenban_diff_{:016x}.jpg", hasher.finish())
-    };
-    let file_path = temp_dir.join(&filename);
+// 幅・高さが 0 の画像は差分計算（積分画像・帯分割など）に渡す前に弾く
+fn ensure_non_empty(img: DynamicImage, path: &str) -> Result<DynamicImage, AppError> {
+    ensure_non_empty_size(img.width(), img.height(), path)?;
+    Ok(img)
+}
 
-    if file_path.exists() {
-        return Ok(file_path.to_string_lossy().to_string());
+fn ensure_non_empty_size(width: u32, height: u32, path: &str) -> Result<(), AppError> {
+    if width == 0 || height == 0 {
+        return Err(AppError::EmptyImage(format!(
+            "画像のサイズが 0 です ({}x{}): {}",
+            width, height, path
+        )));
     }
+    Ok(())
+}
 
-    let rgb = img.to_rgb8();
-    let tmp_path = temp_dir.join(format!("{}.tmp", filename));
-    let file =
-        fs::File::create(&tmp_path).map_err(|e| format!("Failed to create temp file: {}", e))?;
-    let encoder =
-        image::codecs::jpeg::JpegEncoder::new_with_quality(std::io::BufWriter::new(file), 85);
-    rgb.write_with_encoder(encoder)
-        .map_err(|e| format!("JPEG encode error: {}", e))?;
-    fs::rename(&tmp_path, &file_path).map_err(|e| format!("Failed to rename temp file: {}", e))?;
+// PSD 以外の画像を開く。image crate で開けない TIFF は簡易パーサーで再試行する
+// 戻り値の2番目は成功したデコーダー（"image" | "tiff_fallback"）
+fn open_raster_image(path: &str) -> Result<(DynamicImage, &'static str), AppError> {
+    let bytes = read_image_bytes(path)?;
+    decode_raster_bytes(path, &bytes)
+}
 
-    Ok(file_path.to_string_lossy().to_string())
+fn read_image_bytes(path: &str) -> Result<Vec<u8>, AppError> {
+    fs::read(path).map_err(|e| AppError::Io(format!("Failed to open image {}: {}", path, e)))
 }
 
-// RGBAバッファをPNGでtempファイルに書き出し、パスを返す（差分画像用）
-fn encode_rgba_to_png_temp(
-    buf: &[u8],
-    width: u32,
-    height: u32,
-    cache_key: &str,
-) -> Result<String, String> {
-    let temp_dir = get_kenban_temp_dir()?;
-    let filename = {
-        let mut hasher = DefaultHasher::new();
-        cache_key.hash(&mut hasher);
-        format!("kenban_diff_{:016x}.png", hasher.finish())
-    };
-    let file_path = temp_dir.join(&filename);
+// open_raster_image の本体（読み込み済みのバイト列をデコード。path は形式の判定とエラーメッセージ用）
+fn decode_raster_bytes(path: &str, bytes: &[u8]) -> Result<(DynamicImage, &'static str), AppError> {
+    let lower = path.to_lowercase();
+    // BMP / TGA は中身の推測に頼らず拡張子で専用デコーダーを使う（ヘッダーが特殊なBMPの誤判定対策）
+    if lower.ends_with(".bmp") {
+        return decode_with_format(path, bytes, image::ImageFormat::Bmp, "BMP")
+            .map(|img| (img, "image"));
+    }
+    if lower.ends_with(".tga") {
+        return decode_with_format(path, bytes, image::ImageFormat::Tga, "TGA")
+            .map(|img| (img, "image"));
+    }
 
-    if file_path.exists() {
-        return Ok(file_path.to_string_lossy().to_string());
+    let err = match open_image_oriented(bytes) {
+        Ok(img) => return Ok((img, "image")),
+        Err(e) => e,
+    };
+    if !(lower.ends_with(".tif") || lower.ends_with(".tiff")) {
+        if matches!(err, image::ImageError::Unsupported(_)) {
+            return Err(AppError::Unsupported(format!(
+                "未対応の画像形式です: {} ({}) 対応形式: {}",
+                path, err, SUPPORTED_IMAGE_FORMATS
+            )));
+        }
+        return Err(AppError::from_image(
+            &format!("Failed to open image {}", path),
+            err,
+        ));
+    }
+    match decode_tiff_fallback(bytes) {
+        Ok(img) => Ok((img, "tiff_fallback")),
+        // 展開できないサイズはそのまま返す（image crate 側のエラーより原因が明確）
+        Err(e @ AppError::Unsupported(_)) => Err(e),
+        Err(fallback_err) => Err(AppError::Decode(format!(
+            "Failed to open image {}: {} (fallback: {})",
+            path, err, fallback_err
+        ))),
     }
+}
 
-    let img: ImageBuffer<Rgba<u8>, &[u8]> = ImageBuffer::from_raw(width, height, buf)
-        .ok_or_else(|| "Failed to create image buffer".to_string())?;
-    let tmp_path = temp_dir.join(format!("{}.tmp", filename));
-    let file =
-        fs::File::create(&tmp_path).map_err(|e| format!("Failed to create temp file: {}", e))?;
-    img.write_to(&mut std::io::BufWriter::new(file), image::ImageFormat::Png)
-        .map_err(|e| format!("PNG encode error: {}", e))?;
-    fs::rename(&tmp_path, &file_path).map_err(|e| format!("Failed to rename temp file: {}", e))?;
+const SUPPORTED_IMAGE_FORMATS: &str = "PSD, TIFF, PNG, JPEG, WebP, GIF, BMP, TGA";
 
-    Ok(file_path.to_string_lossy().to_string())
+// 形式を決め打ちでデコードする（拡張子と中身が一致しない場合もその形式として読む）
+fn decode_with_format(
+    path: &str,
+    bytes: &[u8],
+    format: image::ImageFormat,
+    label: &str,
+) -> Result<DynamicImage, AppError> {
+    let mut reader = image::ImageReader::new(Cursor::new(bytes));
+    reader.set_format(format);
+    reader.decode().map_err(|e| {
+        AppError::from_image(&format!("{}の読み込みに失敗しました ({})", label, path), e)
+    })
 }
 
-struct DiffPixel {
-    x: u32,
-    y: u32,
+// image::open と同じだが、EXIF の Orientation（JPEG / TIFF / WebP）に従って回転・反転する
+// （スマホで撮影した参考写真が横倒しのまま比較されるのを防ぐ）
+fn open_image_oriented(bytes: &[u8]) -> image::ImageResult<DynamicImage> {
+    use image::ImageDecoder;
+    let mut decoder = image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder
+        .orientation()
+        .unwrap_or(image::metadata::Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
 }
 
-// ピクセル単位の単純差分計算 (rayon行並列)
-// 返り値: (差分RGBAバッファ, 差分ピクセル数, 差分ピクセル座標リスト)
-fn diff_simple_core(
-    a: &[u8],
-    b: &[u8],
-    width: u32,
+// EXIF の回転を反映した画像サイズ（ヘッダーのみ読む）
+fn image_dimensions_oriented(path: impl AsRef<Path>) -> image::ImageResult<(u32, u32)> {
+    use image::metadata::Orientation;
+    use image::ImageDecoder;
+    let mut decoder = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let (w, h) = decoder.dimensions();
+    match decoder.orientation() {
+        Ok(
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH,
+        ) => Ok((h, w)),
+        _ => Ok((w, h)),
+    }
+}
+
+fn page_out_of_range(path: &str, page: usize, count: usize) -> AppError {
+    AppError::NotFound(format!(
+        "ページ {} は範囲外です（{} は {} ページ）",
+        page, path, count
+    ))
+}
+
+fn open_tiff_decoder(
+    path: &str,
+) -> Result<tiff::decoder::Decoder<std::io::BufReader<fs::File>>, AppError> {
+    let file = fs::File::open(path)
+        .map_err(|e| AppError::Io(format!("Failed to open TIFF {}: {}", path, e)))?;
+    tiff::decoder::Decoder::new(std::io::BufReader::new(file))
+        .map(|d| d.with_limits(tiff::decoder::Limits::unlimited()))
+        .map_err(|e| AppError::Decode(format!("TIFF decode error: {}", e)))
+}
+
+fn count_tiff_pages(path: &str) -> Result<usize, AppError> {
+    let mut decoder = open_tiff_decoder(path)?;
+    let mut count = 1;
+    while decoder.more_images() {
+        decoder
+            .next_image()
+            .map_err(|e| AppError::Decode(format!("TIFF decode error: {}", e)))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// マルチページ TIFF の page 番目の IFD をデコード（色分解スキャンは1ページ1版のグレースケールが多い）
+fn decode_tiff_page(path: &str, page: usize) -> Result<DynamicImage, AppError> {
+    use tiff::decoder::DecodingResult;
+    use tiff::ColorType;
+
+    let count = count_tiff_pages(path)?;
+    if page >= count {
+        return Err(page_out_of_range(path, page, count));
+    }
+    let tiff_err = |e: tiff::TiffError| AppError::Decode(format!("TIFF decode error: {}", e));
+    let mut decoder = open_tiff_decoder(path)?;
+    decoder.seek_to_image(page).map_err(tiff_err)?;
+    let (w, h) = decoder.dimensions().map_err(tiff_err)?;
+    let color = decoder.colortype().map_err(tiff_err)?;
+    let data = decoder.read_image().map_err(tiff_err)?;
+
+    let bad_size = || AppError::Decode(format!("TIFF page {} のサイズが不正です", page));
+    let img = match (color, data) {
+        (ColorType::Gray(8), DecodingResult::U8(buf)) => {
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(w, h, buf).ok_or_else(bad_size)?)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(buf)) => {
+            DynamicImage::ImageLuma16(ImageBuffer::from_raw(w, h, buf).ok_or_else(bad_size)?)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(buf)) => {
+            DynamicImage::ImageLumaA8(ImageBuffer::from_raw(w, h, buf).ok_or_else(bad_size)?)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(buf)) => {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(w, h, buf).ok_or_else(bad_size)?)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(buf)) => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(w, h, buf).ok_or_else(bad_size)?)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(buf)) => {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(w, h, buf).ok_or_else(bad_size)?)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(buf)) => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(w, h, buf).ok_or_else(bad_size)?)
+        }
+        (ColorType::CMYK(8), DecodingResult::U8(buf)) => {
+            let rgb: Vec<u8> = buf
+                .chunks_exact(4)
+                .flat_map(|p| {
+                    let k = 255 - p[3] as u32;
+                    [
+                        ((255 - p[0] as u32) * k / 255) as u8,
+                        ((255 - p[1] as u32) * k / 255) as u8,
+                        ((255 - p[2] as u32) * k / 255) as u8,
+                    ]
+                })
+                .collect();
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(w, h, rgb).ok_or_else(bad_size)?)
+        }
+        (color, _) => {
+            return Err(AppError::Unsupported(format!(
+                "未対応の TIFF カラー形式です: {:?}",
+                color
+            )))
+        }
+    };
+    Ok(img)
+}
+
+fn open_gif_decoder(
+    path: &str,
+) -> Result<image::codecs::gif::GifDecoder<std::io::BufReader<fs::File>>, AppError> {
+    let file = fs::File::open(path)
+        .map_err(|e| AppError::Io(format!("Failed to open GIF {}: {}", path, e)))?;
+    image::codecs::gif::GifDecoder::new(std::io::BufReader::new(file))
+        .map_err(|e| AppError::from_image("GIF decode error", e))
+}
+
+// アニメーション GIF の page 番目のフレーム（前フレームとの合成済み）
+fn decode_gif_frame(path: &str, page: usize) -> Result<DynamicImage, AppError> {
+    use image::AnimationDecoder;
+
+    let mut frames = open_gif_decoder(path)?.into_frames();
+    match frames.nth(page) {
+        Some(frame) => {
+            let frame = frame.map_err(|e| AppError::from_image("GIF decode error", e))?;
+            Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+        }
+        None => {
+            let count = count_image_frames(path.to_string())?;
+            Err(page_out_of_range(path, page, count))
+        }
+    }
+}
+
+struct PsdHeader {
+    width: u32,
+    height: u32,
+    channels: u16,
+    depth: u16,
+    color_mode: u16,
+    is_psb: bool,
+}
+
+impl PsdHeader {
+    // 先頭26バイト（シグネチャ・バージョン・チャンネル数・サイズ・深度・カラーモード）を解釈する
+    fn parse(header: &[u8; 26]) -> Result<PsdHeader, AppError> {
+        if &header[0..4] != b"8BPS" {
+            return Err(AppError::Decode("Not a PSD file".to_string()));
+        }
+        let version = u16::from_be_bytes([header[4], header[5]]);
+        if version != 1 && version != 2 {
+            return Err(AppError::Unsupported(format!(
+                "Unsupported PSD version: {}",
+                version
+            )));
+        }
+        Ok(PsdHeader {
+            channels: u16::from_be_bytes([header[12], header[13]]),
+            height: u32::from_be_bytes([header[14], header[15], header[16], header[17]]),
+            width: u32::from_be_bytes([header[18], header[19], header[20], header[21]]),
+            depth: u16::from_be_bytes([header[22], header[23]]),
+            color_mode: u16::from_be_bytes([header[24], header[25]]),
+            is_psb: version == 2,
+        })
+    }
+
+    // 合成画像の展開に必要なバッファ（全チャンネル分のデータと RGBA の大きい方）がアドレス空間に収まるか
+    // 32bit ビルドで巨大な PSB を開くと usize の掛け算があふれたり確保に失敗して落ちるので、先にエラーにする
+    fn ensure_addressable(&self) -> Result<(), AppError> {
+        let sample_bytes = (self.depth as u64).div_ceil(8).max(1);
+        let per_pixel = (self.channels as u64 * sample_bytes).max(4);
+        let needed = (self.width as u64)
+            .checked_mul(self.height as u64)
+            .and_then(|n| n.checked_mul(per_pixel));
+        match needed {
+            Some(n) if n <= isize::MAX as u64 => Ok(()),
+            _ => Err(AppError::Unsupported(format!(
+                "{}の画像サイズが大きすぎてメモリに展開できません ({}x{}, {}ch, {}bit)",
+                if self.is_psb { "PSB" } else { "PSD" },
+                self.width,
+                self.height,
+                self.channels,
+                self.depth
+            ))),
+        }
+    }
+}
+
+// PSD ヘッダー（先頭26バイト）のみを読む。1GB 超の PSD でもファイル全体は読み込まない
+fn read_psd_header(path: &str) -> Result<PsdHeader, AppError> {
+    let mut header = [0u8; 26];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .map_err(|e| AppError::Io(format!("Failed to read PSD header: {}", e)))?;
+    PsdHeader::parse(&header)
+}
+
+// PSD ヘッダーの Color Mode → 表示名
+fn psd_color_mode_name(color_mode: u16) -> &'static str {
+    match color_mode {
+        0 => "Bitmap",
+        1 => "Grayscale",
+        2 => "Indexed",
+        3 => "RGB",
+        4 => "CMYK",
+        7 => "Multichannel",
+        8 => "Duotone",
+        9 => "Lab",
+        _ => "Unknown",
+    }
+}
+
+#[derive(Serialize)]
+struct PsdMetadata {
+    width: u32,
+    height: u32,
+    channels: u16,
+    depth: u16,
+    color_mode: &'static str,       // "RGB" / "CMYK" 等
+    compression_mode: &'static str, // 合成画像の圧縮: "raw" | "rle" | "zip" | "zip_prediction" | "unknown"
+    layer_count: u32,               // 0 なら合成画像のみ（レイヤー情報なし）
+    is_psb: bool,
+}
+
+// PSD のメタデータ（レイヤー数・圧縮形式等）をデコードせずに返す
+// layer_count > 1 のとき、プレビューはPSD保存時の合成画像（fallback）なので「合成のみ」と表示できる
+#[tauri::command]
+fn psd_metadata(path: String) -> Result<PsdMetadata, AppError> {
+    use std::io::{BufReader, Seek, SeekFrom};
+
+    let io_err = |e: std::io::Error| AppError::Io(format!("Failed to read PSD {}: {}", path, e));
+    let mut reader = BufReader::new(fs::File::open(&path).map_err(io_err)?);
+    // ビッグエンディアンの n バイト整数
+    let read_be = |reader: &mut BufReader<fs::File>, n: usize| -> std::io::Result<u64> {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf[..n])?;
+        Ok(buf[..n].iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+    };
+
+    let mut header = [0u8; 26];
+    reader.read_exact(&mut header).map_err(io_err)?;
+    let header = PsdHeader::parse(&header)?;
+    let len_size = if header.is_psb { 8 } else { 4 };
+
+    // Color Mode Data / Image Resources をスキップ
+    for _ in 0..2 {
+        let len = read_be(&mut reader, 4).map_err(io_err)?;
+        reader.seek_relative(len as i64).map_err(io_err)?;
+    }
+
+    // Layer and Mask Information → Layer Info のレイヤー数（負数は合成アルファ付きの意味で絶対値が件数）
+    let layer_section_len = read_be(&mut reader, len_size).map_err(io_err)?;
+    let layer_section_start = reader.stream_position().map_err(io_err)?;
+    let mut layer_count = 0;
+    if layer_section_len >= len_size as u64 + 2 {
+        let layer_info_len = read_be(&mut reader, len_size).map_err(io_err)?;
+        if layer_info_len >= 2 {
+            layer_count = (read_be(&mut reader, 2).map_err(io_err)? as u16 as i16).unsigned_abs();
+        }
+    }
+    reader
+        .seek(SeekFrom::Start(
+            layer_section_start.saturating_add(layer_section_len),
+        ))
+        .map_err(io_err)?;
+
+    let compression_mode = match read_be(&mut reader, 2).map_err(io_err)? {
+        0 => "raw",
+        1 => "rle",
+        2 => "zip",
+        3 => "zip_prediction",
+        _ => "unknown",
+    };
+
+    Ok(PsdMetadata {
+        width: header.width,
+        height: header.height,
+        channels: header.channels,
+        depth: header.depth,
+        color_mode: psd_color_mode_name(header.color_mode),
+        compression_mode,
+        layer_count: layer_count as u32,
+        is_psb: header.is_psb,
+    })
+}
+
+#[derive(Serialize)]
+struct ImageInfo {
+    width: u32,
+    height: u32,
+    color_type: String, // PSD: "RGB" / "CMYK" 等、その他: image crate の ColorType 名 ("Rgb8" 等)
+    format: String,     // "psd" / "tiff" / "jpeg" / "png" ...
+    is_psd: bool,
+    depth: u16,                   // 1チャンネルあたりのビット数
+    content_hash: Option<String>, // ファイルの内容の blake3（ImageResult と同じ値）
+    dpi: Option<f64>, // 水平解像度（PSD: ResolutionInfo、TIFF: XResolution。記録がなければ None）
+}
+
+const PSD_RESOURCE_RESOLUTION_INFO: u16 = 1005;
+
+// PSD の ResolutionInfo から水平解像度を読む（16.16 固定小数点。表示単位によらず pixels/inch で格納）
+// Image Resources セクションまでしか読まない
+fn read_psd_dpi(path: &str) -> Option<f64> {
+    let mut reader = std::io::BufReader::new(fs::File::open(path).ok()?);
+    let mut head = [0u8; 30];
+    reader.read_exact(&mut head).ok()?;
+    let color_data_len = u32::from_be_bytes(head[26..30].try_into().ok()?);
+    reader.seek_relative(color_data_len as i64).ok()?;
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).ok()?;
+    let mut section = Vec::new();
+    reader
+        .take(u32::from_be_bytes(len) as u64)
+        .read_to_end(&mut section)
+        .ok()?;
+    let info = find_image_resource(&section, PSD_RESOURCE_RESOLUTION_INFO)?;
+    let fixed = u32::from_be_bytes(info.get(0..4)?.try_into().ok()?);
+    let dpi = fixed as f64 / 65536.0;
+    (dpi > 0.0).then_some(dpi)
+}
+
+// TIFF の XResolution / ResolutionUnit から dpi を求める（単位なし = 1 は換算できないので None）
+fn read_tiff_dpi(path: &str) -> Option<f64> {
+    use tiff::decoder::ifd::Value;
+    use tiff::tags::Tag;
+    let mut decoder = open_tiff_decoder(path).ok()?;
+    let Some(Value::Rational(num, den)) = decoder.find_tag(Tag::XResolution).ok()? else {
+        return None;
+    };
+    if num == 0 || den == 0 {
+        return None;
+    }
+    let resolution = num as f64 / den as f64;
+    // ResolutionUnit の既定は 2 (inch)
+    match decoder
+        .find_tag_unsigned::<u16>(Tag::ResolutionUnit)
+        .ok()?
+        .unwrap_or(2)
+    {
+        2 => Some(resolution),
+        3 => Some(resolution * 2.54), // pixels/cm
+        _ => None,
+    }
+}
+
+// ヘッダーのみを読んでサイズ・色形式を返す（巨大PSDもデコードしない）
+// with_hash: true のときだけ content_hash を求める（ファイル全体を読むので既定はオフ。計算済みならそれを返す）
+#[tauri::command]
+fn get_image_info(path: String, with_hash: Option<bool>) -> Result<ImageInfo, AppError> {
+    let hash = if with_hash.unwrap_or(false) {
+        content_hash(&path)
+    } else {
+        known_content_hash(&path)
+    };
+    if path.to_lowercase().ends_with(".psd") {
+        let header = read_psd_header(&path)?;
+        return Ok(ImageInfo {
+            width: header.width,
+            height: header.height,
+            color_type: psd_color_mode_name(header.color_mode).to_string(),
+            format: "psd".to_string(),
+            is_psd: true,
+            depth: header.depth,
+            content_hash: hash,
+            dpi: read_psd_dpi(&path),
+        });
+    }
+
+    use image::ImageDecoder;
+    let reader = image::ImageReader::open(&path)
+        .map_err(|e| AppError::Io(format!("Failed to open image {}: {}", path, e)))?
+        .with_guessed_format()
+        .map_err(|e| AppError::Io(format!("Failed to read image {}: {}", path, e)))?;
+    let format = reader
+        .format()
+        .and_then(|f| f.extensions_str().first().copied())
+        .map(|ext| match ext {
+            "jpg" => "jpeg",
+            "tif" => "tiff",
+            other => other,
+        })
+        .ok_or_else(|| AppError::Unsupported(format!("未対応の画像形式です: {}", path)))?
+        .to_string();
+    let decoder = reader
+        .into_decoder()
+        .map_err(|e| AppError::from_image(&format!("Failed to read image header {}", path), e))?;
+    let (width, height) = decoder.dimensions();
+    let color = decoder.color_type();
+    drop(decoder);
+    let dpi = if format == "tiff" {
+        read_tiff_dpi(&path)
+    } else {
+        None
+    };
+
+    Ok(ImageInfo {
+        width,
+        height,
+        color_type: format!("{:?}", color),
+        format,
+        is_psd: false,
+        depth: color.bits_per_pixel() / color.channel_count() as u16,
+        content_hash: hash,
+        dpi,
+    })
+}
+
+// 差分コストの目安（compute_diff_heatmap のデコード込みの概算。マシンにより前後する）
+const DIFF_THROUGHPUT_MPX_PER_SEC: f64 = 12.0; // 1秒あたりに処理できる比較サイズ（メガピクセル）
+const DIFF_FIXED_COST_MS: f64 = 150.0; // ファイルオープン・エンコード等の固定分
+const DIFF_BYTES_PER_PIXEL: f64 = 28.0; // デコード済みRGBA×2 + リサイズ後 + 差分バッファ + 密度マップ(f32)
+
+#[derive(Serialize)]
+struct DiffCostEstimate {
+    megapixels: f64, // 比較サイズ（大きい方に合わせた幅×高さ）
+    estimated_ms: u64,
+    estimated_mem_mb: u64,
+}
+
+// ヘッダーのみを読んだ画像サイズ（PSD はファイルヘッダー、その他は EXIF 回転込み）
+fn header_dimensions(path: &str) -> Result<(u32, u32), AppError> {
+    if path.to_lowercase().ends_with(".psd") {
+        read_psd_dimensions(path)
+    } else {
+        image_dimensions_oriented(path)
+            .map_err(|e| AppError::from_image(&format!("Failed to read image header {}", path), e))
+    }
+}
+
+// 差分計算の重さの見積もり（デコードせずヘッダーのみ読む）。大きな比較の前に警告を出す用
+#[tauri::command]
+fn estimate_diff_cost(path_a: String, path_b: String) -> Result<DiffCostEstimate, AppError> {
+    let (wa, ha) = header_dimensions(&path_a)?;
+    let (wb, hb) = header_dimensions(&path_b)?;
+    let pixels = wa.max(wb) as f64 * ha.max(hb) as f64;
+    let megapixels = pixels / 1_000_000.0;
+    Ok(DiffCostEstimate {
+        megapixels: (megapixels * 10.0).round() / 10.0,
+        estimated_ms: (DIFF_FIXED_COST_MS + megapixels / DIFF_THROUGHPUT_MPX_PER_SEC * 1000.0)
+            as u64,
+        estimated_mem_mb: (pixels * DIFF_BYTES_PER_PIXEL / (1024.0 * 1024.0)).ceil() as u64,
+    })
+}
+
+const BLANK_CHECK_DIM: u32 = 256; // 白紙判定の粗チェックで使う縮小サイズ（長辺）
+const DEFAULT_BLANK_TOLERANCE: u8 = 8;
+
+#[derive(Serialize)]
+struct BlankPageResult {
+    is_blank: bool,
+    color: [u8; 3],    // ページの代表色（縮小画像の平均色）
+    max_deviation: u8, // 代表色からの最大のずれ（チャンネル単位。白紙でない場合は粗チェックで見つかった値）
+}
+
+// 白紙・単色ページの判定（全ピクセルが代表色から tolerance 以内なら白紙）
+// まず縮小画像で判定し、そこで外れたら原寸は見ない。細い線が縮小で消えることがあるので、
+// 縮小で単色と判定された場合のみ原寸で確認する
+#[tauri::command]
+fn is_blank(path: String, tolerance: Option<u8>) -> Result<BlankPageResult, AppError> {
+    let tolerance = tolerance.unwrap_or(DEFAULT_BLANK_TOLERANCE);
+    let img = decode_image_file(&path, 0)?.to_rgb8();
+
+    let small = image::imageops::thumbnail(&img, BLANK_CHECK_DIM, BLANK_CHECK_DIM);
+    let n = small.pixels().len().max(1) as u64;
+    let mut sum = [0u64; 3];
+    for p in small.pixels() {
+        for c in 0..3 {
+            sum[c] += p[c] as u64;
+        }
+    }
+    let color = sum.map(|s| (s / n) as u8);
+    let deviation =
+        |p: &image::Rgb<u8>| -> u8 { (0..3).map(|c| p[c].abs_diff(color[c])).max().unwrap_or(0) };
+
+    let small_dev = small.pixels().map(deviation).max().unwrap_or(0);
+    if small_dev > tolerance {
+        return Ok(BlankPageResult {
+            is_blank: false,
+            color,
+            max_deviation: small_dev,
+        });
+    }
+
+    let full_dev = img
+        .as_raw()
+        .par_chunks(4096 * 3)
+        .map(|chunk| {
+            chunk
+                .chunks_exact(3)
+                .map(|p| deviation(&image::Rgb([p[0], p[1], p[2]])))
+                .max()
+                .unwrap_or(0)
+        })
+        .max()
+        .unwrap_or(0);
+    Ok(BlankPageResult {
+        is_blank: full_dev <= tolerance,
+        color,
+        max_deviation: full_dev,
+    })
+}
+
+// 画像のページ / フレーム数（TIFF は IFD 数、GIF はフレーム数、その他は 1）
+#[tauri::command]
+fn count_image_frames(path: String) -> Result<usize, AppError> {
+    let lower = path.to_lowercase();
+    if lower.ends_with(".tif") || lower.ends_with(".tiff") {
+        count_tiff_pages(&path)
+    } else if lower.ends_with(".gif") {
+        use image::AnimationDecoder;
+        Ok(open_gif_decoder(&path)?.into_frames().count())
+    } else {
+        fs::metadata(&path).map_err(|e| AppError::Io(format!("{}: {}", path, e)))?;
+        Ok(1)
+    }
+}
+
+// PSDファイルをDynamicImageとしてデコード
+// フォールバックパーサー（Image Data Section直読み）を優先し、失敗時のみpsd crateを使用
+fn decode_psd_to_image(path: &str) -> Result<DynamicImage, AppError> {
+    let bytes = with_io_retry(path, || {
+        fs::read(path).map_err(|e| AppError::Io(format!("Failed to read PSD: {}", e)))
+    })?;
+    decode_psd_bytes(bytes).map(|(img, _)| img)
+}
+
+// DynamicImageをJPEG 85%でtempファイルに書き出し、パスを返す（高速エンコード＋IPC転送不要）
+fn encode_to_jpeg_temp(img: &DynamicImage, cache_key: &str) -> Result<String, AppError> {
+    let temp_dir = get_kenban_temp_dir().map_err(AppError::Io)?;
+    let filename = {
+        let mut hasher = DefaultHasher::new();
+        cache_key.hash(&mut hasher);
+        format!("kenban_diff_{:016x}.jpg", hasher.finish())
+    };
+    let file_path = temp_dir.join(&filename);
+
+    if file_path.exists() {
+        return Ok(file_path.to_string_lossy().to_string());
+    }
+
+    let rgb = img.to_rgb8();
+    let tmp_path = temp_dir.join(format!("{}.tmp", filename));
+    let file = fs::File::create(&tmp_path)
+        .map_err(|e| AppError::Io(format!("Failed to create temp file: {}", e)))?;
+    let encoder =
+        image::codecs::jpeg::JpegEncoder::new_with_quality(std::io::BufWriter::new(file), 85);
+    rgb.write_with_encoder(encoder)
+        .map_err(|e| AppError::Encode(format!("JPEG encode error: {}", e)))?;
+    fs::rename(&tmp_path, &file_path)
+        .map_err(|e| AppError::Io(format!("Failed to rename temp file: {}", e)))?;
+
+    Ok(file_path.to_string_lossy().to_string())
+}
+
+// RGBAバッファをPNGでtempファイルに書き出し、パスを返す（差分画像用）
+fn encode_rgba_to_png_temp(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    cache_key: &str,
+) -> Result<String, AppError> {
+    encode_png_temp(
+        buf,
+        width,
+        height,
+        image::ExtendedColorType::Rgba8,
+        cache_key,
+    )
+}
+
+// 任意の色形式のバッファを PNG tempファイルに書き出す（同じキーなら既存ファイルを再利用）
+fn encode_png_temp(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: image::ExtendedColorType,
+    cache_key: &str,
+) -> Result<String, AppError> {
+    let temp_dir = get_kenban_temp_dir().map_err(AppError::Io)?;
+    let filename = {
+        let mut hasher = DefaultHasher::new();
+        png_variant_key(cache_key).hash(&mut hasher);
+        format!("kenban_diff_{:016x}.png", hasher.finish())
+    };
+    let file_path = temp_dir.join(&filename);
+
+    if file_path.exists() {
+        return Ok(file_path.to_string_lossy().to_string());
+    }
+
+    let tmp_path = temp_dir.join(format!("{}.tmp", filename));
+    let file = fs::File::create(&tmp_path)
+        .map_err(|e| AppError::Io(format!("Failed to create temp file: {}", e)))?;
+    write_png(std::io::BufWriter::new(file), buf, width, height, color)
+        .map_err(AppError::Encode)?;
+    fs::rename(&tmp_path, &file_path)
+        .map_err(|e| AppError::Io(format!("Failed to rename temp file: {}", e)))?;
+
+    Ok(file_path.to_string_lossy().to_string())
+}
+
+struct DiffPixel {
+    x: u32,
+    y: u32,
+}
+
+// 色差の判定方式
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum DiffColorMode {
+    #[default]
+    Rgb, // チャンネルごとの絶対差 > threshold
+    #[serde(rename = "deltae")]
+    DeltaE, // CIELAB 上の ΔE (CIEDE2000) > delta_e_threshold
+}
+
+// 単純差分画像の配色（既定: 黒地に赤）
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+struct DiffStyle {
+    diff_color: [u8; 3],     // 差分ピクセルの色
+    background: [u8; 3],     // 差分のないピクセルの色（overlay_on_source 時は未使用）
+    overlay_on_source: bool, // true なら差分のないピクセルに A の画素をそのまま使う
+    // true なら差分ピクセルの明るさを差の大きさ（RGB の最大チャンネル差 / 255）に比例させる
+    // （スキャンノイズ程度の小さな差は暗く、実際の修正は明るく表示される）
+    intensity: bool,
+    // true なら background を両画像の縁の色（中央値）の補色にする（暗い原稿でも差分が埋もれない）
+    auto_background: bool,
+}
+
+impl Default for DiffStyle {
+    fn default() -> Self {
+        Self {
+            diff_color: [255, 0, 0],
+            background: [0, 0, 0],
+            overlay_on_source: false,
+            intensity: false,
+            auto_background: false,
+        }
+    }
+}
+
+impl DiffStyle {
+    // 差分画像のキャッシュキーに含める識別子（既定の配色は空文字で従来と同じキー）
+    fn cache_tag(&self) -> String {
+        let [dr, dg, db] = self.diff_color;
+        let [br, bg, bb] = self.background;
+        let colors = match (self.diff_color, self.background, self.overlay_on_source) {
+            ([255, 0, 0], [0, 0, 0], false) => String::new(),
+            (_, _, true) => format!("_s{:02x}{:02x}{:02x}ov", dr, dg, db),
+            _ => format!(
+                "_s{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                dr, dg, db, br, bg, bb
+            ),
+        };
+        if self.intensity {
+            colors + "_int"
+        } else {
+            colors
+        }
+    }
+
+    // 差分ピクセルの色（intensity 時は最大チャンネル差に比例して暗くする）
+    fn diff_pixel_color(&self, pa: &[u8], pb: &[u8]) -> [u8; 3] {
+        if !self.intensity {
+            return self.diff_color;
+        }
+        let delta = max_rgb_delta(pa, pb) as u16;
+        self.diff_color.map(|c| (c as u16 * delta / 255) as u8)
+    }
+}
+
+// 両画像の外周 1px の色の中央値（チャンネルごと）。用紙・背景の色の推定に使う
+fn border_median_color(images: &[&DynamicImage]) -> [u8; 3] {
+    let mut channels: [Vec<u8>; 3] = Default::default();
+    for img in images {
+        let (w, h) = img.dimensions();
+        let border = (0..w)
+            .flat_map(|x| [(x, 0), (x, h - 1)])
+            .chain((1..h.saturating_sub(1)).flat_map(|y| [(0, y), (w - 1, y)]));
+        for (x, y) in border {
+            let p = img.get_pixel(x, y).0;
+            for c in 0..3 {
+                channels[c].push(p[c]);
+            }
+        }
+    }
+    channels.map(|mut values| {
+        if values.is_empty() {
+            return 0;
+        }
+        let mid = values.len() / 2;
+        *values.select_nth_unstable(mid).1
+    })
+}
+
+// 背景色に対して差分が見やすい地の色: 補色。中間色で補色との差が小さい場合は白か黒
+fn contrasting_background(color: [u8; 3]) -> [u8; 3] {
+    let complement = color.map(|c| 255 - c);
+    if max_rgb_delta(&color, &complement) >= 96 {
+        return complement;
+    }
+    let [r, g, b] = color.map(u32::from);
+    if (r * 299 + g * 587 + b * 114) / 1000 >= 128 {
+        [0, 0, 0]
+    } else {
+        [255, 255, 255]
+    }
+}
+
+// RGB の最大チャンネル差
+fn max_rgb_delta(pa: &[u8], pb: &[u8]) -> u8 {
+    (0..3).map(|c| pa[c].abs_diff(pb[c])).max().unwrap_or(0)
+}
+
+// 最も差の大きい差分ピクセル（「最悪箇所へ移動」用）。座標はマーカーと同じ系
+#[derive(Serialize, Clone, Copy)]
+struct WorstDiff {
+    x: u32,
+    y: u32,
+    delta: u8, // RGB の最大チャンネル差（mode = "deltae" なら ΔE を四捨五入した値、最大 255）
+}
+
+impl WorstDiff {
+    // 差がより大きければ置き換える（同値なら先に見た = 読み順で先の画素を残す）
+    fn keep_max(worst: &mut Option<WorstDiff>, candidate: WorstDiff) {
+        if worst.is_none_or(|w| candidate.delta > w.delta) {
+            *worst = Some(candidate);
+        }
+    }
+}
+
+// 差分ピクセルの差の大きさ（判定と同じ尺度。background 指定時は合成後の画素で測る）
+fn diff_delta(pa: &[u8], pb: &[u8], options: &SimpleDiffOptions) -> u8 {
+    let (pa, pb) = match options.background {
+        Some(bg) => (flatten_pixel(pa, bg), flatten_pixel(pb, bg)),
+        None => ([pa[0], pa[1], pa[2], pa[3]], [pb[0], pb[1], pb[2], pb[3]]),
+    };
+    match options.mode {
+        DiffColorMode::Rgb => max_rgb_delta(&pa, &pb),
+        DiffColorMode::DeltaE => ciede2000(
+            rgb_to_lab(pa[0], pa[1], pa[2]),
+            rgb_to_lab(pb[0], pb[1], pb[2]),
+        )
+        .round()
+        .min(255.0) as u8,
+    }
+}
+
+// 差分ピクセルの中で最大の差を持つ画素（同値なら読み順で先のもの）。差分なしなら None
+// a / b は diff_simple_core で比較したのと同じ RGBA バッファ（幅 width）
+fn worst_diff_pixel(
+    pixels: &[DiffPixel],
+    a: &[u8],
+    b: &[u8],
+    width: u32,
+    options: &SimpleDiffOptions,
+) -> Option<WorstDiff> {
+    pixels
+        .par_iter()
+        .map(|p| {
+            let i = ((p.y as usize) * (width as usize) + p.x as usize) * 4;
+            WorstDiff {
+                x: p.x,
+                y: p.y,
+                delta: diff_delta(&a[i..i + 4], &b[i..i + 4], options),
+            }
+        })
+        .max_by_key(|w| (w.delta, std::cmp::Reverse((w.y, w.x))))
+}
+
+// 2画像のサイズが異なる場合の扱い
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MismatchPolicy {
+    #[default]
+    Resize, // 小さい方を大きい方のサイズへ引き伸ばす（従来動作、縦横比は崩れる）
+    Pad,   // 両方を左上揃えで max(w) x max(h) のキャンバスに置く（余白は PAD_FILL）
+    Error, // AppError::SizeMismatch を返す
+}
+
+// mismatch_policy = "pad" の余白色（中間グレー）
+const PAD_FILL: [u8; 4] = [128, 128, 128, 255];
+
+// compute_diff_simple / check_diff_simple の追加オプション（すべて省略可能）
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+struct SimpleDiffOptions {
+    mode: DiffColorMode,
+    delta_e_threshold: f64, // mode = "deltae" 時のしきい値（2.0 前後で目視可能な色差）
+    compare_alpha: bool,    // アルファ値の差も差分として扱う
+    background: Option<[u8; 3]>, // 指定時は両画像をこの色の上に合成してから比較（透過PSD vs 色紙の印刷など）
+    include_composite: bool,     // compute_diff_simple で A | diff | B の連結画像も出力する
+    mismatch_policy: MismatchPolicy,
+    ignore_antialias: bool, // 周囲8近傍の差分が少ない孤立画素（文字のアンチエイリアス境界）を差分としない
+    antialias_min_neighbors: u8, // ignore_antialias 時、差分とみなすのに必要な差分近傍数（1〜8）
+    style: DiffStyle,       // 差分画像の配色（compute_diff_simple のみ）
+    prefilter: Option<Prefilter>, // 比較前の平滑化（スキャンのノイズ対策）
+    max_markers: Option<usize>, // マーカー数の上限（件数の多い順に残す）
+    merge_overlap: Option<f64>, // 重なりがこの比率 (0〜1) 以上のマーカー同士を1つにまとめる
+    display_max_dim: Option<u32>, // 出力画像をこの長辺に縮小してからエンコード（compute_diff_simple のみ）
+    profile: bool,                // 結果に段階ごとの所要時間 (timings) を含める
+}
+
+// 比較前に両画像へかける前処理
+// blur_sigma が大きいほどスキャンのノイズは消えるが、1〜2px の細線の欠け・追加も平滑化されて見逃しやすくなる
+// （300dpi のスキャンなら 1.0 前後から試す）
+#[derive(Deserialize, Clone, Copy)]
+struct Prefilter {
+    blur_sigma: f64,
+}
+
+impl Default for SimpleDiffOptions {
+    fn default() -> Self {
+        Self {
+            mode: DiffColorMode::Rgb,
+            delta_e_threshold: 2.0,
+            compare_alpha: false,
+            background: None,
+            include_composite: false,
+            mismatch_policy: MismatchPolicy::Resize,
+            ignore_antialias: false,
+            antialias_min_neighbors: 2,
+            style: DiffStyle::default(),
+            prefilter: None,
+            max_markers: None,
+            merge_overlap: None,
+            display_max_dim: None,
+            profile: false,
+        }
+    }
+}
+
+impl SimpleDiffOptions {
+    // 出力画像のキャッシュキーに含める識別子（オプションが違えば別ファイル）
+    fn cache_tag(&self) -> String {
+        let mut tag = match self.mode {
+            DiffColorMode::Rgb => "rgb".to_string(),
+            DiffColorMode::DeltaE => format!("de{}", self.delta_e_threshold),
+        };
+        if self.compare_alpha {
+            tag.push_str("_alpha");
+        }
+        if let Some([r, g, b]) = self.background {
+            tag.push_str(&format!("_bg{:02x}{:02x}{:02x}", r, g, b));
+        }
+        if self.mismatch_policy == MismatchPolicy::Pad {
+            tag.push_str("_pad");
+        }
+        if self.ignore_antialias {
+            tag.push_str(&format!("_aa{}", self.antialias_min_neighbors));
+        }
+        if let Some(sigma) = self.blur_sigma() {
+            tag.push_str(&format!("_bl{}", sigma));
+        }
+        tag.push_str(&self.style.cache_tag());
+        tag
+    }
+
+    // 有効な prefilter のぼかし量（未指定・0以下なら None）
+    fn blur_sigma(&self) -> Option<f32> {
+        self.prefilter
+            .map(|p| p.blur_sigma as f32)
+            .filter(|&sigma| sigma > 0.0)
+    }
+}
+
+// 2画像を同じサイズに揃える（mismatch_policy に従う）。戻り値の2枚は同サイズ
+fn align_image_pair(
+    img_a: DynamicImage,
+    img_b: DynamicImage,
+    policy: MismatchPolicy,
+) -> Result<(DynamicImage, DynamicImage), AppError> {
+    let (wa, ha) = img_a.dimensions();
+    let (wb, hb) = img_b.dimensions();
+    if wa == wb && ha == hb {
+        return Ok((img_a, img_b));
+    }
+    let width = wa.max(wb);
+    let height = ha.max(hb);
+
+    let fit = |img: DynamicImage| -> DynamicImage {
+        let (w, h) = img.dimensions();
+        if w == width && h == height {
+            return img;
+        }
+        match policy {
+            MismatchPolicy::Pad => {
+                let mut canvas = ImageBuffer::from_pixel(width, height, Rgba(PAD_FILL));
+                image::imageops::replace(&mut canvas, &img.to_rgba8(), 0, 0);
+                DynamicImage::ImageRgba8(canvas)
+            }
+            _ => img.resize_exact(width, height, FilterType::Triangle),
+        }
+    };
+
+    match policy {
+        MismatchPolicy::Error => Err(AppError::SizeMismatch {
+            message: format!("画像サイズが一致しません: A={}x{}, B={}x{}", wa, ha, wb, hb),
+            size_a: (wa, ha),
+            size_b: (wb, hb),
+        }),
+        _ => Ok(rayon::join(|| fit(img_a), || fit(img_b))),
+    }
+}
+
+// RGBA ピクセルを背景色の上に合成（結果は不透明）
+fn flatten_pixel(p: &[u8], bg: [u8; 3]) -> [u8; 4] {
+    let a = p[3] as u32;
+    let blend = |c: u8, b: u8| ((c as u32 * a + b as u32 * (255 - a) + 127) / 255) as u8;
+    [
+        blend(p[0], bg[0]),
+        blend(p[1], bg[1]),
+        blend(p[2], bg[2]),
+        255,
+    ]
+}
+
+// ============== CIEDE2000 ==============
+
+// sRGB 8bit → 線形RGB のルックアップテーブル
+fn srgb_to_linear_lut() -> &'static [f64; 256] {
+    static LUT: std::sync::OnceLock<[f64; 256]> = std::sync::OnceLock::new();
+    LUT.get_or_init(|| {
+        let mut lut = [0f64; 256];
+        for (i, v) in lut.iter_mut().enumerate() {
+            let c = i as f64 / 255.0;
+            *v = if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            };
+        }
+        lut
+    })
+}
+
+// sRGB → CIELAB (D65)
+fn rgb_to_lab(r: u8, g: u8, b: u8) -> (f64, f64, f64) {
+    let lut = srgb_to_linear_lut();
+    let (r, g, b) = (lut[r as usize], lut[g as usize], lut[b as usize]);
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+// CIEDE2000 色差
+fn ciede2000(lab1: (f64, f64, f64), lab2: (f64, f64, f64)) -> f64 {
+    let (l1, a1, b1) = lab1;
+    let (l2, a2, b2) = lab2;
+    const POW25_7: f64 = 6103515625.0; // 25^7
+
+    let c_bar = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.0;
+    let c_bar7 = c_bar.powi(7);
+    let g = 0.5 * (1.0 - (c_bar7 / (c_bar7 + POW25_7)).sqrt());
+    let a1p = (1.0 + g) * a1;
+    let a2p = (1.0 + g) * a2;
+    let c1p = a1p.hypot(b1);
+    let c2p = a2p.hypot(b2);
+
+    let hue = |b: f64, ap: f64| {
+        if b == 0.0 && ap == 0.0 {
+            0.0
+        } else {
+            let h = b.atan2(ap).to_degrees();
+            if h < 0.0 {
+                h + 360.0
+            } else {
+                h
+            }
+        }
+    };
+    let h1p = hue(b1, a1p);
+    let h2p = hue(b2, a2p);
+
+    let dl = l2 - l1;
+    let dc = c2p - c1p;
+    let dh = if c1p * c2p == 0.0 {
+        0.0
+    } else {
+        let d = h2p - h1p;
+        if d > 180.0 {
+            d - 360.0
+        } else if d < -180.0 {
+            d + 360.0
+        } else {
+            d
+        }
+    };
+    let dh_big = 2.0 * (c1p * c2p).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar_p = (c1p + c2p) / 2.0;
+    let h_bar = if c1p * c2p == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() > 180.0 {
+        if h1p + h2p < 360.0 {
+            (h1p + h2p + 360.0) / 2.0
+        } else {
+            (h1p + h2p - 360.0) / 2.0
+        }
+    } else {
+        (h1p + h2p) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (h_bar - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar).to_radians().cos()
+        + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let c_bar_p7 = c_bar_p.powi(7);
+    let rc = 2.0 * (c_bar_p7 / (c_bar_p7 + POW25_7)).sqrt();
+    let l50 = (l_bar - 50.0).powi(2);
+    let sl = 1.0 + 0.015 * l50 / (20.0 + l50).sqrt();
+    let sc = 1.0 + 0.045 * c_bar_p;
+    let sh = 1.0 + 0.015 * c_bar_p * t;
+    let rt = -(2.0 * d_theta).to_radians().sin() * rc;
+
+    let (tl, tc, th) = (dl / sl, dc / sc, dh_big / sh);
+    (tl * tl + tc * tc + th * th + rt * tc * th).sqrt()
+}
+
+// 1ピクセルの差分判定（pa / pb は RGBA 4バイト）
+fn pixel_differs(pa: &[u8], pb: &[u8], threshold: i16, options: &SimpleDiffOptions) -> bool {
+    let (pa, pb) = match options.background {
+        Some(bg) => (flatten_pixel(pa, bg), flatten_pixel(pb, bg)),
+        None => ([pa[0], pa[1], pa[2], pa[3]], [pb[0], pb[1], pb[2], pb[3]]),
+    };
+    if options.compare_alpha && (pa[3] as i16 - pb[3] as i16).abs() > threshold {
+        return true;
+    }
+    match options.mode {
+        DiffColorMode::Rgb => {
+            let dr = (pa[0] as i16 - pb[0] as i16).abs();
+            let dg = (pa[1] as i16 - pb[1] as i16).abs();
+            let db = (pa[2] as i16 - pb[2] as i16).abs();
+            dr > threshold || dg > threshold || db > threshold
+        }
+        DiffColorMode::DeltaE => {
+            pa[..3] != pb[..3]
+                && ciede2000(
+                    rgb_to_lab(pa[0], pa[1], pa[2]),
+                    rgb_to_lab(pb[0], pb[1], pb[2]),
+                ) > options.delta_e_threshold
+        }
+    }
+}
+
+// 単純差分の結果: (差分RGBAバッファ, 差分ピクセル数, 差分ピクセル座標リスト, 最も差の大きい差分ピクセル)
+type SimpleDiffParts = (Vec<u8>, u32, Vec<DiffPixel>, Option<WorstDiff>);
+
+// ピクセル単位の単純差分計算 (rayon行並列)
+// ignore: 比較から除外する矩形（差分画像では IGNORE_FILL で塗る）
+// options.ignore_antialias: 差分近傍が antialias_min_neighbors 未満の画素は一致扱い（1px の縁取り差を抑制）
+// options.style: 差分 / 背景の色（overlay_on_source なら背景の代わりに A の画素）
+// want_buf: false なら差分画像バッファを確保・着色しない（返すバッファは空。件数・座標だけ求める）
+// 返り値: SimpleDiffParts
+#[allow(clippy::too_many_arguments)]
+fn diff_simple_core(
+    a: &[u8],
+    b: &[u8],
+    width: u32,
     height: u32,
     threshold: u8,
-) -> (Vec<u8>, u32, Vec<DiffPixel>) {
+    options: &SimpleDiffOptions,
+    ignore: &[CropBounds],
+    want_buf: bool,
+) -> SimpleDiffParts {
     let threshold = threshold as i16;
-    let row_size = (width as usize) * 4;
+    let w = width as usize;
+    let row_size = w * 4;
+
+    // 画素ごとの判定（MASK_SAME / MASK_DIFF / MASK_IGNORED）
+    let classify = |x: usize, y: u32, pa: &[u8], pb: &[u8]| {
+        if in_ignore_region(x as u32, y, ignore) {
+            MASK_IGNORED
+        } else if pixel_differs(pa, pb, threshold, options) {
+            MASK_DIFF
+        } else {
+            MASK_SAME
+        }
+    };
+
+    // 1パス目（ignore_antialias 時のみ）: 近傍を数えるため、全画素の判定を先に行ごとに並列で求める
+    let mask: Option<Vec<u8>> = options.ignore_antialias.then(|| {
+        (0..height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let offset = (y as usize) * row_size;
+                let row_a = &a[offset..offset + row_size];
+                let row_b = &b[offset..offset + row_size];
+                (0..w).map(move |x| {
+                    let i = x * 4;
+                    classify(x, y, &row_a[i..i + 4], &row_b[i..i + 4])
+                })
+            })
+            .collect()
+    });
+    let min_neighbors = options.antialias_min_neighbors.clamp(1, 8) as u32;
 
-    // 行ごとに並列処理
-    let rows: Vec<(Vec<u8>, u32, Vec<DiffPixel>)> = (0..height)
+    // 判定と着色（ignore_antialias 時は差分近傍の少ない孤立画素を除外。それ以外はここで判定する1パス）
+    let rows: Vec<SimpleDiffParts> = (0..height)
         .into_par_iter()
         .map(|y| {
-            let offset = (y as usize) * row_size;
-            let row_a = &a[offset..offset + row_size];
-            let row_b = &b[offset..offset + row_size];
-            let mut row_buf = vec![0u8; row_size];
+            let row_a = &a[(y as usize) * row_size..(y as usize + 1) * row_size];
+            let row_b = &b[(y as usize) * row_size..(y as usize + 1) * row_size];
+            let style = &options.style;
+            let mut row_buf = if want_buf {
+                vec![0u8; row_size]
+            } else {
+                Vec::new()
+            };
             let mut count = 0u32;
             let mut pixels = Vec::new();
+            let mut worst = None;
 
-            for x in 0..width as usize {
+            for x in 0..w {
                 let i = x * 4;
-                let dr = (row_a[i] as i16 - row_b[i] as i16).abs();
-                let dg = (row_a[i + 1] as i16 - row_b[i + 1] as i16).abs();
-                let db = (row_a[i + 2] as i16 - row_b[i + 2] as i16).abs();
-
-                if dr > threshold || dg > threshold || db > threshold {
-                    row_buf[i] = 255; // R
-                    row_buf[i + 1] = 0; // G
-                    row_buf[i + 2] = 0; // B
-                    row_buf[i + 3] = 255; // A
+                let m = match &mask {
+                    Some(mask) => mask[(y as usize) * w + x],
+                    None => classify(x, y, &row_a[i..i + 4], &row_b[i..i + 4]),
+                };
+                let differs = m == MASK_DIFF
+                    && mask.as_ref().is_none_or(|mask| {
+                        count_diff_neighbors(mask, width, height, x as u32, y) >= min_neighbors
+                    });
+
+                if differs {
                     count += 1;
                     pixels.push(DiffPixel { x: x as u32, y });
+                    let delta = diff_delta(&row_a[i..i + 4], &row_b[i..i + 4], options);
+                    WorstDiff::keep_max(
+                        &mut worst,
+                        WorstDiff {
+                            x: x as u32,
+                            y,
+                            delta,
+                        },
+                    );
+                }
+                if !want_buf {
+                    continue;
+                }
+                if m == MASK_IGNORED {
+                    row_buf[i..i + 4].copy_from_slice(&IGNORE_FILL);
+                } else if differs {
+                    row_buf[i..i + 3].copy_from_slice(
+                        &style.diff_pixel_color(&row_a[i..i + 4], &row_b[i..i + 4]),
+                    );
+                    row_buf[i + 3] = 255;
+                } else if style.overlay_on_source {
+                    // A の画素の上に差分を重ねる
+                    row_buf[i..i + 3].copy_from_slice(&row_a[i..i + 3]);
+                    row_buf[i + 3] = 255;
                 } else {
-                    // 黒背景（alpha=255）
+                    // 背景（既定は黒、alpha=255）
+                    row_buf[i..i + 3].copy_from_slice(&style.background);
                     row_buf[i + 3] = 255;
                 }
             }
-            (row_buf, count, pixels)
+            (row_buf, count, pixels, worst)
         })
         .collect();
 
-    let total_size = (width as usize) * (height as usize) * 4;
+    let total_size = if want_buf {
+        (width as usize) * (height as usize) * 4
+    } else {
+        0
+    };
     let mut diff_buf = vec![0u8; total_size];
     let mut total_count = 0u32;
     let mut all_pixels = Vec::new();
+    let mut worst = None;
 
-    for (y, (row_buf, count, pixels)) in rows.into_iter().enumerate() {
-        let offset = y * row_size;
-        diff_buf[offset..offset + row_size].copy_from_slice(&row_buf);
+    for (y, (row_buf, count, pixels, row_worst)) in rows.into_iter().enumerate() {
+        if want_buf {
+            let offset = y * row_size;
+            diff_buf[offset..offset + row_size].copy_from_slice(&row_buf);
+        }
         total_count += count;
         all_pixels.extend(pixels);
+        if let Some(w) = row_worst {
+            WorstDiff::keep_max(&mut worst, w);
+        }
     }
 
-    (diff_buf, total_count, all_pixels)
+    (diff_buf, total_count, all_pixels, worst)
 }
 
-// ヒートマップ差分計算（積分画像→密度マップ→着色）
-fn diff_heatmap_core(
+// 差分画像 (RGBA) の表示用縮小。出力1画素 = 元画像のブロック
+// ブロック内に差分ピクセルがあれば最も明るい差分色（max-pool）、なければブロックの平均色
+fn downscale_diff_buf(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    diff_pixels: &[DiffPixel],
+    out_w: u32,
+    out_h: u32,
+) -> Result<Vec<u8>, AppError> {
+    let expected = (width as usize) * (height as usize) * 4;
+    if buf.len() != expected || out_w == 0 || out_h == 0 || out_w > width || out_h > height {
+        return Err(AppError::Other(format!(
+            "差分画像の縮小サイズが不正です: {}x{} ({} bytes) → {}x{}",
+            width,
+            height,
+            buf.len(),
+            out_w,
+            out_h
+        )));
+    }
+    // 出力座標 o が受け持つ元座標の範囲の先頭（元座標 v は v * out / full の出力画素に属する）
+    let block_start =
+        |o: u32, out: u32, full: u32| (o as u64 * full as u64).div_ceil(out as u64) as usize;
+    let row_size = (width as usize) * 4;
+
+    let mut out = vec![0u8; (out_w as usize) * (out_h as usize) * 4];
+    out.par_chunks_mut(out_w as usize * 4)
+        .enumerate()
+        .for_each(|(oy, out_row)| {
+            let y0 = block_start(oy as u32, out_h, height);
+            let y1 = block_start(oy as u32 + 1, out_h, height);
+            for ox in 0..out_w {
+                let x0 = block_start(ox, out_w, width);
+                let x1 = block_start(ox + 1, out_w, width);
+                let mut sum = [0u64; 4];
+                for y in y0..y1 {
+                    for p in buf[y * row_size + x0 * 4..y * row_size + x1 * 4].chunks_exact(4) {
+                        for c in 0..4 {
+                            sum[c] += p[c] as u64;
+                        }
+                    }
+                }
+                let n = ((y1 - y0) * (x1 - x0)) as u64;
+                let o = ox as usize * 4;
+                for c in 0..4 {
+                    out_row[o + c] = ((sum[c] + n / 2) / n) as u8;
+                }
+            }
+        });
+
+    // 差分ピクセルのあるブロックは差分色で上書き（intensity 時は最も明るい色）
+    let brightness = |p: &[u8]| p[..3].iter().map(|&c| c as u32).sum::<u32>();
+    let mut pooled = vec![false; (out_w as usize) * (out_h as usize)];
+    for p in diff_pixels {
+        let ox = (p.x as u64 * out_w as u64 / width as u64) as usize;
+        let oy = (p.y as u64 * out_h as u64 / height as u64) as usize;
+        let src = (p.y as usize) * row_size + (p.x as usize) * 4;
+        let o = oy * out_w as usize + ox;
+        let color = &buf[src..src + 4];
+        if !pooled[o] || brightness(color) > brightness(&out[o * 4..o * 4 + 4]) {
+            out[o * 4..o * 4 + 4].copy_from_slice(color);
+            pooled[o] = true;
+        }
+    }
+    Ok(out)
+}
+
+const MASK_SAME: u8 = 0;
+const MASK_DIFF: u8 = 1;
+const MASK_IGNORED: u8 = 2;
+
+// (x, y) の8近傍のうち MASK_DIFF の画素数
+fn count_diff_neighbors(mask: &[u8], width: u32, height: u32, x: u32, y: u32) -> u32 {
+    let mut n = 0;
+    for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+        for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+            if (nx, ny) != (x, y) && mask[(ny * width + nx) as usize] == MASK_DIFF {
+                n += 1;
+            }
+        }
+    }
+    n
+}
+
+// この画素数以上の比較は横ストリップ単位で RGBA 変換し、2枚分の RGBA 全体を同時に持たない
+const TILED_DIFF_MIN_PIXELS: u64 = 100_000_000;
+const DIFF_BAND_ROWS: u32 = 512;
+
+// 除外領域を帯（y0 から rows 行）内の座標系へ平行移動
+fn ignore_regions_for_band(regions: &[CropBounds], y0: u32, rows: u32) -> Vec<CropBounds> {
+    regions
+        .iter()
+        .filter(|r| r.bottom > y0 && r.top < y0 + rows)
+        .map(|r| CropBounds {
+            left: r.left,
+            top: r.top.saturating_sub(y0),
+            right: r.right,
+            bottom: (r.bottom - y0).min(rows),
+        })
+        .collect()
+}
+
+// 同サイズの2画像の単純差分。巨大画像は帯ごとに変換→diff_simple_core で処理する
+// （image crate は部分デコードできないため、デコード済み画像からの RGBA 変換を帯単位にする）
+// want_buf: false なら差分画像バッファを組み立てない（check_diff_simple 用）
+fn diff_simple_images(
+    img_a: &DynamicImage,
+    img_b: &DynamicImage,
+    threshold: u8,
+    options: &SimpleDiffOptions,
+    ignore: &[CropBounds],
+    want_buf: bool,
+) -> SimpleDiffParts {
+    let (width, height) = img_a.dimensions();
+
+    // prefilter: 両画像をぼかしてから比較（overlay_on_source の下地もぼかした A になる）
+    let blurred;
+    let (img_a, img_b) = match options.blur_sigma() {
+        Some(sigma) => {
+            blurred = rayon::join(|| img_a.fast_blur(sigma), || img_b.fast_blur(sigma));
+            (&blurred.0, &blurred.1)
+        }
+        None => (img_a, img_b),
+    };
+
+    // 通常サイズ: 全体を一括変換する高速パス
+    if (width as u64) * (height as u64) < TILED_DIFF_MIN_PIXELS {
+        let rgba_a = img_a.to_rgba8();
+        let rgba_b = img_b.to_rgba8();
+        return diff_simple_core(
+            rgba_a.as_raw(),
+            rgba_b.as_raw(),
+            width,
+            height,
+            threshold,
+            options,
+            ignore,
+            want_buf,
+        );
+    }
+
+    let mut diff_buf = if want_buf {
+        Vec::with_capacity((width as usize) * (height as usize) * 4)
+    } else {
+        Vec::new()
+    };
+    let mut total_count = 0u32;
+    let mut all_pixels = Vec::new();
+    let mut worst = None;
+
+    // ignore_antialias は近傍を見るため、帯の上下に1行ずつ余分に読み込んで境界でも同じ結果にする
+    let margin = u32::from(options.ignore_antialias);
+    let mut y0 = 0;
+    while y0 < height {
+        let rows = DIFF_BAND_ROWS.min(height - y0);
+        let top = y0.saturating_sub(margin);
+        let bottom = (y0 + rows + margin).min(height);
+        let band_a = img_a.crop_imm(0, top, width, bottom - top).to_rgba8();
+        let band_b = img_b.crop_imm(0, top, width, bottom - top).to_rgba8();
+        let band_ignore = ignore_regions_for_band(ignore, top, bottom - top);
+
+        let (buf, _, pixels, band_worst) = diff_simple_core(
+            band_a.as_raw(),
+            band_b.as_raw(),
+            width,
+            bottom - top,
+            threshold,
+            options,
+            &band_ignore,
+            want_buf,
+        );
+        // 余分に読んだ行の結果は捨てる
+        let skip = y0 - top;
+        if want_buf {
+            let row_size = (width as usize) * 4;
+            diff_buf.extend_from_slice(
+                &buf[skip as usize * row_size..(skip + rows) as usize * row_size],
+            );
+        }
+        let kept = |y: u32| y >= skip && y < skip + rows;
+        let band_pixels: Vec<DiffPixel> = pixels.into_iter().filter(|p| kept(p.y)).collect();
+        // 最大の差が余分に読んだ行にあった場合は、残す行の差分ピクセルから求め直す
+        let band_worst = match band_worst {
+            Some(w) if !kept(w.y) => worst_diff_pixel(
+                &band_pixels,
+                band_a.as_raw(),
+                band_b.as_raw(),
+                width,
+                options,
+            ),
+            w => w,
+        };
+        if let Some(w) = band_worst {
+            WorstDiff::keep_max(
+                &mut worst,
+                WorstDiff {
+                    y: w.y - skip + y0,
+                    ..w
+                },
+            );
+        }
+        total_count += band_pixels.len() as u32;
+        all_pixels.extend(band_pixels.into_iter().map(|p| DiffPixel {
+            x: p.x,
+            y: p.y - skip + y0,
+        }));
+        y0 += rows;
+    }
+
+    (diff_buf, total_count, all_pixels, worst)
+}
+
+// ヒートマップの平滑化・マーカー抽出パラメータ（長さはすべてTIFFピクセル単位）
+// 既定値は 300〜350dpi のスキャン向け。1200dpi なら radius / grid_size / min_radius を約4倍にする
+// （dpi と *_mm を指定すれば解像度によらず同じ物理サイズになる。dpi は get_image_info で取得できる）
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+struct HeatmapParams {
+    radius: u32,                // 密度を数える窓の半径（窓は (2r+1)×(2r+1) px）
+    density_threshold: f32,     // 窓内の差分ピクセル比率 (0..1) がこれ以下の点は着色しない
+    grid_size: u32, // マーカークラスタリングのグリッド幅（隣接セル同士を1マーカーにまとめる）
+    min_cluster: u32, // マーカーとして残すクラスタの最小ピクセル数
+    min_radius: f64, // マーカー円の最小半径
+    max_markers: Option<usize>, // マーカー数の上限（件数の多い順に残す）
+    merge_overlap: Option<f64>, // 重なりがこの比率 (0〜1) 以上のマーカー同士を1つにまとめる
+    classify_regions: bool, // 差分を文字らしい領域 / 写真らしい領域に分けて数える（region_counts）
+    palette: HeatmapPalette, // "default" | "viridis" | "magma"（色覚特性に配慮した配色）
+    dpi: Option<f64>, // TIFF の解像度。*_mm の換算に使う
+    radius_mm: Option<f64>, // radius をミリで指定（dpi が必要。指定時は radius より優先）
+    min_radius_mm: Option<f64>, // min_radius をミリで指定（同上）
+    profile: bool,  // 結果に段階ごとの所要時間 (timings) を含める（画像には影響しない）
+}
+
+impl Default for HeatmapParams {
+    fn default() -> Self {
+        Self {
+            radius: 15,
+            density_threshold: 0.05,
+            grid_size: 250,
+            min_cluster: 20,
+            min_radius: 80.0,
+            max_markers: None,
+            merge_overlap: None,
+            classify_regions: false,
+            palette: HeatmapPalette::Default,
+            dpi: None,
+            radius_mm: None,
+            min_radius_mm: None,
+            profile: false,
+        }
+    }
+}
+
+impl HeatmapParams {
+    // フロントから受け取った値を検証し、radius_mm / min_radius_mm を dpi でピクセルに換算した設定を返す
+    fn validated(mut self) -> Result<Self, AppError> {
+        if self.grid_size == 0 {
+            return Err(AppError::Other(
+                "grid_size は 1 以上を指定してください".to_string(),
+            ));
+        }
+        if self.density_threshold.is_nan() {
+            return Err(AppError::Other(
+                "density_threshold が数値ではありません".to_string(),
+            ));
+        }
+        if self.radius_mm.is_none() && self.min_radius_mm.is_none() {
+            return Ok(self);
+        }
+        let dpi = self
+            .dpi
+            .filter(|d| d.is_finite() && *d > 0.0)
+            .ok_or_else(|| {
+                AppError::Other(
+                    "radius_mm / min_radius_mm を使うには dpi を指定してください".to_string(),
+                )
+            })?;
+        let to_px = |mm: f64| mm.max(0.0) / 25.4 * dpi;
+        if let Some(mm) = self.radius_mm.take() {
+            self.radius = (to_px(mm).round() as u32).max(1);
+        }
+        if let Some(mm) = self.min_radius_mm.take() {
+            self.min_radius = to_px(mm);
+        }
+        Ok(self)
+    }
+
+    // 差分画像のキャッシュキーに含める識別子（クラスタリング設定は画像に影響しない）
+    fn cache_tag(&self) -> String {
+        format!(
+            "_r{}_dt{}{}",
+            self.radius,
+            self.density_threshold,
+            self.palette.cache_tag()
+        )
+    }
+
+    // クラスタリング → 除外領域のマーカーを除く → 件数の上限・重なりの統合
+    fn cluster(&self, pixels: &[DiffPixel], ignore: &[CropBounds]) -> Vec<DiffMarker> {
+        let markers = drop_ignored_markers(
+            cluster_markers(pixels, self.grid_size, self.min_cluster, self.min_radius),
+            ignore,
+        );
+        limit_markers(markers, self.max_markers, self.merge_overlap)
+    }
+}
+
+// 1px = 1byte (0/1) の差分マスク。いずれかのチャンネル差が threshold を超えれば 1
+fn heatmap_diff_mask(
     a: &[u8],
     b: &[u8],
     width: u32,
     height: u32,
     threshold: u8,
-) -> (Vec<u8>, u32, Vec<DiffPixel>) {
+    ignore: &[CropBounds],
+) -> Vec<u8> {
     let w = width as usize;
-    let h = height as usize;
     let threshold = threshold as i16;
-
-    // Phase 1: diffMask作成（rayon並列）
-    let diff_mask: Vec<u8> = (0..h)
+    (0..height as usize)
         .into_par_iter()
         .flat_map(|y| {
             let offset = y * w * 4;
             (0..w)
                 .map(move |x| {
+                    if in_ignore_region(x as u32, y as u32, ignore) {
+                        return 0u8;
+                    }
                     let i = offset + x * 4;
                     let dr = (a[i] as i16 - b[i] as i16).abs();
                     let dg = (a[i + 1] as i16 - b[i + 1] as i16).abs();
@@ -1273,38 +5926,215 @@ fn diff_heatmap_core(
                 })
                 .collect::<Vec<_>>()
         })
-        .collect();
+        .collect()
+}
 
-    // Phase 2: 積分画像（sequential - データ依存あり）
+// 0/1 マスクの積分画像（(w+1) x (h+1)、先頭の行・列は 0。sequential - データ依存あり）
+fn integral_image(mask: &[u8], w: usize, h: usize) -> Vec<f32> {
     let iw = w + 1;
-    let ih = h + 1;
-    let mut integral = vec![0f32; iw * ih];
+    let mut integral = vec![0f32; iw * (h + 1)];
     for y in 0..h {
         for x in 0..w {
             let idx = (y + 1) * iw + (x + 1);
-            integral[idx] = diff_mask[y * w + x] as f32 + integral[idx - 1] + integral[idx - iw]
+            integral[idx] = mask[y * w + x] as f32 + integral[idx - 1] + integral[idx - iw]
                 - integral[idx - iw - 1];
         }
     }
+    integral
+}
+
+// (x, y) を中心とする (2r+1)×(2r+1) 窓（画像端で切り詰め）に占める 1 の比率
+fn window_density(integral: &[f32], w: usize, h: usize, x: usize, y: usize, radius: i32) -> f32 {
+    let iw = w + 1;
+    let x1 = (x as i32 - radius).max(0) as usize;
+    let y1 = (y as i32 - radius).max(0) as usize;
+    let x2 = ((x as i32 + radius) as usize).min(w - 1);
+    let y2 = ((y as i32 + radius) as usize).min(h - 1);
+    let area = ((x2 - x1 + 1) * (y2 - y1 + 1)) as f32;
+    let sum = integral[(y2 + 1) * iw + (x2 + 1)]
+        - integral[y1 * iw + (x2 + 1)]
+        - integral[(y2 + 1) * iw + x1]
+        + integral[y1 * iw + x1];
+    sum / area
+}
+
+// 隣接ピクセルとの輝度差がこれを超えるピクセルをエッジとみなす
+const TEXT_EDGE_STEP: i16 = 64;
+// 周辺のエッジ比率がこれ以上なら文字らしい領域とみなす（細い線の輪郭が密集する）
+const TEXT_EDGE_DENSITY: f32 = 0.1;
+
+// 差分ピクセルを文字らしい領域 / 写真らしい領域に分けた件数
+#[derive(Serialize, Clone, Copy, Default)]
+struct RegionDiffCounts {
+    text: u32,  // 周辺にエッジが密集している（写植・手書き文字など）
+    photo: u32, // それ以外（写真・ベタ・グラデーション・余白）
+}
+
+// A・B どちらかで右隣か下隣との輝度差が大きいピクセル = 1 のエッジマスク
+fn edge_mask(a: &[u8], b: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let w = width as usize;
+    let h = height as usize;
+    let (luma_a, luma_b) = rayon::join(|| rgba_to_luma(a), || rgba_to_luma(b));
+    let is_edge = |luma: &[u8], i: usize, x: usize, y: usize| {
+        let v = luma[i] as i16;
+        (x + 1 < w && (v - luma[i + 1] as i16).abs() > TEXT_EDGE_STEP)
+            || (y + 1 < h && (v - luma[i + w] as i16).abs() > TEXT_EDGE_STEP)
+    };
+    (0..h)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            let (luma_a, luma_b) = (&luma_a, &luma_b);
+            (0..w).map(move |x| {
+                let i = y * w + x;
+                (is_edge(luma_a, i, x, y) || is_edge(luma_b, i, x, y)) as u8
+            })
+        })
+        .collect()
+}
+
+// 差分ピクセルを周辺（radius の窓）のエッジ密度で文字 / 写真に振り分けて数える
+fn tally_region_diffs(
+    diff_mask: &[u8],
+    edges: &[u8],
+    w: usize,
+    h: usize,
+    radius: i32,
+) -> RegionDiffCounts {
+    let integral = integral_image(edges, w, h);
+    (0..h)
+        .into_par_iter()
+        .map(|y| {
+            let mut counts = RegionDiffCounts::default();
+            for x in 0..w {
+                if diff_mask[y * w + x] == 0 {
+                    continue;
+                }
+                if window_density(&integral, w, h, x, y, radius) >= TEXT_EDGE_DENSITY {
+                    counts.text += 1;
+                } else {
+                    counts.photo += 1;
+                }
+            }
+            counts
+        })
+        .reduce(RegionDiffCounts::default, |a, b| RegionDiffCounts {
+            text: a.text + b.text,
+            photo: a.photo + b.photo,
+        })
+}
+
+// 正規化密度がこれ以上のピクセルを高密度（マーカー・diff_probability の対象）とする
+const HEATMAP_HIGH_DENSITY: f32 = 0.6;
+
+// ヒートマップの配色。viridis / magma は知覚的に均一で、赤緑の色覚特性でも濃淡を読み取れる
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum HeatmapPalette {
+    #[default]
+    Default, // 青 → 緑 → 黄 → 赤
+    Viridis,
+    Magma,
+}
+
+// viridis / magma を 1/8 刻みでサンプリングした色（間は線形補間）
+const VIRIDIS_STOPS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [71, 44, 122],
+    [59, 81, 139],
+    [44, 113, 142],
+    [33, 144, 141],
+    [39, 173, 129],
+    [92, 200, 99],
+    [170, 220, 50],
+    [253, 231, 37],
+];
+const MAGMA_STOPS: [[u8; 3]; 9] = [
+    [0, 0, 4],
+    [28, 16, 68],
+    [79, 18, 123],
+    [129, 37, 129],
+    [181, 54, 122],
+    [229, 80, 100],
+    [251, 135, 97],
+    [254, 194, 135],
+    [252, 253, 191],
+];
+// 黒背景に溶け込まないよう、カラーマップの暗い端はこの位置から使う
+const COLORMAP_FLOOR: f32 = 0.15;
+
+impl HeatmapPalette {
+    fn cache_tag(self) -> &'static str {
+        match self {
+            Self::Default => "",
+            Self::Viridis => "_viridis",
+            Self::Magma => "_magma",
+        }
+    }
+
+    // 正規化密度 (0..1) → 色
+    fn color(self, normalized: f32) -> [u8; 3] {
+        let stops = match self {
+            Self::Default => return default_heatmap_color(normalized),
+            Self::Viridis => &VIRIDIS_STOPS,
+            Self::Magma => &MAGMA_STOPS,
+        };
+        let t = (COLORMAP_FLOOR + (1.0 - COLORMAP_FLOOR) * normalized.clamp(0.0, 1.0))
+            * (stops.len() - 1) as f32;
+        let i = (t as usize).min(stops.len() - 2);
+        let f = t - i as f32;
+        std::array::from_fn(|c| {
+            let (a, b) = (stops[i][c] as f32, stops[i + 1][c] as f32);
+            (a + (b - a) * f).round() as u8
+        })
+    }
+}
+
+// 従来の配色: 0〜0.3 青→緑、0.3〜0.6 緑→黄、0.6〜 黄→赤（高密度）
+fn default_heatmap_color(normalized: f32) -> [u8; 3] {
+    if normalized < 0.3 {
+        [0, (normalized / 0.3 * 200.0) as u8, 200]
+    } else if normalized < HEATMAP_HIGH_DENSITY {
+        let t = (normalized - 0.3) / 0.3;
+        [
+            (t * 255.0) as u8,
+            (200.0 + t * 55.0) as u8,
+            ((1.0 - t) * 200.0) as u8,
+        ]
+    } else {
+        let t = (normalized - 0.6) / 0.4;
+        [255, ((1.0 - t) * 255.0) as u8, 0]
+    }
+}
+
+// ヒートマップ差分計算（積分画像→密度マップ→着色）
+// 戻り値: (ヒートマップRGBA, 高密度ピクセル数, 高密度ピクセル, 領域別の差分数 ※classify_regions 時のみ)
+fn diff_heatmap_core(
+    a: &[u8],
+    b: &[u8],
+    width: u32,
+    height: u32,
+    threshold: u8,
+    ignore: &[CropBounds],
+    params: &HeatmapParams,
+) -> (Vec<u8>, u32, Vec<DiffPixel>, Option<RegionDiffCounts>) {
+    let w = width as usize;
+    let h = height as usize;
+
+    // Phase 1: diffMask作成（rayon並列）
+    let diff_mask = heatmap_diff_mask(a, b, width, height, threshold, ignore);
+
+    // Phase 2: 積分画像
+    let integral = integral_image(&diff_mask, w, h);
 
     // Phase 3: 密度マップ（rayon並列 - integralは読み取り専用）
-    let radius: i32 = 15;
+    let radius = params.radius as i32;
     let density_and_max: Vec<(f32, f32)> = (0..h)
         .into_par_iter()
         .map(|y| {
             let mut row_max = 0f32;
             let row: Vec<f32> = (0..w)
                 .map(|x| {
-                    let x1 = (x as i32 - radius).max(0) as usize;
-                    let y1 = (y as i32 - radius).max(0) as usize;
-                    let x2 = ((x as i32 + radius) as usize).min(w - 1);
-                    let y2 = ((y as i32 + radius) as usize).min(h - 1);
-                    let area = ((x2 - x1 + 1) * (y2 - y1 + 1)) as f32;
-                    let sum = integral[(y2 + 1) * iw + (x2 + 1)]
-                        - integral[y1 * iw + (x2 + 1)]
-                        - integral[(y2 + 1) * iw + x1]
-                        + integral[y1 * iw + x1];
-                    let d = sum / area;
+                    let d = window_density(&integral, w, h, x, y, radius);
                     if d > row_max {
                         row_max = d;
                     }
@@ -1323,7 +6153,7 @@ fn diff_heatmap_core(
     let max_density = density_and_max.iter().map(|(_, m)| *m).fold(0f32, f32::max);
 
     // Phase 4: ヒートマップ着色 + 高密度ピクセル収集（rayon並列）
-    let density_threshold = 0.05f32;
+    let density_threshold = params.density_threshold;
     let rows: Vec<(Vec<u8>, u32, Vec<DiffPixel>)> = (0..h)
         .into_par_iter()
         .map(|y| {
@@ -1343,28 +6173,17 @@ fn diff_heatmap_core(
                 };
 
                 if diff_mask[pixel_idx] == 1 && density > density_threshold {
-                    let (r, g, b) = if normalized < 0.3 {
-                        (0u8, (normalized / 0.3 * 200.0) as u8, 200u8)
-                    } else if normalized < 0.6 {
-                        let t = (normalized - 0.3) / 0.3;
-                        (
-                            (t * 255.0) as u8,
-                            (200.0 + t * 55.0) as u8,
-                            ((1.0 - t) * 200.0) as u8,
-                        )
-                    } else {
-                        let t = (normalized - 0.6) / 0.4;
+                    if normalized >= HEATMAP_HIGH_DENSITY {
                         high_count += 1;
                         high_pixels.push(DiffPixel {
                             x: x as u32,
                             y: y as u32,
                         });
-                        (255u8, ((1.0 - t) * 255.0) as u8, 0u8)
-                    };
-                    row_buf[di] = r;
-                    row_buf[di + 1] = g;
-                    row_buf[di + 2] = b;
+                    }
+                    row_buf[di..di + 3].copy_from_slice(&params.palette.color(normalized));
                     row_buf[di + 3] = 255;
+                } else if in_ignore_region(x as u32, y as u32, ignore) {
+                    row_buf[di..di + 4].copy_from_slice(&IGNORE_FILL);
                 } else {
                     // 黒背景
                     row_buf[di + 3] = 255;
@@ -1386,7 +6205,12 @@ fn diff_heatmap_core(
         all_high_pixels.extend(pixels);
     }
 
-    (heatmap_buf, total_high, all_high_pixels)
+    // Phase 5: 差分を文字 / 写真領域に振り分け（同じ窓の大きさでエッジ密度を見る）
+    let region_counts = params
+        .classify_regions
+        .then(|| tally_region_diffs(&diff_mask, &edge_mask(a, b, width, height), w, h, radius));
+
+    (heatmap_buf, total_high, all_high_pixels, region_counts)
 }
 
 // Union-Findクラスタリング → DiffMarkerリスト
@@ -1488,102 +6312,1484 @@ fn cluster_markers(
                 y: cy,
                 radius: marker_radius,
                 count: g.4,
+                bbox: MarkerBounds {
+                    left: g.0 as f64,
+                    top: g.2 as f64,
+                    right: g.1 as f64 + 1.0,
+                    bottom: g.3 as f64 + 1.0,
+                },
+                id: 0,
+                kind: MarkerKind::Changed,
+            }
+        })
+        .collect();
+
+    // groups は HashMap なので、件数が同じときは y → x で順序を確定させる
+    sort_and_number_markers(&mut markers);
+    markers
+}
+
+// 件数の多い順（同数なら y → x）に並べ、1始まりの id を振り直す
+fn sort_and_number_markers(markers: &mut [DiffMarker]) {
+    markers.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then(a.y.total_cmp(&b.y))
+            .then(a.x.total_cmp(&b.x))
+    });
+    for (i, m) in markers.iter_mut().enumerate() {
+        m.id = i as u32 + 1;
+    }
+}
+
+// 修正の多いページでマーカーが画面を埋め尽くさないようにする後処理（どちらも未指定なら何もしない）
+// merge_overlap: 2つの円の重なり（小さい方の直径に対する重なり幅の比 0〜1）がこれ以上なら1つにまとめる
+// max_markers: まとめた後、件数の多い順にこの数だけ残す
+fn limit_markers(
+    mut markers: Vec<DiffMarker>,
+    max_markers: Option<usize>,
+    merge_overlap: Option<f64>,
+) -> Vec<DiffMarker> {
+    if max_markers.is_none() && merge_overlap.is_none() {
+        return markers;
+    }
+    if let Some(threshold) = merge_overlap {
+        let threshold = threshold.clamp(0.0, 1.0);
+        // まとめて大きくなった円が別の円と重なることがあるので、まとめるものがなくなるまで繰り返す
+        loop {
+            let before = markers.len();
+            let mut i = 0;
+            while i < markers.len() {
+                let mut j = i + 1;
+                while j < markers.len() {
+                    let overlap = circle_overlap(&markers[i], &markers[j]);
+                    if overlap > 0.0 && overlap >= threshold {
+                        let other = markers.swap_remove(j);
+                        markers[i] = merge_two_markers(&markers[i], &other);
+                    } else {
+                        j += 1;
+                    }
+                }
+                i += 1;
+            }
+            if markers.len() == before {
+                break;
+            }
+        }
+    }
+    sort_and_number_markers(&mut markers);
+    if let Some(max) = max_markers {
+        markers.truncate(max);
+    }
+    markers
+}
+
+// 2つのマーカー円の重なり具合（0 = 離れている、1 = 小さい方が大きい方に完全に含まれる）
+fn circle_overlap(a: &DiffMarker, b: &DiffMarker) -> f64 {
+    let d = (a.x - b.x).hypot(a.y - b.y);
+    let depth = a.radius + b.radius - d;
+    if depth <= 0.0 {
+        return 0.0;
+    }
+    (depth / (2.0 * a.radius.min(b.radius))).min(1.0)
+}
+
+// 2つのマーカーを、両方の円を含む最小の円にまとめる
+fn merge_two_markers(a: &DiffMarker, b: &DiffMarker) -> DiffMarker {
+    let d = (a.x - b.x).hypot(a.y - b.y);
+    let (x, y, radius) = if d + b.radius <= a.radius {
+        (a.x, a.y, a.radius)
+    } else if d + a.radius <= b.radius {
+        (b.x, b.y, b.radius)
+    } else {
+        let radius = (d + a.radius + b.radius) / 2.0;
+        let t = (radius - a.radius) / d;
+        (a.x + (b.x - a.x) * t, a.y + (b.y - a.y) * t, radius)
+    };
+    DiffMarker {
+        x,
+        y,
+        radius,
+        count: a.count + b.count,
+        bbox: MarkerBounds {
+            left: a.bbox.left.min(b.bbox.left),
+            top: a.bbox.top.min(b.bbox.top),
+            right: a.bbox.right.max(b.bbox.right),
+            bottom: a.bbox.bottom.max(b.bbox.bottom),
+        },
+        id: 0,
+        kind: if a.kind == b.kind {
+            a.kind
+        } else {
+            MarkerKind::Changed
+        },
+    }
+}
+
+const MARKER_KIND_MAX_SAMPLES: usize = 200_000; // 種類判定で見る差分ピクセル数の上限（全マーカー合計）
+const BACKGROUND_MIN_LEVEL: u8 = 230; // 全チャンネルがこれ以上なら紙の白（背景）とみなす
+const MARKER_KIND_BG_RATIO: f64 = 0.7; // 片側の背景率がこれ以上・反対側が 1 - これ 未満なら追加 / 欠落
+const MARKER_INDEX_CELL: u32 = 256; // 差分ピクセル → マーカーの索引のセル幅（px）
+
+// セル (x / MARKER_INDEX_CELL, y / MARKER_INDEX_CELL) → bbox がそのセルに掛かるマーカーの番号（昇順）
+fn marker_cell_index(markers: &[DiffMarker]) -> HashMap<(u32, u32), Vec<usize>> {
+    let cell = MARKER_INDEX_CELL as f64;
+    let mut index: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+    for (i, m) in markers.iter().enumerate() {
+        let b = &m.bbox;
+        if !(b.right > b.left && b.bottom > b.top) || b.right <= 0.0 || b.bottom <= 0.0 {
+            continue;
+        }
+        // bbox は right / bottom を含まないので、終端のセルは切り上げ（排他）
+        let (gx0, gy0) = (
+            (b.left.max(0.0) / cell) as u32,
+            (b.top.max(0.0) / cell) as u32,
+        );
+        let (gx1, gy1) = (
+            (b.right / cell).ceil() as u32,
+            (b.bottom / cell).ceil() as u32,
+        );
+        for gy in gy0..gy1 {
+            for gx in gx0..gx1 {
+                index.entry((gx, gy)).or_default().push(i);
             }
+        }
+    }
+    index
+}
+
+fn is_background_like(p: Rgba<u8>) -> bool {
+    p[3] < 16 || p.0[..3].iter().all(|&c| c >= BACKGROUND_MIN_LEVEL)
+}
+
+// 各マーカー内の差分ピクセルで A / B が背景かどうかを数え、kind を付ける
+// 差分ピクセルとデコード済み画像は同じ座標系（マーカー座標と同じ）であること
+fn classify_markers<A, B>(
+    mut markers: Vec<DiffMarker>,
+    pixels: &[DiffPixel],
+    img_a: &A,
+    img_b: &B,
+) -> Vec<DiffMarker>
+where
+    A: GenericImageView<Pixel = Rgba<u8>>,
+    B: GenericImageView<Pixel = Rgba<u8>>,
+{
+    if markers.is_empty() {
+        return markers;
+    }
+    let step = pixels.len().div_ceil(MARKER_KIND_MAX_SAMPLES).max(1);
+    let index = marker_cell_index(&markers);
+    // マーカーごとの (サンプル数, A が背景, B が背景)
+    let mut counts = vec![(0u32, 0u32, 0u32); markers.len()];
+    for p in pixels.iter().step_by(step) {
+        if !img_a.in_bounds(p.x, p.y) || !img_b.in_bounds(p.x, p.y) {
+            continue;
+        }
+        let (x, y) = (p.x as f64, p.y as f64);
+        // 重なるマーカーが複数あれば番号の小さい方（並び順で先のマーカー）に数える
+        let cell = (p.x / MARKER_INDEX_CELL, p.y / MARKER_INDEX_CELL);
+        let Some(&i) = index.get(&cell).and_then(|candidates| {
+            candidates.iter().find(|&&i| {
+                let b = &markers[i].bbox;
+                x >= b.left && x < b.right && y >= b.top && y < b.bottom
+            })
+        }) else {
+            continue;
+        };
+        let c = &mut counts[i];
+        c.0 += 1;
+        c.1 += is_background_like(img_a.get_pixel(p.x, p.y)) as u32;
+        c.2 += is_background_like(img_b.get_pixel(p.x, p.y)) as u32;
+    }
+    for (m, &(n, bg_a, bg_b)) in markers.iter_mut().zip(&counts) {
+        if n == 0 {
+            continue;
+        }
+        let (ra, rb) = (bg_a as f64 / n as f64, bg_b as f64 / n as f64);
+        m.kind = if rb >= MARKER_KIND_BG_RATIO && ra < 1.0 - MARKER_KIND_BG_RATIO {
+            MarkerKind::Removed
+        } else if ra >= MARKER_KIND_BG_RATIO && rb < 1.0 - MARKER_KIND_BG_RATIO {
+            MarkerKind::Added
+        } else {
+            MarkerKind::Changed
+        };
+    }
+    markers
+}
+
+// ============== 自動位置合わせ ==============
+// スキャン画像とPSDの数pxのずれを、輝度の平均絶対差が最小になる平行移動として推定する。
+// 縮小画像で粗探索 → 原寸で粗探索結果の周辺のみ精密探索。
+
+const ALIGN_MAX_SHIFT: i32 = 32; // 探索する最大ずれ量（原寸px）
+const ALIGN_COARSE_DIM: u32 = 512; // 粗探索時の長辺サイズ
+const ALIGN_FINE_STEP: usize = 4; // 精密探索時のサンプリング間隔
+
+// RGBAバッファ → 輝度 (BT.601)
+fn rgba_to_luma(buf: &[u8]) -> Vec<u8> {
+    buf.par_chunks_exact(4)
+        .map(|p| ((p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000) as u8)
+        .collect()
+}
+
+// 輝度画像を factor x factor ブロック平均で縮小
+fn downsample_luma(
+    luma: &[u8],
+    width: usize,
+    height: usize,
+    factor: usize,
+) -> (Vec<u8>, usize, usize) {
+    let dw = (width / factor).max(1);
+    let dh = (height / factor).max(1);
+    let out = (0..dh)
+        .into_par_iter()
+        .flat_map(|dy| {
+            (0..dw)
+                .map(move |dx| {
+                    let mut sum = 0u32;
+                    let mut n = 0u32;
+                    for y in (dy * factor)..((dy + 1) * factor).min(height) {
+                        for x in (dx * factor)..((dx + 1) * factor).min(width) {
+                            sum += luma[y * width + x] as u32;
+                            n += 1;
+                        }
+                    }
+                    (sum / n.max(1)) as u8
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    (out, dw, dh)
+}
+
+// A を (dx, dy) ずらしたときの重なり部分の平均絶対差
+fn shift_cost(
+    a: &[u8],
+    b: &[u8],
+    width: usize,
+    height: usize,
+    dx: i32,
+    dy: i32,
+    step: usize,
+) -> f64 {
+    let x0 = dx.max(0) as usize;
+    let y0 = dy.max(0) as usize;
+    let x1 = (width as i32 + dx.min(0)).max(0) as usize;
+    let y1 = (height as i32 + dy.min(0)).max(0) as usize;
+    if x0 >= x1 || y0 >= y1 {
+        return f64::MAX;
+    }
+
+    let mut sum = 0u64;
+    let mut n = 0u64;
+    for y in (y0..y1).step_by(step) {
+        let ay = (y as i32 - dy) as usize;
+        for x in (x0..x1).step_by(step) {
+            let ax = (x as i32 - dx) as usize;
+            sum += (a[ay * width + ax] as i32 - b[y * width + x] as i32).unsigned_abs() as u64;
+            n += 1;
+        }
+    }
+    sum as f64 / n as f64
+}
+
+// 候補の中からコスト最小のずれを選ぶ（同コストなら移動量の小さい方）
+fn best_shift(
+    a: &[u8],
+    b: &[u8],
+    width: usize,
+    height: usize,
+    candidates: Vec<(i32, i32)>,
+    step: usize,
+) -> (i32, i32) {
+    candidates
+        .into_par_iter()
+        .map(|(dx, dy)| (shift_cost(a, b, width, height, dx, dy, step), dx, dy))
+        .min_by(|p, q| {
+            p.0.total_cmp(&q.0)
+                .then((p.1.abs() + p.2.abs()).cmp(&(q.1.abs() + q.2.abs())))
         })
+        .map(|(_, dx, dy)| (dx, dy))
+        .unwrap_or((0, 0))
+}
+
+// A→B の平行移動量を推定
+fn estimate_translation(a: &[u8], b: &[u8], width: u32, height: u32) -> AlignOffset {
+    let w = width as usize;
+    let h = height as usize;
+    let (luma_a, luma_b) = rayon::join(|| rgba_to_luma(a), || rgba_to_luma(b));
+
+    // 1. 縮小画像で粗探索
+    let factor = (width.max(height) / ALIGN_COARSE_DIM).max(1) as usize;
+    let (coarse_a, cw, ch) = downsample_luma(&luma_a, w, h, factor);
+    let (coarse_b, _, _) = downsample_luma(&luma_b, w, h, factor);
+    let coarse_range = (ALIGN_MAX_SHIFT + factor as i32 - 1) / factor as i32;
+    let coarse: Vec<(i32, i32)> = (-coarse_range..=coarse_range)
+        .flat_map(|dy| (-coarse_range..=coarse_range).map(move |dx| (dx, dy)))
         .collect();
+    let (cdx, cdy) = best_shift(&coarse_a, &coarse_b, cw, ch, coarse, 1);
+
+    // 2. 原寸で粗探索結果 ±factor の範囲を精密探索
+    let f = factor as i32;
+    let (bx, by) = (cdx * f, cdy * f);
+    let fine: Vec<(i32, i32)> = ((by - f)..=(by + f))
+        .flat_map(|dy| ((bx - f)..=(bx + f)).map(move |dx| (dx, dy)))
+        .filter(|(dx, dy)| dx.abs() <= ALIGN_MAX_SHIFT && dy.abs() <= ALIGN_MAX_SHIFT)
+        .collect();
+    let step = if factor > 1 { ALIGN_FINE_STEP } else { 1 };
+    let (dx, dy) = best_shift(&luma_a, &luma_b, w, h, fine, step);
+
+    AlignOffset { dx, dy }
+}
+
+// A を (dx, dy) ずらした画像を生成。はみ出して空いた部分は B の画素で埋める（差分にしない）
+fn shift_rgba(a: &[u8], b: &[u8], width: u32, height: u32, offset: AlignOffset) -> Vec<u8> {
+    let w = width as usize;
+    let h = height as usize;
+    let mut out = b.to_vec();
+    out.par_chunks_mut(w * 4).enumerate().for_each(|(y, row)| {
+        let ay = y as i32 - offset.dy;
+        if ay < 0 || ay >= h as i32 {
+            return;
+        }
+        let src_row = &a[ay as usize * w * 4..(ay as usize + 1) * w * 4];
+        for x in 0..w {
+            let ax = x as i32 - offset.dx;
+            if ax >= 0 && ax < w as i32 {
+                let si = ax as usize * 4;
+                row[x * 4..x * 4 + 4].copy_from_slice(&src_row[si..si + 4]);
+            }
+        }
+    });
+    out
+}
 
-    markers.sort_by(|a, b| b.count.cmp(&a.count));
+// TIFF座標のマーカーを元PSD座標へ変換（位置合わせのずれ → 拡縮 → クロップ位置の順に戻す）
+fn markers_to_psd_space(
+    markers: &[DiffMarker],
+    crop_bounds: &CropBounds,
+    scale_x: f64,
+    scale_y: f64,
+    align_offset: Option<AlignOffset>,
+) -> Vec<DiffMarker> {
+    let (dx, dy) = align_offset.map_or((0.0, 0.0), |o| (o.dx as f64, o.dy as f64));
     markers
+        .iter()
+        .map(|m| DiffMarker {
+            x: crop_bounds.left as f64 + (m.x - dx) * scale_x,
+            y: crop_bounds.top as f64 + (m.y - dy) * scale_y,
+            radius: m.radius * scale_x.max(scale_y),
+            count: m.count,
+            bbox: MarkerBounds {
+                left: crop_bounds.left as f64 + (m.bbox.left - dx) * scale_x,
+                top: crop_bounds.top as f64 + (m.bbox.top - dy) * scale_y,
+                right: crop_bounds.left as f64 + (m.bbox.right - dx) * scale_x,
+                bottom: crop_bounds.top as f64 + (m.bbox.bottom - dy) * scale_y,
+            },
+            id: m.id,
+            kind: m.kind,
+        })
+        .collect()
+}
+
+// auto_align 指定時は A を B に位置合わせした画像とずれ量を返す
+fn align_if_requested(
+    rgba_a: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    rgba_b: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    auto_align: bool,
+) -> (ImageBuffer<Rgba<u8>, Vec<u8>>, Option<AlignOffset>) {
+    if !auto_align {
+        return (rgba_a, None);
+    }
+    let (width, height) = rgba_b.dimensions();
+    let offset = estimate_translation(rgba_a.as_raw(), rgba_b.as_raw(), width, height);
+    if offset.dx == 0 && offset.dy == 0 {
+        return (rgba_a, Some(offset));
+    }
+    let shifted = shift_rgba(rgba_a.as_raw(), rgba_b.as_raw(), width, height, offset);
+    let aligned = ImageBuffer::from_raw(width, height, shifted).unwrap_or(rgba_a);
+    (aligned, Some(offset))
+}
+
+// A | diff | B を横に連結した1枚の画像を作る（3枚とも width x height）
+fn compose_triptych(
+    img_a: &DynamicImage,
+    diff_rgba: &[u8],
+    img_b: &DynamicImage,
+    width: u32,
+    height: u32,
+) -> DynamicImage {
+    let rgb_a = img_a.to_rgb8();
+    let rgb_b = img_b.to_rgb8();
+    let w = width as usize;
+    let mut canvas = vec![0u8; w * 3 * height as usize * 3];
+    canvas
+        .par_chunks_mut(w * 3 * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let src = y * w * 3;
+            row[..w * 3].copy_from_slice(&rgb_a.as_raw()[src..src + w * 3]);
+            for (dst, px) in row[w * 3..w * 6]
+                .chunks_exact_mut(3)
+                .zip(diff_rgba[y * w * 4..(y + 1) * w * 4].chunks_exact(4))
+            {
+                dst.copy_from_slice(&px[..3]);
+            }
+            row[w * 6..].copy_from_slice(&rgb_b.as_raw()[src..src + w * 3]);
+        });
+    DynamicImage::ImageRgb8(
+        ImageBuffer::from_raw(width * 3, height, canvas).expect("triptych buffer size"),
+    )
+}
+
+// 同サイズに揃えた2画像と、そのプレビュー用キャッシュキー
+struct AlignedPair {
+    img_a: DynamicImage,
+    img_b: DynamicImage,
+    width: u32,
+    height: u32,
+    key_a: String, // ファイル + ページの識別子（派生画像のキー用）
+    key_b: String,
+    cache_a: String, // 揃えた A / B の JPEG のキャッシュキー
+    cache_b: String,
+    timings: DiffTimings, // decode_ms / resize_ms のみ計測済み
+}
+
+impl AlignedPair {
+    // 両画像を ROI で切り出す（キーにも ROI を含める）。ROI は画像内に収まっていること
+    fn crop_to_roi(self, roi: &CropBounds) -> Result<AlignedPair, AppError> {
+        if roi.clamp_to(self.width, self.height)? != *roi {
+            return Err(AppError::InvalidCrop(format!(
+                "ROI が画像 ({}x{}) からはみ出しています (left={}, top={}, right={}, bottom={})",
+                self.width, self.height, roi.left, roi.top, roi.right, roi.bottom
+            )));
+        }
+        let (w, h) = (roi.right - roi.left, roi.bottom - roi.top);
+        let tag = format!("_roi{}-{}-{}-{}", roi.left, roi.top, roi.right, roi.bottom);
+        Ok(AlignedPair {
+            img_a: self.img_a.crop_imm(roi.left, roi.top, w, h),
+            img_b: self.img_b.crop_imm(roi.left, roi.top, w, h),
+            width: w,
+            height: h,
+            key_a: self.key_a + &tag,
+            key_b: self.key_b + &tag,
+            cache_a: self.cache_a + &tag,
+            cache_b: self.cache_b + &tag,
+            timings: self.timings,
+        })
+    }
+}
+
+// 除外領域を ROI ローカル座標に移す（ROI 外の部分は捨てる）
+fn ignore_regions_in_roi(regions: &[CropBounds], roi: &CropBounds) -> Vec<CropBounds> {
+    regions
+        .iter()
+        .filter_map(|g| {
+            let local = CropBounds {
+                left: g.left.max(roi.left) - roi.left,
+                top: g.top.max(roi.top) - roi.top,
+                right: g.right.min(roi.right).saturating_sub(roi.left),
+                bottom: g.bottom.min(roi.bottom).saturating_sub(roi.top),
+            };
+            (local.left < local.right && local.top < local.bottom).then_some(local)
+        })
+        .collect()
+}
+
+// ファイルの代わりに path_b に指定できる合成の比較用画像（見当・ノセの確認用）
+//   "color:#RRGGBB"                        → 単色
+//   "gradient:#RRGGBB,#RRGGBB[,vertical]" → 左→右（vertical なら上→下）の線形グラデーション
+enum SyntheticReference {
+    Color([u8; 3]),
+    Gradient {
+        from: [u8; 3],
+        to: [u8; 3],
+        vertical: bool,
+    },
+}
+
+impl SyntheticReference {
+    // 疑似パスでなければ None、書式が不正ならエラー
+    fn parse(path: &str) -> Option<Result<Self, AppError>> {
+        let invalid = || AppError::Unsupported(format!("Invalid synthetic reference: {}", path));
+        if let Some(spec) = path.strip_prefix("color:") {
+            return Some(parse_hex_rgb(spec).map(Self::Color).ok_or_else(invalid));
+        }
+        let spec = path.strip_prefix("gradient:")?;
+        let parts: Vec<&str> = spec.split(',').map(str::trim).collect();
+        let vertical = match parts.get(2).copied() {
+            None | Some("horizontal") => Some(false),
+            Some("vertical") => Some(true),
+            Some(_) => None,
+        };
+        let parsed = match (parts.len(), vertical) {
+            (2 | 3, Some(vertical)) => parse_hex_rgb(parts[0])
+                .zip(parse_hex_rgb(parts[1]))
+                .map(|(from, to)| Self::Gradient { from, to, vertical }),
+            _ => None,
+        };
+        Some(parsed.ok_or_else(invalid))
+    }
+
+    fn render(&self, width: u32, height: u32) -> DynamicImage {
+        let img = match *self {
+            Self::Color(c) => RgbImage::from_pixel(width, height, Rgb(c)),
+            Self::Gradient { from, to, vertical } => {
+                let span = if vertical { height } else { width }
+                    .saturating_sub(1)
+                    .max(1) as f32;
+                RgbImage::from_fn(width, height, |x, y| {
+                    let t = if vertical { y } else { x } as f32 / span;
+                    Rgb(std::array::from_fn(|i| {
+                        (from[i] as f32 + (to[i] as f32 - from[i] as f32) * t).round() as u8
+                    }))
+                })
+            }
+        };
+        DynamicImage::ImageRgb8(img)
+    }
+}
+
+// "#RRGGBB"（# は省略可）→ [r, g, b]
+fn parse_hex_rgb(s: &str) -> Option<[u8; 3]> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok();
+    Some([channel(0)?, channel(1)?, channel(2)?])
+}
+
+// 2ファイルを並列デコードし、mismatch_policy に従ってサイズを揃える
+// （compute_diff_simple / compute_onion_skin 共通。同じキーなので A / B の JPEG を共有する）
+// path_b が合成の比較用画像（SyntheticReference）なら A と同じサイズで生成する
+fn decode_aligned_pair(
+    path_a: &str,
+    path_b: &str,
+    page: usize,
+    policy: MismatchPolicy,
+) -> Result<AlignedPair, AppError> {
+    let mut lap = Instant::now();
+    let mut timings = DiffTimings::default();
+    let synthetic = SyntheticReference::parse(path_b);
+    let is_synthetic = synthetic.is_some();
+    let (img_a, img_b) = match synthetic {
+        Some(reference) => {
+            let reference = reference?;
+            let img_a = decode_image_file(path_a, page)?;
+            let img_b = reference.render(img_a.width(), img_a.height());
+            (img_a, img_b)
+        }
+        None => {
+            let (img_a, img_b) = decode_pair(
+                interactive_pool(),
+                || decode_image_file(path_a, page),
+                || decode_image_file(path_b, page),
+            );
+            (img_a?, img_b?)
+        }
+    };
+    timings.decode_ms = lap_ms(&mut lap);
+
+    let (wa, ha) = img_a.dimensions();
+    let (wb, hb) = img_b.dimensions();
+
+    // サイズが異なれば mismatch_policy に従って揃える
+    let (img_a, img_b) = align_image_pair(img_a, img_b, policy)?;
+    let (width, height) = img_a.dimensions();
+    timings.resize_ms = lap_ms(&mut lap);
+
+    // 揃えた結果が元サイズと異なる場合は、その揃え方とサイズもキーに含める
+    let aligned_tag = |w: u32, h: u32| {
+        if w == width && h == height {
+            String::new()
+        } else {
+            let how = match policy {
+                MismatchPolicy::Pad => "pad",
+                _ => "rs",
+            };
+            format!("_{}{}x{}", how, width, height)
+        }
+    };
+    // 2ページ目以降は別キー（1ページ目は従来と同じキーのまま）
+    let page_tag = if page > 0 {
+        format!("_p{}", page)
+    } else {
+        String::new()
+    };
+    let key_a = format!("{}{}", versioned_path_key(path_a), page_tag);
+    // 合成画像は A のサイズで作るのでサイズもキーに含める
+    let key_b = if is_synthetic {
+        format!("{}_{}x{}", path_b, wb, hb)
+    } else {
+        format!("{}{}", versioned_path_key(path_b), page_tag)
+    };
+    let cache_a = format!("simple_a_{}{}", key_a, aligned_tag(wa, ha));
+    let cache_b = format!("simple_b_{}{}", key_b, aligned_tag(wb, hb));
+
+    Ok(AlignedPair {
+        img_a,
+        img_b,
+        width,
+        height,
+        key_a,
+        key_b,
+        cache_a,
+        cache_b,
+        timings,
+    })
+}
+
+#[derive(Serialize)]
+struct OnionSkinResult {
+    src_a: String,     // 揃えた A（compute_diff_simple の src_a と同じファイル）
+    src_b: String,     // 揃えた B
+    blend_src: String, // A * (1 - alpha) + B * alpha
+    alpha: f32,
+    image_width: u32,
+    image_height: u32,
+}
+
+// ブリンク / オニオンスキン比較用: 揃えた A・B と半透明合成画像を返す
+// フロントは src_a / src_b を切り替えて点滅表示し、blend_src で重ね合わせを表示する
+// alpha: B の不透明度 0〜1（省略時 0.5）
+#[tauri::command]
+fn compute_onion_skin(
+    path_a: String,
+    path_b: String,
+    alpha: Option<f32>,
+    mismatch_policy: Option<MismatchPolicy>,
+    page: Option<usize>,
+) -> Result<OnionSkinResult, AppError> {
+    let alpha = alpha.unwrap_or(0.5).clamp(0.0, 1.0);
+    let AlignedPair {
+        img_a,
+        img_b,
+        width,
+        height,
+        key_a,
+        key_b,
+        cache_a,
+        cache_b,
+        ..
+    } = decode_aligned_pair(
+        &path_a,
+        &path_b,
+        page.unwrap_or(0),
+        mismatch_policy.unwrap_or_default(),
+    )?;
+
+    let (rgb_a, rgb_b) = rayon::join(|| img_a.to_rgb8(), || img_b.to_rgb8());
+    let wb = (alpha * 256.0).round() as u32;
+    let mut blend = vec![0u8; rgb_a.as_raw().len()];
+    blend
+        .par_iter_mut()
+        .zip(rgb_a.as_raw().par_iter().zip(rgb_b.as_raw().par_iter()))
+        .for_each(|(out, (&pa, &pb))| {
+            *out = ((pa as u32 * (256 - wb) + pb as u32 * wb + 128) >> 8) as u8;
+        });
+    let blend_img = DynamicImage::ImageRgb8(
+        ImageBuffer::from_raw(width, height, blend).expect("blend buffer size"),
+    );
+    let cache_o = format!("onion_{}_{}_a{}", key_a, key_b, wb);
+
+    let (src_a, (src_b, blend_src)) = rayon::join(
+        || encode_to_jpeg_temp(&img_a, &cache_a),
+        || {
+            rayon::join(
+                || encode_to_jpeg_temp(&img_b, &cache_b),
+                || encode_to_jpeg_temp(&blend_img, &cache_o),
+            )
+        },
+    );
+
+    Ok(OnionSkinResult {
+        src_a: src_a?,
+        src_b: src_b?,
+        blend_src: blend_src?,
+        alpha,
+        image_width: width,
+        image_height: height,
+    })
 }
 
 // tiff-tiff / psd-psd 用の差分計算
+// path_b: "color:#RRGGBB" / "gradient:#RRGGBB,#RRGGBB[,vertical]" なら A と同サイズの合成画像と比較する
+// options.mode: "rgb"（既定）| "deltae"（CIEDE2000、色校正向け）
+// ignore_regions: 比較しない矩形（比較サイズ = 大きい方の画像のピクセル座標）
+// options.include_composite: true なら3枚を横に並べた composite_src も返す（デコード済みバッファを再利用）
+// page: マルチページ TIFF / GIF で比較するページ（両ファイル共通、省略時 0）
+// roi: 指定時はこの範囲（比較サイズのピクセル座標）だけを切り出して比較する。
+//      返す画像・マーカー・image_width / image_height は ROI 内のローカル座標
 #[tauri::command]
 fn compute_diff_simple(
     path_a: String,
     path_b: String,
-    threshold: u8,
-) -> Result<DiffSimpleResult, String> {
-    // 2ファイル並列デコード
-    let (img_a, img_b) = rayon::join(|| decode_image_file(&path_a), || decode_image_file(&path_b));
-    let img_a = img_a?;
-    let img_b = img_b?;
+    threshold: DiffThreshold,
+    options: Option<SimpleDiffOptions>,
+    ignore_regions: Option<Vec<CropBounds>>,
+    page: Option<usize>,
+    roi: Option<CropBounds>,
+) -> Result<DiffSimpleResult, AppError> {
+    let threshold = threshold.to_u8();
+    let mut options = options.unwrap_or_default();
+    let ignore_regions = ignore_regions.unwrap_or_default();
+    let pair = decode_aligned_pair(&path_a, &path_b, page.unwrap_or(0), options.mismatch_policy)?;
+
+    // ROI で両画像を切り出し、除外領域も ROI ローカル座標に移す
+    let (
+        AlignedPair {
+            img_a,
+            img_b,
+            width,
+            height,
+            key_a,
+            key_b,
+            cache_a,
+            cache_b,
+            mut timings,
+        },
+        ignore_regions,
+    ) = match roi {
+        Some(r) => (
+            pair.crop_to_roi(&r)?,
+            ignore_regions_in_roi(&ignore_regions, &r),
+        ),
+        None => (pair, ignore_regions),
+    };
+    let mut lap = Instant::now();
+
+    // 地の色を原稿の縁の色から決める（決めた色はキャッシュキーにも入る）
+    if options.style.auto_background && !options.style.overlay_on_source {
+        options.style.background = contrasting_background(border_median_color(&[&img_a, &img_b]));
+    }
+
+    // 差分計算（巨大画像は帯単位）
+    let (diff_buf, diff_count, diff_pixels, worst) =
+        diff_simple_images(&img_a, &img_b, threshold, &options, &ignore_regions, true);
+
+    // マーカークラスタリング → 追加 / 欠落 / 変更の判定
+    let markers = drop_ignored_markers(
+        cluster_markers(&diff_pixels, 200, 1, 300.0),
+        &ignore_regions,
+    );
+    let markers = limit_markers(markers, options.max_markers, options.merge_overlap);
+    let markers = classify_markers(markers, &diff_pixels, &img_a, &img_b);
+    timings.diff_ms = lap_ms(&mut lap);
+
+    // 表示用に縮小してからエンコード（マーカー・image_width / image_height はフル解像度のまま）
+    let (out_w, out_h) = match options.display_max_dim {
+        Some(max) if max > 0 => fit_within(width, height, max, max),
+        _ => (width, height),
+    };
+    let display_scale = out_w as f64 / width as f64;
+    let (img_a, img_b, diff_buf, display_tag) = if (out_w, out_h) != (width, height) {
+        // 差分画像は補間すると1px の差分が背景に溶けて消えるので、ブロック内に差分があればその色を残す
+        let ((img_a, img_b), diff_buf) = rayon::join(
+            || {
+                rayon::join(
+                    || img_a.resize_exact(out_w, out_h, FilterType::Triangle),
+                    || img_b.resize_exact(out_w, out_h, FilterType::Triangle),
+                )
+            },
+            || downscale_diff_buf(&diff_buf, width, height, &diff_pixels, out_w, out_h),
+        );
+        let tag = format!("_disp{}x{}", out_w, out_h);
+        (img_a, img_b, diff_buf?, tag)
+    } else {
+        (img_a, img_b, diff_buf, String::new())
+    };
+
+    // 3画像を並列エンコード → JPEG tempファイル（A/B）+ PNG tempファイル（diff）
+    let cache_a = cache_a + &display_tag;
+    let cache_b = cache_b + &display_tag;
+    let cache_d = format!(
+        "simple_d_{}_{}_t{}_{}{}{}",
+        key_a,
+        key_b,
+        threshold,
+        options.cache_tag(),
+        ignore_regions_tag(&ignore_regions),
+        display_tag
+    );
+    let (src_a_result, (src_b_result, diff_result)) = rayon::join(
+        || encode_to_jpeg_temp(&img_a, &cache_a),
+        || {
+            rayon::join(
+                || encode_to_jpeg_temp(&img_b, &cache_b),
+                || encode_rgba_to_png_temp(&diff_buf, out_w, out_h, &cache_d),
+            )
+        },
+    );
+
+    let composite_src = if options.include_composite {
+        let cache_c = cache_d.replacen("simple_d_", "simple_c_", 1);
+        let composite = compose_triptych(&img_a, &diff_buf, &img_b, out_w, out_h);
+        Some(encode_to_jpeg_temp(&composite, &cache_c)?)
+    } else {
+        None
+    };
+    timings.encode_ms = lap_ms(&mut lap);
+
+    Ok(DiffSimpleResult {
+        src_a: src_a_result?,
+        src_b: src_b_result?,
+        diff_src: diff_result?,
+        has_diff: diff_count > 0,
+        diff_count,
+        diff_ratio: diff_ratio(diff_count, width, height, &ignore_regions),
+        markers,
+        image_width: width,
+        image_height: height,
+        composite_src,
+        max_delta: worst.map_or(0, |w| w.delta),
+        worst,
+        roi,
+        display_scale,
+        timings: options.profile.then_some(timings),
+    })
+}
+
+// psd-tiff 用のヒートマップ差分計算
+// auto_align: true ならクロップ・リサイズ後のPSDをTIFFに位置合わせしてから差分を取る
+// ignore_regions: 比較しない矩形（TIFFピクセル座標）
+// params: 密度半径・マーカー抽出の調整（未指定なら既定値）
+#[tauri::command]
+fn compute_diff_heatmap(
+    psd_path: String,
+    tiff_path: String,
+    crop_bounds: CropBounds,
+    threshold: DiffThreshold,
+    auto_align: Option<bool>,
+    ignore_regions: Option<Vec<CropBounds>>,
+    params: Option<HeatmapParams>,
+) -> Result<DiffHeatmapResult, AppError> {
+    let threshold = threshold.to_u8();
+    let ignore_regions = ignore_regions.unwrap_or_default();
+    let params = params.unwrap_or_default().validated()?;
+    let mut lap = Instant::now();
+    let mut timings = DiffTimings::default();
+    // 並列デコード
+    let (psd_result, tiff_result) = decode_pair(
+        interactive_pool(),
+        || decode_psd_to_image(&psd_path),
+        || image::open(&tiff_path).map_err(|e| AppError::from_image("Failed to open TIFF", e)),
+    );
+    let psd_img = ensure_non_empty(psd_result?, &psd_path)?;
+    let tiff_img = ensure_non_empty(tiff_result?, &tiff_path)?;
+    timings.decode_ms = lap_ms(&mut lap);
+
+    let (tiff_w, tiff_h) = tiff_img.dimensions();
+
+    // PSDをクロップ（範囲はPSDの実寸に収める）
+    let crop_bounds = crop_bounds.clamp_to(psd_img.width(), psd_img.height())?;
+    let crop_w = crop_bounds.right - crop_bounds.left;
+    let crop_h = crop_bounds.bottom - crop_bounds.top;
+    let cropped = psd_img.crop_imm(crop_bounds.left, crop_bounds.top, crop_w, crop_h);
 
-    let (wa, ha) = img_a.dimensions();
-    let (wb, hb) = img_b.dimensions();
-    let width = wa.max(wb);
-    let height = ha.max(hb);
+    // TIFFサイズにリサイズ（CatmullRom = Photoshop ResampleMethod.AUTOMATIC 相当）
+    let processed_psd = cropped.resize_exact(tiff_w, tiff_h, FilterType::CatmullRom);
 
-    // 必要ならリサイズ
-    let img_a = if wa != width || ha != height {
-        img_a.resize_exact(width, height, FilterType::Triangle)
-    } else {
-        img_a
-    };
-    let img_b = if wb != width || hb != height {
-        img_b.resize_exact(width, height, FilterType::Triangle)
+    let rgba_b = tiff_img.to_rgba8();
+    let (rgba_a, align_offset) = align_if_requested(
+        processed_psd.to_rgba8(),
+        &rgba_b,
+        auto_align.unwrap_or(false),
+    );
+    timings.resize_ms = lap_ms(&mut lap);
+
+    // ヒートマップ差分計算
+    let (heatmap_buf, high_density_count, high_pixels, region_counts) = diff_heatmap_core(
+        rgba_a.as_raw(),
+        rgba_b.as_raw(),
+        tiff_w,
+        tiff_h,
+        threshold,
+        &ignore_regions,
+        &params,
+    );
+
+    // 位置合わせした場合は表示用のprocessedAも合わせた画像にする
+    let processed_psd = if align_offset.is_some() {
+        DynamicImage::ImageRgba8(rgba_a)
     } else {
-        img_b
+        processed_psd
     };
 
-    let rgba_a = img_a.to_rgba8();
-    let rgba_b = img_b.to_rgba8();
+    // マーカークラスタリング（既定: gridSize=250, minCluster=20, minRadius=80）
+    let markers = params.cluster(&high_pixels, &ignore_regions);
+    let markers = classify_markers(markers, &high_pixels, &processed_psd, &rgba_b);
+    let scale_x = crop_w as f64 / tiff_w as f64;
+    let scale_y = crop_h as f64 / tiff_h as f64;
+    let psd_markers = markers_to_psd_space(&markers, &crop_bounds, scale_x, scale_y, align_offset);
+    timings.diff_ms = lap_ms(&mut lap);
 
-    // 差分計算
-    let (diff_buf, diff_count, diff_pixels) =
-        diff_simple_core(rgba_a.as_raw(), rgba_b.as_raw(), width, height, threshold);
-
-    // マーカークラスタリング
-    let markers = cluster_markers(&diff_pixels, 200, 1, 300.0);
+    // diffProbability計算
+    let diff_probability = if high_density_count > 0 {
+        let total_pixels = (tiff_w as f64) * (tiff_h as f64);
+        let base_prob = 70.0;
+        let additional = (high_density_count as f64 / total_pixels * 50000.0).min(30.0);
+        ((base_prob + additional) * 10.0).round() / 10.0
+    } else {
+        0.0
+    };
 
-    // 3画像を並列エンコード → JPEG tempファイル（A/B）+ PNG tempファイル（diff）
-    let cache_a = format!("simple_a_{}", versioned_path_key(&path_a));
-    let cache_b = format!("simple_b_{}", versioned_path_key(&path_b));
+    // 4画像を並列エンコード → JPEG tempファイル（A/B/processedA）+ PNG tempファイル（diff）
+    let cache_a = format!("heatmap_a_{}", versioned_path_key(&psd_path));
+    let cache_b = format!("heatmap_b_{}", versioned_path_key(&tiff_path));
+    // 位置合わせ結果が変わればprocessedA/diffも別ファイルにする
+    let align_suffix = align_offset
+        .map(|o| format!("_align{}_{}", o.dx, o.dy))
+        .unwrap_or_default();
+    // クロップ範囲が変われば processedA も diff も別の画像になる（レポートに古いクロップを埋め込まない）
+    let crop_suffix = format!(
+        "_c{}-{}-{}-{}",
+        crop_bounds.left, crop_bounds.top, crop_bounds.right, crop_bounds.bottom
+    );
+    let cache_pa = format!(
+        "heatmap_pa_{}_{}{}{}",
+        versioned_path_key(&psd_path),
+        versioned_path_key(&tiff_path),
+        crop_suffix,
+        align_suffix
+    );
     let cache_d = format!(
-        "simple_d_{}_{}",
-        versioned_path_key(&path_a),
-        versioned_path_key(&path_b)
+        "heatmap_d_{}_{}_t{}{}{}{}{}",
+        versioned_path_key(&psd_path),
+        versioned_path_key(&tiff_path),
+        threshold,
+        crop_suffix,
+        align_suffix,
+        ignore_regions_tag(&ignore_regions),
+        params.cache_tag()
     );
-    let (src_a_result, (src_b_result, diff_result)) = rayon::join(
-        || encode_to_jpeg_temp(&img_a, &cache_a),
+    let ((src_a_result, src_b_result), (processed_a_result, diff_result)) = rayon::join(
         || {
             rayon::join(
-                || encode_to_jpeg_temp(&img_b, &cache_b),
-                || encode_rgba_to_png_temp(&diff_buf, width, height, &cache_d),
+                || encode_to_jpeg_temp(&psd_img, &cache_a),
+                || encode_to_jpeg_temp(&tiff_img, &cache_b),
+            )
+        },
+        || {
+            rayon::join(
+                || encode_to_jpeg_temp(&processed_psd, &cache_pa),
+                || encode_rgba_to_png_temp(&heatmap_buf, tiff_w, tiff_h, &cache_d),
             )
         },
     );
+    timings.encode_ms = lap_ms(&mut lap);
 
-    Ok(DiffSimpleResult {
+    Ok(DiffHeatmapResult {
         src_a: src_a_result?,
         src_b: src_b_result?,
+        processed_a: processed_a_result?,
         diff_src: diff_result?,
+        has_diff: high_density_count > 0,
+        diff_probability,
+        high_density_count,
+        markers,
+        image_width: tiff_w,
+        image_height: tiff_h,
+        align_offset,
+        psd_markers,
+        crop_left: crop_bounds.left,
+        crop_top: crop_bounds.top,
+        scale_x,
+        scale_y,
+        region_counts,
+        timings: params.profile.then_some(timings),
+    })
+}
+
+// 同じファイル、またはバイト単位で同一のコピーか（サイズが違えば中身は読まない）
+fn files_identical(path_a: &str, path_b: &str) -> bool {
+    if let (Ok(a), Ok(b)) = (fs::canonicalize(path_a), fs::canonicalize(path_b)) {
+        if a == b {
+            return true;
+        }
+    }
+    let (Ok(meta_a), Ok(meta_b)) = (fs::metadata(path_a), fs::metadata(path_b)) else {
+        return false;
+    };
+    if meta_a.len() != meta_b.len() {
+        return false;
+    }
+    let (Ok(mut reader_a), Ok(mut reader_b)) = (fs::File::open(path_a), fs::File::open(path_b))
+    else {
+        return false;
+    };
+    // 先頭から 1MB ずつ比べ、最初の不一致で打ち切る
+    let mut buf_a = vec![0u8; 1 << 20];
+    let mut buf_b = vec![0u8; 1 << 20];
+    loop {
+        let n = match reader_a.read(&mut buf_a) {
+            Ok(0) => return true,
+            Ok(n) => n,
+            Err(_) => return false,
+        };
+        if reader_b.read_exact(&mut buf_b[..n]).is_err() || buf_a[..n] != buf_b[..n] {
+            return false;
+        }
+    }
+}
+
+// Phase1用: 軽量差分チェック（画像エンコードなし）
+// scale: 0 < scale < 1 なら縮小して比較（diff_count は縮小後のピクセル数、マーカーはフル解像度座標）
+// 同じファイル・同一内容のコピー同士はデコードせずに差分なしを返す
+#[tauri::command]
+fn check_diff_simple(
+    path_a: String,
+    path_b: String,
+    threshold: DiffThreshold,
+    options: Option<SimpleDiffOptions>,
+    ignore_regions: Option<Vec<CropBounds>>,
+    scale: Option<f64>,
+) -> Result<DiffCheckSimpleResult, AppError> {
+    check_diff_simple_on(
+        interactive_pool(),
+        path_a,
+        path_b,
+        threshold,
+        options,
+        ignore_regions,
+        scale,
+    )
+}
+
+// check_diff_simple の本体。pool: デコードに使うプール（一括チェックからは batch_pool()）
+fn check_diff_simple_on(
+    pool: &rayon::ThreadPool,
+    path_a: String,
+    path_b: String,
+    threshold: DiffThreshold,
+    options: Option<SimpleDiffOptions>,
+    ignore_regions: Option<Vec<CropBounds>>,
+    scale: Option<f64>,
+) -> Result<DiffCheckSimpleResult, AppError> {
+    let threshold = threshold.to_u8();
+    let options = options.unwrap_or_default();
+    let ignore_regions = ignore_regions.unwrap_or_default();
+    let scale = scale.unwrap_or(1.0);
+    if !(scale > 0.0 && scale <= 1.0) {
+        return Err(AppError::Other(format!(
+            "scale は 0 より大きく 1 以下で指定してください: {}",
+            scale
+        )));
+    }
+    if files_identical(&path_a, &path_b) {
+        let (width, height) = header_dimensions(&path_a)?;
+        return Ok(DiffCheckSimpleResult {
+            has_diff: false,
+            diff_count: 0,
+            diff_ratio: 0.0,
+            markers: Vec::new(),
+            image_width: width,
+            image_height: height,
+            timings: options.profile.then(DiffTimings::default),
+        });
+    }
+    let mut lap = Instant::now();
+    let mut timings = DiffTimings::default();
+    // 2ファイル並列デコード
+    let (img_a, img_b) = decode_pair(
+        pool,
+        || decode_image_file(&path_a, 0),
+        || decode_image_file(&path_b, 0),
+    );
+    let img_a = img_a?;
+    let img_b = img_b?;
+    timings.decode_ms = lap_ms(&mut lap);
+
+    // サイズが異なれば mismatch_policy に従って揃える
+    let (img_a, img_b) = align_image_pair(img_a, img_b, options.mismatch_policy)?;
+    let (width, height) = img_a.dimensions();
+    timings.resize_ms = lap_ms(&mut lap);
+
+    // 差分計算（巨大画像は帯単位）
+    let (diff_pixels, diff_count, ratio) = if scale < 1.0 {
+        // 縮小してから比較し、差分座標をフル解像度へ戻す（Phase1 の粗い判定用）
+        let sw = ((width as f64 * scale).round() as u32).max(1);
+        let sh = ((height as f64 * scale).round() as u32).max(1);
+        let (small_a, small_b) = rayon::join(
+            || img_a.resize_exact(sw, sh, FilterType::Triangle),
+            || img_b.resize_exact(sw, sh, FilterType::Triangle),
+        );
+        timings.resize_ms += lap_ms(&mut lap);
+        let (fx, fy) = (width as f64 / sw as f64, height as f64 / sh as f64);
+        let small_ignore: Vec<CropBounds> = ignore_regions
+            .iter()
+            .map(|r| CropBounds {
+                left: (r.left as f64 / fx).floor() as u32,
+                top: (r.top as f64 / fy).floor() as u32,
+                right: (r.right as f64 / fx).ceil() as u32,
+                bottom: (r.bottom as f64 / fy).ceil() as u32,
+            })
+            .collect();
+        // ぼかし量も縮小後の画素単位に合わせる
+        let mut small_options = options.clone();
+        if let Some(p) = small_options.prefilter.as_mut() {
+            p.blur_sigma *= scale;
+        }
+        let (_diff_buf, diff_count, diff_pixels, _) = diff_simple_images(
+            &small_a,
+            &small_b,
+            threshold,
+            &small_options,
+            &small_ignore,
+            false,
+        );
+        let full_pixels: Vec<DiffPixel> = diff_pixels
+            .iter()
+            .map(|p| DiffPixel {
+                x: (((p.x as f64 + 0.5) * fx) as u32).min(width - 1),
+                y: (((p.y as f64 + 0.5) * fy) as u32).min(height - 1),
+            })
+            .collect();
+        let ratio = diff_ratio(diff_count, sw, sh, &small_ignore);
+        (full_pixels, diff_count, ratio)
+    } else {
+        let (_diff_buf, diff_count, diff_pixels, _) =
+            diff_simple_images(&img_a, &img_b, threshold, &options, &ignore_regions, false);
+        let ratio = diff_ratio(diff_count, width, height, &ignore_regions);
+        (diff_pixels, diff_count, ratio)
+    };
+
+    // マーカークラスタリング（座標は常にフル解像度）→ 追加 / 欠落 / 変更の判定
+    let markers = drop_ignored_markers(
+        cluster_markers(&diff_pixels, 200, 1, 300.0),
+        &ignore_regions,
+    );
+    let markers = limit_markers(markers, options.max_markers, options.merge_overlap);
+    let markers = classify_markers(markers, &diff_pixels, &img_a, &img_b);
+    timings.diff_ms = lap_ms(&mut lap);
+
+    // 画像エンコードをスキップ！
+    Ok(DiffCheckSimpleResult {
         has_diff: diff_count > 0,
         diff_count,
+        diff_ratio: ratio,
         markers,
         image_width: width,
         image_height: height,
+        timings: options.profile.then_some(timings),
     })
 }
 
-// psd-tiff 用のヒートマップ差分計算
+// 2画像の生の差分マスク（ヒートマップの diffMask と同じ判定）を返す。OCR・領域解析など外部ツール向け
+// サイズが異なる場合は小さい方を大きい方に合わせてリサイズ
 #[tauri::command]
-fn compute_diff_heatmap(
+fn compute_diff_mask(
+    path_a: String,
+    path_b: String,
+    threshold: DiffThreshold,
+) -> Result<DiffMaskResult, AppError> {
+    let threshold = threshold.to_u8();
+    let (img_a, img_b) = decode_pair(
+        interactive_pool(),
+        || decode_image_file(&path_a, 0),
+        || decode_image_file(&path_b, 0),
+    );
+    let (img_a, img_b) = align_image_pair(img_a?, img_b?, MismatchPolicy::Resize)?;
+    let (width, height) = img_a.dimensions();
+    let (rgba_a, rgba_b) = rayon::join(|| img_a.to_rgba8(), || img_b.to_rgba8());
+
+    let mask = heatmap_diff_mask(
+        rgba_a.as_raw(),
+        rgba_b.as_raw(),
+        width,
+        height,
+        threshold,
+        &[],
+    );
+    let diff_count = mask.iter().filter(|&&m| m != 0).count() as u32;
+
+    // 8px → 1byte に詰める
+    let row_bytes = (width as usize).div_ceil(8);
+    let mut packed = vec![0u8; row_bytes * height as usize];
+    packed
+        .par_chunks_mut(row_bytes)
+        .zip(mask.par_chunks(width as usize))
+        .for_each(|(dst, src)| {
+            for (x, _) in src.iter().enumerate().filter(|(_, &m)| m != 0) {
+                dst[x / 8] |= 0x80 >> (x % 8);
+            }
+        });
+
+    let luma: Vec<u8> = mask.iter().map(|&m| m * 255).collect();
+    let cache_key = format!(
+        "mask_{}_{}_t{}",
+        versioned_path_key(&path_a),
+        versioned_path_key(&path_b),
+        threshold
+    );
+    let mask_src = encode_png_temp(
+        &luma,
+        width,
+        height,
+        image::ExtendedColorType::L8,
+        &cache_key,
+    )?;
+
+    Ok(DiffMaskResult {
+        mask_base64: STANDARD.encode(&packed),
+        mask_src,
+        diff_count,
+        width,
+        height,
+    })
+}
+
+// compute_diff_simple の非同期版（重い処理はブロッキングスレッドで実行し、コマンドスレッドを塞がない）
+#[tauri::command]
+async fn compute_diff_simple_async(
+    path_a: String,
+    path_b: String,
+    threshold: DiffThreshold,
+    options: Option<SimpleDiffOptions>,
+    ignore_regions: Option<Vec<CropBounds>>,
+    page: Option<usize>,
+    roi: Option<CropBounds>,
+) -> Result<DiffSimpleResult, AppError> {
+    run_blocking(move || {
+        compute_diff_simple(
+            path_a,
+            path_b,
+            threshold,
+            options,
+            ignore_regions,
+            page,
+            roi,
+        )
+    })
+    .await
+}
+
+// compute_diff_heatmap の非同期版
+#[tauri::command]
+async fn compute_diff_heatmap_async(
     psd_path: String,
     tiff_path: String,
     crop_bounds: CropBounds,
-    threshold: u8,
-) -> Result<DiffHeatmapResult, String> {
+    threshold: DiffThreshold,
+    auto_align: Option<bool>,
+    ignore_regions: Option<Vec<CropBounds>>,
+    params: Option<HeatmapParams>,
+) -> Result<DiffHeatmapResult, AppError> {
+    run_blocking(move || {
+        compute_diff_heatmap(
+            psd_path,
+            tiff_path,
+            crop_bounds,
+            threshold,
+            auto_align,
+            ignore_regions,
+            params,
+        )
+    })
+    .await
+}
+
+// compute_diff の比較方式
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum DiffMode {
+    Simple,  // compute_diff_simple（tiff-tiff / psd-psd）
+    Heatmap, // compute_diff_heatmap（psd-tiff。path_a = PSD, path_b = TIFF）
+}
+
+// compute_diff の引数（方式ごとに使わない項目は無視する）
+#[derive(Deserialize)]
+struct DiffOptions {
+    mode: DiffMode,
+    threshold: DiffThreshold,
+    crop: Option<CropBounds>, // simple: roi / heatmap: PSD のクロップ範囲（省略時は PSD 全体）
+    auto_align: Option<bool>, // heatmap のみ
+    ignore_regions: Option<Vec<CropBounds>>,
+    page: Option<usize>,               // simple のみ
+    simple: Option<SimpleDiffOptions>, // simple のみ
+    heatmap: Option<HeatmapParams>,    // heatmap のみ
+}
+
+// compute_diff の結果（mode フィールドで方式を判別する）
+#[derive(Serialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+enum DiffResult {
+    Simple(DiffSimpleResult),
+    Heatmap(DiffHeatmapResult),
+}
+
+// 差分計算の統一コマンド。options.mode で compute_diff_simple / compute_diff_heatmap に振り分ける
+// （既存の2コマンドはそのまま使える。新しい方式はここに分岐を足す）
+#[tauri::command]
+async fn compute_diff(
+    path_a: String,
+    path_b: String,
+    options: DiffOptions,
+) -> Result<DiffResult, AppError> {
+    run_blocking(move || match options.mode {
+        DiffMode::Simple => compute_diff_simple(
+            path_a,
+            path_b,
+            options.threshold,
+            options.simple,
+            options.ignore_regions,
+            options.page,
+            options.crop,
+        )
+        .map(DiffResult::Simple),
+        DiffMode::Heatmap => compute_diff_heatmap(
+            path_a,
+            path_b,
+            options.crop.unwrap_or(CropBounds {
+                left: 0,
+                top: 0,
+                right: u32::MAX,
+                bottom: u32::MAX,
+            }),
+            options.threshold,
+            options.auto_align,
+            options.ignore_regions,
+            options.heatmap,
+        )
+        .map(DiffResult::Heatmap),
+    })
+    .await
+}
+
+// 同期コマンドの本体を tokio のブロッキングスレッドで実行する
+async fn run_blocking<T, F>(f: F) -> Result<T, AppError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, AppError> + Send + 'static,
+{
+    tauri::async_runtime::spawn_blocking(f)
+        .await
+        .map_err(|e| AppError::Other(format!("Background task failed: {}", e)))?
+}
+
+// warmup で流す画像の一辺（ヒートマップの既定 radius より大きければよい）
+const WARMUP_SIZE: u32 = 64;
+
+#[derive(Serialize)]
+struct WarmupResult {
+    threads: usize, // rayon グローバルプールのスレッド数
+    elapsed_ms: u64,
+}
+
+// 起動直後の初回差分だけ遅い（rayon プールのスレッド起動、デコーダー / エンコーダーの初回呼び出し）ので、
+// スプラッシュ表示中に小さな画像で差分の経路を一通り流して済ませておく。何度呼んでもよい
+#[tauri::command]
+async fn warmup() -> Result<WarmupResult, AppError> {
+    run_blocking(|| {
+        let start = Instant::now();
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(WARMUP_SIZE, WARMUP_SIZE, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255])
+        }));
+
+        // エンコード → デコードの往復（JPEG プレビュー / PNG 差分画像 / TIFF 原稿）
+        let jpeg = encode_image_as(&img, PreviewFormat::Jpeg, PREVIEW_JPEG_QUALITY)
+            .map_err(AppError::Encode)?;
+        let png = encode_image_as(&img, PreviewFormat::Png, PREVIEW_JPEG_QUALITY)
+            .map_err(AppError::Encode)?;
+        let mut tiff = Cursor::new(Vec::new());
+        img.write_to(&mut tiff, image::ImageFormat::Tiff)
+            .map_err(|e| AppError::Encode(format!("Failed to encode TIFF: {}", e)))?;
+        let decode = |bytes: &[u8]| {
+            image::load_from_memory(bytes)
+                .map_err(|e| AppError::from_image("Warmup decode failed", e))
+        };
+        // 操作用プール・先読み用プールのスレッドもここで起こす
+        let (a, b) = decode_pair(
+            interactive_pool(),
+            || decode(&jpeg),
+            || decode(tiff.get_ref()),
+        );
+        decode_pool().install(|| decode(&png))?;
+        let (a, b) = (a?.to_rgba8(), b?.to_rgba8());
+
+        // 差分計算（グローバルプールの全スレッドが動く）
+        diff_simple_core(
+            a.as_raw(),
+            b.as_raw(),
+            WARMUP_SIZE,
+            WARMUP_SIZE,
+            0,
+            &SimpleDiffOptions::default(),
+            &[],
+            true,
+        );
+        diff_heatmap_core(
+            a.as_raw(),
+            b.as_raw(),
+            WARMUP_SIZE,
+            WARMUP_SIZE,
+            0,
+            &[],
+            &HeatmapParams::default(),
+        );
+
+        Ok(WarmupResult {
+            threads: rayon::current_num_threads(),
+            elapsed_ms: start.elapsed().as_millis() as u64,
+        })
+    })
+    .await
+}
+
+// Phase1用: 軽量ヒートマップ差分チェック（画像エンコードなし）
+#[tauri::command]
+fn check_diff_heatmap(
+    psd_path: String,
+    tiff_path: String,
+    crop_bounds: CropBounds,
+    threshold: DiffThreshold,
+    auto_align: Option<bool>,
+    ignore_regions: Option<Vec<CropBounds>>,
+    params: Option<HeatmapParams>,
+) -> Result<DiffCheckHeatmapResult, AppError> {
+    check_diff_heatmap_on(
+        interactive_pool(),
+        psd_path,
+        tiff_path,
+        crop_bounds,
+        threshold,
+        auto_align,
+        ignore_regions,
+        params,
+    )
+}
+
+// check_diff_heatmap の本体。pool: デコードに使うプール（一括チェックからは batch_pool()）
+#[allow(clippy::too_many_arguments)]
+fn check_diff_heatmap_on(
+    pool: &rayon::ThreadPool,
+    psd_path: String,
+    tiff_path: String,
+    crop_bounds: CropBounds,
+    threshold: DiffThreshold,
+    auto_align: Option<bool>,
+    ignore_regions: Option<Vec<CropBounds>>,
+    params: Option<HeatmapParams>,
+) -> Result<DiffCheckHeatmapResult, AppError> {
+    let threshold = threshold.to_u8();
+    let ignore_regions = ignore_regions.unwrap_or_default();
+    let params = params.unwrap_or_default().validated()?;
+    let mut lap = Instant::now();
+    let mut timings = DiffTimings::default();
     // 並列デコード
-    let (psd_result, tiff_result) = rayon::join(
+    let (psd_result, tiff_result) = decode_pair(
+        pool,
         || decode_psd_to_image(&psd_path),
-        || image::open(&tiff_path).map_err(|e| format!("Failed to open TIFF: {}", e)),
+        || image::open(&tiff_path).map_err(|e| AppError::from_image("Failed to open TIFF", e)),
     );
-    let psd_img = psd_result?;
-    let tiff_img = tiff_result?;
+    let psd_img = ensure_non_empty(psd_result?, &psd_path)?;
+    let tiff_img = ensure_non_empty(tiff_result?, &tiff_path)?;
+    timings.decode_ms = lap_ms(&mut lap);
 
     let (tiff_w, tiff_h) = tiff_img.dimensions();
 
-    // PSDをクロップ
+    // PSDをクロップ（範囲はPSDの実寸に収める）
+    let crop_bounds = crop_bounds.clamp_to(psd_img.width(), psd_img.height())?;
     let crop_w = crop_bounds.right - crop_bounds.left;
     let crop_h = crop_bounds.bottom - crop_bounds.top;
     let cropped = psd_img.crop_imm(crop_bounds.left, crop_bounds.top, crop_w, crop_h);
@@ -1591,77 +7797,318 @@ fn compute_diff_heatmap(
     // TIFFサイズにリサイズ（CatmullRom = Photoshop ResampleMethod.AUTOMATIC 相当）
     let processed_psd = cropped.resize_exact(tiff_w, tiff_h, FilterType::CatmullRom);
 
-    let rgba_a = processed_psd.to_rgba8();
     let rgba_b = tiff_img.to_rgba8();
+    let (rgba_a, align_offset) = align_if_requested(
+        processed_psd.to_rgba8(),
+        &rgba_b,
+        auto_align.unwrap_or(false),
+    );
+    timings.resize_ms = lap_ms(&mut lap);
 
     // ヒートマップ差分計算
-    let (heatmap_buf, high_density_count, high_pixels) =
-        diff_heatmap_core(rgba_a.as_raw(), rgba_b.as_raw(), tiff_w, tiff_h, threshold);
+    let (_heatmap_buf, high_density_count, high_pixels, region_counts) = diff_heatmap_core(
+        rgba_a.as_raw(),
+        rgba_b.as_raw(),
+        tiff_w,
+        tiff_h,
+        threshold,
+        &ignore_regions,
+        &params,
+    );
+
+    // マーカークラスタリング
+    let markers = params.cluster(&high_pixels, &ignore_regions);
+    let markers = classify_markers(markers, &high_pixels, &rgba_a, &rgba_b);
+    let scale_x = crop_w as f64 / tiff_w as f64;
+    let scale_y = crop_h as f64 / tiff_h as f64;
+    let psd_markers = markers_to_psd_space(&markers, &crop_bounds, scale_x, scale_y, align_offset);
+    timings.diff_ms = lap_ms(&mut lap);
+
+    // diffProbability計算
+    let diff_probability = if high_density_count > 0 {
+        let total_pixels = (tiff_w as f64) * (tiff_h as f64);
+        let base_prob = 70.0;
+        let additional = (high_density_count as f64 / total_pixels * 50000.0).min(30.0);
+        ((base_prob + additional) * 10.0).round() / 10.0
+    } else {
+        0.0
+    };
+
+    // 画像エンコードをスキップ！
+    Ok(DiffCheckHeatmapResult {
+        has_diff: high_density_count > 0,
+        diff_probability,
+        high_density_count,
+        markers,
+        image_width: tiff_w,
+        image_height: tiff_h,
+        align_offset,
+        psd_markers,
+        crop_left: crop_bounds.left,
+        crop_top: crop_bounds.top,
+        scale_x,
+        scale_y,
+        region_counts,
+        timings: params.profile.then_some(timings),
+    })
+}
+
+// 一括チェックで同時に処理するペア数の上限（PSD/TIFFを同時に展開するためメモリに効く）
+const BATCH_DIFF_THREADS: usize = 4;
+static BATCH_POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+
+// 一括チェック専用のrayonプール（呼び出しごとに作らず使い回す。同時に呼ばれた一括チェックもこの並列数を分け合う）
+fn batch_pool() -> &'static rayon::ThreadPool {
+    BATCH_POOL.get_or_init(|| {
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(BATCH_DIFF_THREADS);
+        build_named_pool("kenban-batch", threads)
+            .or_else(|_| build_named_pool("kenban-batch", 1))
+            .expect("failed to create batch thread pool")
+    })
+}
+
+#[derive(Serialize)]
+struct BatchHeatmapEntry {
+    psd_path: String,
+    tiff_path: String,
+    status: &'static str, // "ok" | "error" | "cancelled"
+    result: Option<DiffCheckHeatmapResult>,
+    error: Option<AppError>,
+}
+
+// 複数の (PSD, TIFF, クロップ範囲) ペアを一括でヒートマップ差分チェック（結果は入力順）
+// batch_pool で並列数を抑え、cancel_preload で未着手のペアはスキップする
+#[tauri::command]
+async fn batch_check_diff_heatmap(
+    state: State<'_, AppState>,
+    pairs: Vec<(String, String, CropBounds)>,
+    threshold: DiffThreshold,
+) -> Result<Vec<BatchHeatmapEntry>, AppError> {
+    let cancel = CancelToken::new(&state.preload_cancel);
+    run_blocking(move || {
+        Ok(batch_pool().install(|| {
+            pairs
+                .into_par_iter()
+                .map(|(psd_path, tiff_path, crop_bounds)| {
+                    if cancel.is_cancelled() {
+                        return BatchHeatmapEntry {
+                            psd_path,
+                            tiff_path,
+                            status: "cancelled",
+                            result: None,
+                            error: None,
+                        };
+                    }
+                    let (status, result, error) = match check_diff_heatmap_on(
+                        batch_pool(),
+                        psd_path.clone(),
+                        tiff_path.clone(),
+                        crop_bounds,
+                        threshold,
+                        None,
+                        None,
+                        None,
+                    ) {
+                        Ok(result) => ("ok", Some(result), None),
+                        Err(e) => ("error", None, Some(e)),
+                    };
+                    BatchHeatmapEntry {
+                        psd_path,
+                        tiff_path,
+                        status,
+                        result,
+                        error,
+                    }
+                })
+                .collect()
+        }))
+    })
+    .await
+}
+
+#[derive(Serialize)]
+struct PagePairResult {
+    page: usize, // 1始まり（自然順で i 番目同士を組にする）
+    path_a: Option<String>,
+    path_b: Option<String>,
+    status: &'static str, // "ok" | "error" | "cancelled" | "unpaired"
+    result: Option<DiffCheckSimpleResult>,
+    error: Option<AppError>,
+    warning: Option<String>, // unpaired のとき: どちらのフォルダにしかないか
+}
+
+// 2フォルダのファイルを自然順で i 番目同士に組み、check_diff_simple で一括チェック（結果はページ順）
+// 片方にしかないファイル（枚数が違うときの余り）は status = "unpaired" として警告付きで返す
+// 並列数（batch_pool）・キャンセル（cancel_preload）は batch_check_diff_heatmap と同じ
+#[tauri::command]
+async fn diff_folders(
+    state: State<'_, AppState>,
+    folder_a: String,
+    folder_b: String,
+    extensions: Vec<String>,
+    threshold: DiffThreshold,
+) -> Result<Vec<PagePairResult>, AppError> {
+    let cancel = CancelToken::new(&state.preload_cancel);
+    run_blocking(move || {
+        let files_a = list_files_in_folder(folder_a, extensions.clone(), None, None)?;
+        let files_b = list_files_in_folder(folder_b, extensions, None, None)?;
+
+        let page_count = files_a.len().max(files_b.len());
+        let mut files_a = files_a.into_iter();
+        let mut files_b = files_b.into_iter();
+        let pairs: Vec<(usize, Option<String>, Option<String>)> = (1..=page_count)
+            .map(|page| (page, files_a.next(), files_b.next()))
+            .collect();
+
+        Ok(batch_pool().install(|| {
+            pairs
+                .into_par_iter()
+                .map(|(page, path_a, path_b)| {
+                    let mut entry = PagePairResult {
+                        page,
+                        path_a,
+                        path_b,
+                        status: "ok",
+                        result: None,
+                        error: None,
+                        warning: None,
+                    };
+                    let Some((path_a, path_b)) = entry.path_a.clone().zip(entry.path_b.clone())
+                    else {
+                        let side = if entry.path_a.is_some() { "A" } else { "B" };
+                        let path = entry.path_a.as_deref().or(entry.path_b.as_deref());
+                        entry.status = "unpaired";
+                        entry.warning = Some(format!(
+                            "{} ページ目: フォルダ {} にしかありません ({})",
+                            page,
+                            side,
+                            path.unwrap_or_default()
+                        ));
+                        return entry;
+                    };
+                    if cancel.is_cancelled() {
+                        entry.status = "cancelled";
+                        return entry;
+                    }
+                    match check_diff_simple_on(
+                        batch_pool(),
+                        path_a,
+                        path_b,
+                        threshold,
+                        None,
+                        None,
+                        None,
+                    ) {
+                        Ok(result) => entry.result = Some(result),
+                        Err(e) => {
+                            entry.status = "error";
+                            entry.error = Some(e);
+                        }
+                    }
+                    entry
+                })
+                .collect()
+        }))
+    })
+    .await
+}
+
+// ============== SSIM類似度 ==============
+
+#[derive(Serialize)]
+struct SsimResult {
+    score: f64,           // 全ウィンドウの平均SSIM（1.0 = 完全一致）
+    ssim_map_src: String, // 縮小SSIMマップ PNG tempファイルパス（白=類似 / 黒=相違）
+    map_width: u32,
+    map_height: u32,
+    image_width: u32,
+    image_height: u32,
+}
+
+const SSIM_WINDOW: usize = 8;
+const SSIM_STEP: usize = 4; // ウィンドウのスライド幅（マップは 1/4 解像度になる）
+
+// 輝度チャンネル上で 8x8 ウィンドウをスライドさせて SSIM を計算 (rayon行並列)
+// 返り値: (平均SSIM, SSIMマップ, マップ幅, マップ高さ)
+fn ssim_core(a: &[u8], b: &[u8], width: u32, height: u32) -> (f64, Vec<f32>, u32, u32) {
+    let w = width as usize;
+    let h = height as usize;
+    if w < SSIM_WINDOW || h < SSIM_WINDOW {
+        // ウィンドウより小さい画像は全体を1ウィンドウとして扱う
+        let s = ssim_window(a, b, w, 0, 0, w, h);
+        return (s, vec![s as f32], 1, 1);
+    }
+
+    let map_w = (w - SSIM_WINDOW) / SSIM_STEP + 1;
+    let map_h = (h - SSIM_WINDOW) / SSIM_STEP + 1;
+
+    let map: Vec<f32> = (0..map_h)
+        .into_par_iter()
+        .flat_map(|my| {
+            let y = my * SSIM_STEP;
+            (0..map_w)
+                .map(move |mx| {
+                    let x = mx * SSIM_STEP;
+                    ssim_window(a, b, w, x, y, SSIM_WINDOW, SSIM_WINDOW) as f32
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
 
-    // マーカークラスタリング (gridSize=250, minCluster=20, minRadius=80)
-    let markers = cluster_markers(&high_pixels, 250, 20, 80.0);
+    let score = map.iter().map(|&s| s as f64).sum::<f64>() / map.len() as f64;
+    (score, map, map_w as u32, map_h as u32)
+}
 
-    // diffProbability計算
-    let diff_probability = if high_density_count > 0 {
-        let total_pixels = (tiff_w as f64) * (tiff_h as f64);
-        let base_prob = 70.0;
-        let additional = (high_density_count as f64 / total_pixels * 50000.0).min(30.0);
-        ((base_prob + additional) * 10.0).round() / 10.0
-    } else {
-        0.0
-    };
+// 1ウィンドウ分の SSIM（C1 = (0.01*255)^2, C2 = (0.03*255)^2）
+fn ssim_window(
+    a: &[u8],
+    b: &[u8],
+    stride: usize,
+    x0: usize,
+    y0: usize,
+    ww: usize,
+    wh: usize,
+) -> f64 {
+    const C1: f64 = 6.5025;
+    const C2: f64 = 58.5225;
+
+    let n = (ww * wh) as f64;
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0f64, 0f64, 0f64, 0f64, 0f64);
+    for y in y0..y0 + wh {
+        let row = y * stride;
+        for x in x0..x0 + ww {
+            let va = a[row + x] as f64;
+            let vb = b[row + x] as f64;
+            sum_a += va;
+            sum_b += vb;
+            sum_aa += va * va;
+            sum_bb += vb * vb;
+            sum_ab += va * vb;
+        }
+    }
 
-    // 4画像を並列エンコード → JPEG tempファイル（A/B/processedA）+ PNG tempファイル（diff）
-    let cache_a = format!("heatmap_a_{}", versioned_path_key(&psd_path));
-    let cache_b = format!("heatmap_b_{}", versioned_path_key(&tiff_path));
-    let cache_pa = format!(
-        "heatmap_pa_{}_{}",
-        versioned_path_key(&psd_path),
-        versioned_path_key(&tiff_path)
-    );
-    let cache_d = format!(
-        "heatmap_d_{}_{}",
-        versioned_path_key(&psd_path),
-        versioned_path_key(&tiff_path)
-    );
-    let ((src_a_result, src_b_result), (processed_a_result, diff_result)) = rayon::join(
-        || {
-            rayon::join(
-                || encode_to_jpeg_temp(&psd_img, &cache_a),
-                || encode_to_jpeg_temp(&tiff_img, &cache_b),
-            )
-        },
-        || {
-            rayon::join(
-                || encode_to_jpeg_temp(&processed_psd, &cache_pa),
-                || encode_rgba_to_png_temp(&heatmap_buf, tiff_w, tiff_h, &cache_d),
-            )
-        },
-    );
+    let mean_a = sum_a / n;
+    let mean_b = sum_b / n;
+    let var_a = (sum_aa / n - mean_a * mean_a).max(0.0);
+    let var_b = (sum_bb / n - mean_b * mean_b).max(0.0);
+    let cov = sum_ab / n - mean_a * mean_b;
 
-    Ok(DiffHeatmapResult {
-        src_a: src_a_result?,
-        src_b: src_b_result?,
-        processed_a: processed_a_result?,
-        diff_src: diff_result?,
-        has_diff: high_density_count > 0,
-        diff_probability,
-        high_density_count,
-        markers,
-        image_width: tiff_w,
-        image_height: tiff_h,
-    })
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
 }
 
-// Phase1用: 軽量差分チェック（画像エンコードなし）
+// SSIM類似度スコアを計算（アンチエイリアスやサブピクセルずれに強い比較）
 #[tauri::command]
-fn check_diff_simple(
-    path_a: String,
-    path_b: String,
-    threshold: u8,
-) -> Result<DiffCheckSimpleResult, String> {
+fn compute_ssim(path_a: String, path_b: String) -> Result<SsimResult, AppError> {
     // 2ファイル並列デコード
-    let (img_a, img_b) = rayon::join(|| decode_image_file(&path_a), || decode_image_file(&path_b));
+    let (img_a, img_b) = decode_pair(
+        interactive_pool(),
+        || decode_image_file(&path_a, 0),
+        || decode_image_file(&path_b, 0),
+    );
     let img_a = img_a?;
     let img_b = img_b?;
 
@@ -1682,81 +8129,402 @@ fn check_diff_simple(
         img_b
     };
 
-    let rgba_a = img_a.to_rgba8();
-    let rgba_b = img_b.to_rgba8();
+    let (luma_a, luma_b) = rayon::join(|| img_a.to_luma8(), || img_b.to_luma8());
+    let (score, map, map_w, map_h) = ssim_core(luma_a.as_raw(), luma_b.as_raw(), width, height);
 
-    // 差分計算
-    let (_diff_buf, diff_count, diff_pixels) =
-        diff_simple_core(rgba_a.as_raw(), rgba_b.as_raw(), width, height, threshold);
+    // SSIMマップをグレースケールRGBAに変換（負のSSIMは0に丸める）
+    let map_buf: Vec<u8> = map
+        .iter()
+        .flat_map(|&s| {
+            let v = (s.clamp(0.0, 1.0) * 255.0).round() as u8;
+            [v, v, v, 255]
+        })
+        .collect();
 
-    // マーカークラスタリング
-    let markers = cluster_markers(&diff_pixels, 200, 1, 300.0);
+    let cache_m = format!(
+        "ssim_m_{}_{}",
+        versioned_path_key(&path_a),
+        versioned_path_key(&path_b)
+    );
+    let ssim_map_src = encode_rgba_to_png_temp(&map_buf, map_w, map_h, &cache_m)?;
 
-    // 画像エンコードをスキップ！
-    Ok(DiffCheckSimpleResult {
-        has_diff: diff_count > 0,
-        diff_count,
-        markers,
+    Ok(SsimResult {
+        score,
+        ssim_map_src,
+        map_width: map_w,
+        map_height: map_h,
         image_width: width,
         image_height: height,
     })
 }
 
-// Phase1用: 軽量ヒートマップ差分チェック（画像エンコードなし）
+// ============== 差分レポート出力 (PDF) ==============
+
+const REPORT_PAGE_W: f32 = 210.0; // A4 縦 (mm)
+const REPORT_PAGE_H: f32 = 297.0;
+const REPORT_MARGIN: f32 = 15.0;
+const REPORT_ROWS_PER_PAGE: usize = 45;
+
+// PSDヘッダーから画像サイズ (width, height) だけを読む
+fn read_psd_dimensions(path: &str) -> Result<(u32, u32), AppError> {
+    read_psd_header(path).map(|h| (h.width, h.height))
+}
+
+// 組み込みフォント (Helvetica) はラテン文字のみのため、それ以外は ? に置き換える
+fn report_text(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+// temp JPEG をそのまま (DCT) 埋め込み、番号付きマーカー円を重ねた1ページを追加
+// markers は image_w x image_h のピクセル座標
+fn add_report_image_page(
+    doc: &printpdf::PdfDocumentReference,
+    font: &printpdf::IndirectFontRef,
+    title: &str,
+    jpeg_path: &str,
+    markers: &[DiffMarker],
+    image_w: u32,
+) -> Result<(), AppError> {
+    use printpdf::{Mm, Px};
+
+    let (w, h) = image::image_dimensions(jpeg_path)
+        .map_err(|e| AppError::from_image("Failed to read image dimensions", e))?;
+    let jpeg = fs::read(jpeg_path)
+        .map_err(|e| AppError::Io(format!("Failed to read preview image: {}", e)))?;
+
+    let (page, layer) = doc.add_page(Mm(REPORT_PAGE_W), Mm(REPORT_PAGE_H), title);
+    let layer = doc.get_page(page).get_layer(layer);
+    layer.use_text(
+        report_text(title),
+        12.0,
+        Mm(REPORT_MARGIN),
+        Mm(REPORT_PAGE_H - REPORT_MARGIN),
+        font,
+    );
+
+    // 余白内に収まるよう縮小（タイトル行の分だけ上を空ける）
+    let avail_w = REPORT_PAGE_W - REPORT_MARGIN * 2.0;
+    let avail_h = REPORT_PAGE_H - REPORT_MARGIN * 2.0 - 10.0;
+    let mm_per_px = (avail_w / w as f32).min(avail_h / h as f32);
+    let draw_h = h as f32 * mm_per_px;
+    let origin_x = REPORT_MARGIN;
+    let origin_y = REPORT_MARGIN;
+
+    printpdf::Image::from(printpdf::ImageXObject {
+        width: Px(w as usize),
+        height: Px(h as usize),
+        color_space: printpdf::ColorSpace::Rgb,
+        bits_per_component: printpdf::ColorBits::Bit8,
+        interpolate: true,
+        image_data: jpeg,
+        image_filter: Some(printpdf::ImageFilter::DCT),
+        smask: None,
+        clipping_bbox: None,
+    })
+    .add_to_layer(
+        layer.clone(),
+        printpdf::ImageTransform {
+            translate_x: Some(Mm(origin_x)),
+            translate_y: Some(Mm(origin_y)),
+            dpi: Some(25.4 / mm_per_px),
+            ..Default::default()
+        },
+    );
+
+    // マーカー座標 → ページ座標（PDFは左下原点）
+    let red = printpdf::Color::Rgb(printpdf::Rgb::new(1.0, 0.0, 0.0, None));
+    let mm_per_marker_px = w as f32 * mm_per_px / image_w.max(1) as f32;
+    layer.set_outline_color(red.clone());
+    layer.set_fill_color(red);
+    layer.set_outline_thickness(1.0);
+    for m in markers {
+        let cx = origin_x + m.x as f32 * mm_per_marker_px;
+        let cy = origin_y + draw_h - m.y as f32 * mm_per_marker_px;
+        let r = (m.radius as f32 * mm_per_marker_px).max(2.0);
+        layer.add_line(printpdf::Line {
+            points: printpdf::calculate_points_for_circle(Mm(r), Mm(cx), Mm(cy)),
+            is_closed: true,
+        });
+        layer.use_text(
+            m.id.to_string(),
+            9.0,
+            Mm(cx + r * 0.75),
+            Mm(cy + r * 0.75),
+            font,
+        );
+    }
+    Ok(())
+}
+
+// psd-tiff のヒートマップ差分を PDF レポートとして書き出す（印刷所への受け渡し用）
+// 1ページ目: 概要 / 2-3ページ目: PSD・TIFF に番号付きマーカー / 以降: マーカー一覧
+// crop_bounds 未指定なら PSD 全体、threshold 未指定なら画面と同じ 70
 #[tauri::command]
-fn check_diff_heatmap(
+fn generate_diff_report(
     psd_path: String,
     tiff_path: String,
-    crop_bounds: CropBounds,
-    threshold: u8,
-) -> Result<DiffCheckHeatmapResult, String> {
-    // 並列デコード
-    let (psd_result, tiff_result) = rayon::join(
-        || decode_psd_to_image(&psd_path),
-        || image::open(&tiff_path).map_err(|e| format!("Failed to open TIFF: {}", e)),
+    output_path: String,
+    crop_bounds: Option<CropBounds>,
+    threshold: Option<u8>,
+) -> Result<String, AppError> {
+    use printpdf::Mm;
+
+    let crop_bounds = match crop_bounds {
+        Some(c) => c,
+        None => {
+            let (width, height) = read_psd_dimensions(&psd_path)?;
+            CropBounds {
+                left: 0,
+                top: 0,
+                right: width,
+                bottom: height,
+            }
+        }
+    };
+    let threshold = threshold.unwrap_or(70);
+    let diff = compute_diff_heatmap(
+        psd_path.clone(),
+        tiff_path.clone(),
+        crop_bounds,
+        threshold.into(),
+        None,
+        None,
+        None,
+    )?;
+
+    let (doc, page, layer) = printpdf::PdfDocument::new(
+        "KENBAN Diff Report",
+        Mm(REPORT_PAGE_W),
+        Mm(REPORT_PAGE_H),
+        "Summary",
     );
-    let psd_img = psd_result?;
-    let tiff_img = tiff_result?;
-
-    let (tiff_w, tiff_h) = tiff_img.dimensions();
-
-    // PSDをクロップ
-    let crop_w = crop_bounds.right - crop_bounds.left;
-    let crop_h = crop_bounds.bottom - crop_bounds.top;
-    let cropped = psd_img.crop_imm(crop_bounds.left, crop_bounds.top, crop_w, crop_h);
+    let font = doc
+        .add_builtin_font(printpdf::BuiltinFont::Helvetica)
+        .map_err(|e| AppError::Encode(format!("Failed to load PDF font: {}", e)))?;
 
-    // TIFFサイズにリサイズ（CatmullRom = Photoshop ResampleMethod.AUTOMATIC 相当）
-    let processed_psd = cropped.resize_exact(tiff_w, tiff_h, FilterType::CatmullRom);
+    // 概要ページ
+    {
+        let layer = doc.get_page(page).get_layer(layer);
+        let summary = [
+            "KENBAN Diff Report".to_string(),
+            String::new(),
+            format!("PSD:  {}", psd_path),
+            format!("TIFF: {}", tiff_path),
+            format!(
+                "Crop origin: ({}, {})  scale: {:.4} x {:.4}",
+                diff.crop_left, diff.crop_top, diff.scale_x, diff.scale_y
+            ),
+            format!(
+                "Image size (TIFF px): {} x {}",
+                diff.image_width, diff.image_height
+            ),
+            format!("Threshold: {}", threshold),
+            format!("Diff probability: {}%", diff.diff_probability),
+            format!("High density pixels: {}", diff.high_density_count),
+            format!("Markers: {}", diff.markers.len()),
+        ];
+        for (i, line) in summary.iter().enumerate() {
+            let size = if i == 0 { 16.0 } else { 10.0 };
+            layer.use_text(
+                report_text(line),
+                size,
+                Mm(REPORT_MARGIN),
+                Mm(REPORT_PAGE_H - REPORT_MARGIN - i as f32 * 7.0),
+                &font,
+            );
+        }
+    }
 
-    let rgba_a = processed_psd.to_rgba8();
-    let rgba_b = tiff_img.to_rgba8();
+    // 画像ページ（マーカーはTIFF座標なので、TIFFサイズに揃えた processed_a に重ねる）
+    add_report_image_page(
+        &doc,
+        &font,
+        "PSD (cropped / resized)",
+        &diff.processed_a,
+        &diff.markers,
+        diff.image_width,
+    )?;
+    add_report_image_page(
+        &doc,
+        &font,
+        "TIFF",
+        &diff.src_b,
+        &diff.markers,
+        diff.image_width,
+    )?;
+
+    // マーカー一覧ページ
+    let rows: Vec<String> = diff
+        .markers
+        .iter()
+        .zip(diff.psd_markers.iter())
+        .map(|(m, p)| {
+            format!(
+                "{:>4}   TIFF ({:.0}, {:.0})   PSD ({:.0}, {:.0})   radius {:.0}   count {}",
+                m.id, m.x, m.y, p.x, p.y, m.radius, m.count
+            )
+        })
+        .collect();
+    for (page_no, chunk) in rows.chunks(REPORT_ROWS_PER_PAGE).enumerate() {
+        let (page, layer) = doc.add_page(Mm(REPORT_PAGE_W), Mm(REPORT_PAGE_H), "Markers");
+        let layer = doc.get_page(page).get_layer(layer);
+        layer.use_text(
+            format!(
+                "Markers ({}/{})",
+                page_no + 1,
+                rows.len().div_ceil(REPORT_ROWS_PER_PAGE)
+            ),
+            12.0,
+            Mm(REPORT_MARGIN),
+            Mm(REPORT_PAGE_H - REPORT_MARGIN),
+            &font,
+        );
+        for (i, row) in chunk.iter().enumerate() {
+            layer.use_text(
+                row.as_str(),
+                9.0,
+                Mm(REPORT_MARGIN),
+                Mm(REPORT_PAGE_H - REPORT_MARGIN - 10.0 - i as f32 * 5.5),
+                &font,
+            );
+        }
+    }
 
-    // ヒートマップ差分計算
-    let (_heatmap_buf, high_density_count, high_pixels) =
-        diff_heatmap_core(rgba_a.as_raw(), rgba_b.as_raw(), tiff_w, tiff_h, threshold);
+    let bytes = doc
+        .save_to_bytes()
+        .map_err(|e| AppError::Encode(format!("Failed to build PDF: {}", e)))?;
+    write_file_atomic(Path::new(&output_path), &bytes).map_err(AppError::Io)?;
+    Ok(output_path)
+}
 
-    // マーカークラスタリング
-    let markers = cluster_markers(&high_pixels, 250, 20, 80.0);
+// マーカー記録（レビュー回ごとの比較用にファイルへ保存する）
+#[derive(Serialize, Deserialize)]
+struct MarkerExport {
+    source_a: String,
+    source_b: String,
+    image_width: u32,
+    image_height: u32,
+    markers: Vec<DiffMarker>,
+}
 
-    // diffProbability計算
-    let diff_probability = if high_density_count > 0 {
-        let total_pixels = (tiff_w as f64) * (tiff_h as f64);
-        let base_prob = 70.0;
-        let additional = (high_density_count as f64 / total_pixels * 50000.0).min(30.0);
-        ((base_prob + additional) * 10.0).round() / 10.0
+// CSVフィールドのエスケープ（カンマ・引用符・改行を含む場合は "" で囲む）
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
     } else {
-        0.0
+        s.to_string()
+    }
+}
+
+// マーカーを JSON / CSV で書き出す（format: "json" | "csv"）
+// CSV は1行1マーカー。画像サイズとソースパスは各行の末尾列に入れる
+#[tauri::command]
+fn export_markers(
+    export: MarkerExport,
+    format: String,
+    output_path: String,
+) -> Result<String, AppError> {
+    let content = match format.to_lowercase().as_str() {
+        "json" => serde_json::to_string_pretty(&export)
+            .map_err(|e| AppError::Encode(format!("Failed to serialize markers: {}", e)))?,
+        "csv" => {
+            let mut out = String::from(
+                "x,y,radius,count,image_width,image_height,source_a,source_b,\
+                 bbox_left,bbox_top,bbox_right,bbox_bottom,id,kind\n",
+            );
+            let source_a = csv_field(&export.source_a);
+            let source_b = csv_field(&export.source_b);
+            for m in &export.markers {
+                out.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                    m.x,
+                    m.y,
+                    m.radius,
+                    m.count,
+                    export.image_width,
+                    export.image_height,
+                    source_a,
+                    source_b,
+                    m.bbox.left,
+                    m.bbox.top,
+                    m.bbox.right,
+                    m.bbox.bottom,
+                    m.id,
+                    m.kind.as_str()
+                ));
+            }
+            out
+        }
+        other => {
+            return Err(AppError::Unsupported(format!(
+                "未対応の出力形式です: {} (json / csv)",
+                other
+            )))
+        }
     };
 
-    // 画像エンコードをスキップ！
-    Ok(DiffCheckHeatmapResult {
-        has_diff: high_density_count > 0,
-        diff_probability,
-        high_density_count,
-        markers,
-        image_width: tiff_w,
-        image_height: tiff_h,
-    })
+    write_file_atomic(Path::new(&output_path), content.as_bytes()).map_err(AppError::Io)?;
+    Ok(output_path)
+}
+
+// 読み順に並べたマーカー（seq は 1 始まりの読み順。id は件数順の番号のまま）
+#[derive(Serialize)]
+struct OrderedMarker {
+    seq: u32,
+    #[serde(flatten)]
+    marker: DiffMarker,
+}
+
+// マーカーを読み順（上の段から、段の中は左→右、rtl なら右→左）に並べた添字
+// 中心の y が段の先頭マーカーの半径以内なら同じ段とみなす（同じ入力なら毎回同じ順）
+fn reading_order(markers: &[DiffMarker], rtl: bool) -> Vec<usize> {
+    let mut by_y: Vec<usize> = (0..markers.len()).collect();
+    by_y.sort_by(|&a, &b| {
+        markers[a]
+            .y
+            .total_cmp(&markers[b].y)
+            .then(markers[a].id.cmp(&markers[b].id))
+    });
+    let mut start = 0;
+    while start < by_y.len() {
+        let anchor = &markers[by_y[start]];
+        let end = by_y[start..]
+            .iter()
+            .position(|&i| markers[i].y - anchor.y > anchor.radius)
+            .map_or(by_y.len(), |n| start + n);
+        by_y[start..end].sort_by(|&a, &b| {
+            let by_x = if rtl {
+                markers[b].x.total_cmp(&markers[a].x)
+            } else {
+                markers[a].x.total_cmp(&markers[b].x)
+            };
+            by_x.then(markers[a].id.cmp(&markers[b].id))
+        });
+        start = end;
+    }
+    by_y
+}
+
+// 「次の指摘へ」移動用に、差分結果のマーカーを読み順に並べ直す
+// rtl: 右綴じ（右→左に読む）原稿なら true
+#[tauri::command]
+fn markers_in_reading_order(markers: Vec<DiffMarker>, rtl: Option<bool>) -> Vec<OrderedMarker> {
+    reading_order(&markers, rtl.unwrap_or(false))
+        .into_iter()
+        .enumerate()
+        .map(|(seq, i)| OrderedMarker {
+            seq: seq as u32 + 1,
+            marker: markers[i].clone(),
+        })
+        .collect()
 }
 
 // ============== PDF差分計算 (PDFium) ==============
@@ -1832,11 +8600,15 @@ fn compute_pdf_diff(
     page: u32,
     dpi: f32,
     threshold: u8,
-) -> Result<DiffSimpleResult, String> {
+) -> Result<DiffSimpleResult, AppError> {
     let pdfium = get_pdfium()?;
 
-    let (samples_a, wa, ha) = render_pdf_page_pdfium(&pdfium, &path_a, page, dpi)?;
-    let (samples_b, wb, hb) = render_pdf_page_pdfium(&pdfium, &path_b, page, dpi)?;
+    let (samples_a, wa, ha) =
+        render_pdf_page_pdfium(&pdfium, &path_a, page, dpi).map_err(AppError::Decode)?;
+    let (samples_b, wb, hb) =
+        render_pdf_page_pdfium(&pdfium, &path_b, page, dpi).map_err(AppError::Decode)?;
+    ensure_non_empty_size(wa, ha, &path_a)?;
+    ensure_non_empty_size(wb, hb, &path_b)?;
 
     let width = wa.max(wb);
     let height = ha.max(hb);
@@ -1867,11 +8639,24 @@ fn compute_pdf_diff(
     };
 
     // rayon並列差分計算
-    let (diff_buf, diff_count, diff_pixels) =
-        diff_simple_core(rgba_a.as_raw(), rgba_b.as_raw(), width, height, threshold);
+    let (diff_buf, diff_count, diff_pixels, worst) = diff_simple_core(
+        rgba_a.as_raw(),
+        rgba_b.as_raw(),
+        width,
+        height,
+        threshold,
+        &SimpleDiffOptions::default(),
+        &[],
+        true,
+    );
 
     // マーカークラスタリング
-    let markers = cluster_markers(&diff_pixels, 200, 1, 300.0);
+    let markers = classify_markers(
+        cluster_markers(&diff_pixels, 200, 1, 300.0),
+        &diff_pixels,
+        &rgba_a,
+        &rgba_b,
+    );
 
     // 3画像を並列エンコード → JPEG tempファイル（A/B）+ PNG tempファイル（diff）
     let img_a = DynamicImage::ImageRgba8(rgba_a);
@@ -1900,9 +8685,16 @@ fn compute_pdf_diff(
         diff_src: diff_result?,
         has_diff: diff_count > 0,
         diff_count,
+        diff_ratio: diff_ratio(diff_count, width, height, &[]),
         markers,
         image_width: width,
         image_height: height,
+        composite_src: None,
+        max_delta: worst.map_or(0, |w| w.delta),
+        worst,
+        roi: None,
+        display_scale: 1.0,
+        timings: None,
     })
 }
 
@@ -1920,9 +8712,10 @@ fn render_pdf_page(
     page: u32,
     dpi: f32,
     split_side: Option<String>,
-) -> Result<PdfPageImage, String> {
+) -> Result<PdfPageImage, AppError> {
     let pdfium = get_pdfium()?;
-    let (samples, width, height) = render_pdf_page_pdfium(&pdfium, &path, page, dpi)?;
+    let (samples, width, height) =
+        render_pdf_page_pdfium(&pdfium, &path, page, dpi).map_err(AppError::Decode)?;
 
     // 見開き分割: 左右半分を切り出し
     if let Some(ref side) = split_side {
@@ -1956,11 +8749,11 @@ fn render_pdf_page(
 
 // PDFの総ページ数を取得
 #[tauri::command]
-fn get_pdf_page_count(path: String) -> Result<u32, String> {
+fn get_pdf_page_count(path: String) -> Result<u32, AppError> {
     let pdfium = get_pdfium()?;
     let doc = pdfium
         .load_pdf_from_file(&path, None)
-        .map_err(|e| format!("Failed to open PDF '{}': {}", path, e))?;
+        .map_err(|e| AppError::Decode(format!("Failed to open PDF '{}': {}", path, e)))?;
     Ok(doc.pages().len() as u32)
 }
 
@@ -2142,18 +8935,18 @@ fn write_json_access_log(action: &str, file_path: &str, data: Option<&serde_json
 }
 
 #[tauri::command]
-fn read_text_file(path: String) -> Result<String, String> {
-    let content =
-        std::fs::read_to_string(&path).map_err(|e| format!("ファイル読み込みエラー: {}", e))?;
+fn read_text_file(path: String) -> Result<String, AppError> {
+    let content = std::fs::read_to_string(&path)
+        .map_err(|e| AppError::Io(format!("ファイル読み込みエラー: {}", e)))?;
     let parsed = serde_json::from_str::<serde_json::Value>(&content).ok();
     write_json_access_log("read", &path, parsed.as_ref());
     Ok(content)
 }
 
 #[tauri::command]
-fn write_text_file(path: String, content: String) -> Result<(), String> {
+fn write_text_file(path: String, content: String) -> Result<(), AppError> {
     std::fs::write(&path, content.as_bytes())
-        .map_err(|e| format!("ファイル書き込みエラー: {}", e))?;
+        .map_err(|e| AppError::Io(format!("ファイル書き込みエラー: {}", e)))?;
     let parsed = serde_json::from_str::<serde_json::Value>(&content).ok();
     write_json_access_log("write", &path, parsed.as_ref());
     Ok(())
@@ -2162,6 +8955,12 @@ fn write_text_file(path: String, content: String) -> Result<(), String> {
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let args: Vec<String> = std::env::args().collect();
+    let settings = load_settings();
+    let cache_max_entries = settings
+        .cache_max_entries
+        .unwrap_or(DEFAULT_CACHE_MAX_ENTRIES)
+        .max(1);
+    EMBED_SRGB.store(settings.embed_srgb.unwrap_or(true), Ordering::Relaxed);
     tauri::Builder::default()
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_opener::init())
@@ -2169,25 +8968,76 @@ pub fn run() {
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_process::init())
         .manage(AppState {
-            image_cache: Mutex::new(ImageCache::new(100)), // 最大100件キャッシュ
+            image_cache: Arc::new(RwLock::new(ImageCache::new(
+                cache_max_entries,
+                DEFAULT_CACHE_MAX_BYTES,
+            ))), // 既定100件 / 512MB
+            disk_cache_enabled: AtomicBool::new(true),
+            preload_cancel: Arc::default(),
+            preload_in_flight: Arc::default(),
+            image_chunks: ChunkStore::default(),
+            settings: Mutex::new(settings),
             cli_args: args,
         })
         .invoke_handler(tauri::generate_handler![
             greet,
             parse_psd,
+            parse_psd_bytes,
+            parse_psd_with_transparency,
+            list_psd_layers,
+            count_image_frames,
+            get_image_info,
+            psd_metadata,
+            is_blank,
+            estimate_diff_cost,
+            compute_diff_mask,
+            compute_onion_skin,
+            parse_psd_layer,
             open_file_with_default_app,
             open_file_in_photoshop,
             save_screenshot,
+            save_diff_image,
+            transform_image,
+            export_region,
+            copy_screenshot_to_clipboard,
             open_folder,
             decode_and_resize_image,
+            decode_and_resize_image_bytes,
+            open_image_chunks,
+            get_image_chunk,
+            release_image_chunk,
             preload_images,
+            cancel_preload,
             clear_image_cache,
+            save_session_state,
+            load_session_state,
+            set_cache_policy,
+            set_psd_decode_timeout,
+            set_cache_max_entries,
+            set_embed_srgb,
+            set_decode_threads,
+            cache_stats,
             list_files_in_folder,
+            validate_job_folder,
+            pair_files_for_spread,
+            list_files_with_metadata,
             open_pdf_in_mojiq,
+            set_mojiq_path,
+            open_pdf_in_viewer,
             compute_diff_simple,
+            compute_diff_simple_async,
             compute_diff_heatmap,
+            compute_diff_heatmap_async,
+            compute_diff,
+            warmup,
             check_diff_simple,
             check_diff_heatmap,
+            batch_check_diff_heatmap,
+            diff_folders,
+            generate_diff_report,
+            export_markers,
+            markers_in_reading_order,
+            compute_ssim,
             compute_pdf_diff,
             render_pdf_page,
             get_pdf_page_count,
@@ -2207,3 +9057,549 @@ pub fn run() {
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 最小構成の PSD（Color Mode Data・Image Resources・Layer and Mask は空）
+    // image_data は compression (u16) から始まる Image Data Section
+    fn psd_file(channels: u16, width: u32, height: u32, depth: u16, color_mode: u16) -> Vec<u8> {
+        let mut b = b"8BPS".to_vec();
+        b.extend(1u16.to_be_bytes());
+        b.extend([0u8; 6]);
+        b.extend(channels.to_be_bytes());
+        b.extend(height.to_be_bytes());
+        b.extend(width.to_be_bytes());
+        b.extend(depth.to_be_bytes());
+        b.extend(color_mode.to_be_bytes());
+        b.extend([0u8; 12]);
+        b
+    }
+
+    // 1bit 10x2: 各行 2 バイト（右端 6 ビットはパディング。すべて 1 を立てて無視されることを確かめる）
+    const BITMAP_ROWS: [[u8; 2]; 2] = [[0b1000_0000, 0b0111_1111], [0b0101_0101, 0b1011_1111]];
+    const BITMAP_EXPECTED: [[u8; 10]; 2] = [
+        [0, 255, 255, 255, 255, 255, 255, 255, 255, 0],
+        [255, 0, 255, 0, 255, 0, 255, 0, 0, 255],
+    ];
+
+    fn assert_bitmap_pixels(img: &DynamicImage) {
+        assert_eq!(img.dimensions(), (10, 2));
+        for (y, row) in BITMAP_EXPECTED.iter().enumerate() {
+            for (x, &v) in row.iter().enumerate() {
+                assert_eq!(
+                    img.get_pixel(x as u32, y as u32).0,
+                    [v, v, v, 255],
+                    "pixel ({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bitmap_psd_raw() {
+        let mut b = psd_file(1, 10, 2, 1, 0);
+        b.extend(0u16.to_be_bytes());
+        b.extend(BITMAP_ROWS.concat());
+        assert_bitmap_pixels(&decode_psd_fallback(&b).unwrap());
+    }
+
+    #[test]
+    fn grayscale_alpha_psd_keeps_alpha() {
+        // グレー 2x1 + 2チャンネル目（緑ではなく透明度か追加チャンネルとして読む）
+        let gray_psd = |layer_count: i16| {
+            let mut b = psd_file(2, 2, 1, 8, 1);
+            b.truncate(b.len() - 4);
+            b.extend(6u32.to_be_bytes());
+            b.extend(2u32.to_be_bytes());
+            b.extend(layer_count.to_be_bytes());
+            b.extend(0u16.to_be_bytes());
+            b.extend([10, 200, 0, 128]);
+            decode_psd_fallback(&b).unwrap().to_rgba8().into_raw()
+        };
+        // レイヤー数が負 → 2チャンネル目は合成画像の透明度
+        assert_eq!(gray_psd(-1), [10, 10, 10, 0, 200, 200, 200, 128]);
+        // そうでなければ選択範囲・スポットカラーなので不透明のまま
+        assert_eq!(gray_psd(0), [10, 10, 10, 255, 200, 200, 200, 255]);
+        assert_eq!(gray_psd(1), [10, 10, 10, 255, 200, 200, 200, 255]);
+    }
+
+    #[test]
+    fn float32_psd_raw_and_zip() {
+        // RGB 32bit 2x1（リニア 0.5 は sRGB ガンマで 188 になる。範囲外はクランプ）
+        let planes: Vec<u8> = [[1.0f32, -1.0], [0.0, 0.5], [0.0, 4.0]]
+            .iter()
+            .flatten()
+            .flat_map(|v| v.to_be_bytes())
+            .collect();
+        let expected = [255, 0, 0, 255, 0, 188, 255, 255];
+
+        let mut raw = psd_file(3, 2, 1, 32, 3);
+        raw.extend(0u16.to_be_bytes());
+        raw.extend(&planes);
+        assert_eq!(
+            decode_psd_fallback(&raw).unwrap().to_rgba8().into_raw(),
+            expected
+        );
+
+        let mut zip = psd_file(3, 2, 1, 32, 3);
+        zip.extend(2u16.to_be_bytes());
+        let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(&planes).unwrap();
+        zip.extend(enc.finish().unwrap());
+        assert_eq!(
+            decode_psd_fallback(&zip).unwrap().to_rgba8().into_raw(),
+            expected
+        );
+
+        // 画素データが足りなければ panic せずにエラー
+        raw.truncate(raw.len() - 4);
+        assert!(decode_psd_fallback(&raw).is_err());
+    }
+
+    #[test]
+    fn indexed_psd_uses_planar_palette() {
+        // Color Mode Data に 256 色パレット（R×256 → G×256 → B×256 の順）
+        let mut b = psd_file(1, 3, 1, 8, 2);
+        b.truncate(b.len() - 12);
+        b.extend(768u32.to_be_bytes());
+        b.extend((0..=255u8).collect::<Vec<_>>());
+        b.extend((0..=255u8).map(|i| 255 - i).collect::<Vec<_>>());
+        b.extend((0..=255u8).map(|i| i / 2).collect::<Vec<_>>());
+        b.extend([0u8; 8]);
+        b.extend(0u16.to_be_bytes());
+        b.extend([0, 7, 255]);
+        let img = decode_psd_fallback(&b).unwrap();
+        assert_eq!(
+            img.to_rgba8().into_raw(),
+            [0, 255, 0, 255, 7, 248, 3, 255, 255, 0, 127, 255]
+        );
+    }
+
+    #[test]
+    fn rgba_psd_keeps_transparency() {
+        // RGB 2x1 + 4チャンネル目
+        let rgba_psd = |layer_count: i16| {
+            let mut b = psd_file(4, 2, 1, 8, 3);
+            b.truncate(b.len() - 4);
+            // Layer and Mask: Layer Info（長さ 2）のレイヤー数だけ書く
+            b.extend(6u32.to_be_bytes());
+            b.extend(2u32.to_be_bytes());
+            b.extend(layer_count.to_be_bytes());
+            b.extend(0u16.to_be_bytes());
+            b.extend([255, 0, 0, 255, 0, 0, 128, 0]);
+            decode_psd_fallback(&b).unwrap().to_rgba8().into_raw()
+        };
+        // レイヤー数が負 → 4チャンネル目は合成画像の透明度
+        assert_eq!(rgba_psd(-1), [255, 0, 0, 128, 0, 255, 0, 0]);
+        // そうでなければ選択範囲・スポットカラーなので不透明のまま
+        assert_eq!(rgba_psd(1), [255, 0, 0, 255, 0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn bitmap_psd_rle() {
+        let mut b = psd_file(1, 10, 2, 1, 0);
+        b.extend(1u16.to_be_bytes());
+        // 各行 3 バイト（PackBits のリテラル: 長さ-1 + 2 バイト）
+        b.extend(3u16.to_be_bytes());
+        b.extend(3u16.to_be_bytes());
+        for row in BITMAP_ROWS {
+            b.push(1);
+            b.extend(row);
+        }
+        assert_bitmap_pixels(&decode_psd_fallback(&b).unwrap());
+    }
+
+    #[test]
+    fn psd_crate_panic_is_contained() {
+        // Layer and Mask セクションの長さがファイル末尾を越えている → psd crate はスライス範囲外で panic する
+        let mut b = psd_file(3, 1, 1, 8, 3);
+        b.truncate(b.len() - 4);
+        b.extend(1000u32.to_be_bytes());
+        b.extend([0u8; 8]);
+        let path =
+            std::env::temp_dir().join(format!("kenban_test_{}_bad_layers.psd", std::process::id()));
+        fs::write(&path, &b).unwrap();
+        let result = with_psd_layers(path.to_str().unwrap(), |psd| Ok(psd.layers().len()));
+        let _ = fs::remove_file(&path);
+        assert!(matches!(result, Err(AppError::Decode(_))));
+    }
+
+    #[test]
+    fn heatmap_params_validation() {
+        let params = |f: fn(&mut HeatmapParams)| {
+            let mut p = HeatmapParams::default();
+            f(&mut p);
+            p.validated()
+        };
+        assert!(params(|_| {}).is_ok());
+        assert!(params(|p| p.grid_size = 0).is_err());
+        assert!(params(|p| p.density_threshold = f32::NAN).is_err());
+        assert!(params(|p| p.radius_mm = Some(1.0)).is_err());
+        let p = params(|p| {
+            p.dpi = Some(600.0);
+            p.radius_mm = Some(1.0);
+            p.min_radius_mm = Some(2.54);
+        })
+        .unwrap();
+        assert_eq!(p.radius, 24);
+        assert!((p.min_radius - 60.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cancel_token_only_affects_running_calls() {
+        let generation = Arc::new(AtomicU64::new(0));
+        let running = CancelToken::new(&generation);
+        assert!(!running.is_cancelled());
+        generation.fetch_add(1, Ordering::Relaxed); // cancel_preload
+        let next = CancelToken::new(&generation);
+        // 後から始まった呼び出しが、実行中の呼び出しへのキャンセルを打ち消さない
+        assert!(running.is_cancelled());
+        assert!(!next.is_cancelled());
+    }
+
+    #[test]
+    fn unpack_bitmap_rows_ignores_padding() {
+        let packed = BITMAP_ROWS.concat();
+        assert_eq!(
+            unpack_bitmap_rows(&packed, 10, 2, 2),
+            BITMAP_EXPECTED.concat()
+        );
+    }
+
+    #[test]
+    fn image_cache_keeps_bitmap_only_on_largest() {
+        let entry = |w: u32, h: u32| CachedImage {
+            file_path: String::new(),
+            width: w,
+            height: h,
+            original_width: 100_000,
+            original_height: 100_000,
+            byte_size: 10,
+            bitmap: Some(Arc::new(DynamicImage::new_rgb8(w.min(8), h.min(8)))),
+        };
+        let mut cache = ImageCache::new(10, u64::MAX);
+        cache.insert("f:100x100".to_string(), entry(100, 100));
+        cache.insert("f:50x50".to_string(), entry(50, 50));
+        assert!(cache.get("f:50x50").unwrap().bitmap.is_none());
+        assert_eq!(cache.largest_for("f").unwrap().width, 100);
+        // 幅 × 高さ が u32 を超えても大小を取り違えない
+        cache.insert("f:70000x70000".to_string(), entry(70_000, 70_000));
+        assert_eq!(cache.largest_for("f").unwrap().width, 70_000);
+        assert!(cache.get("f:100x100").unwrap().bitmap.is_none());
+        assert_eq!(cache.total_bytes, 30 + 8 * 8 * 3);
+        cache.remove_entry("f:70000x70000");
+        assert_eq!(cache.total_bytes, 20);
+    }
+
+    // 8bit グレー・非圧縮・1ストリップの最小 TIFF（リトルエンディアン）
+    fn gray_tiff(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+        let entries: [(u16, u32); 7] = [
+            (TIFF_TAG_WIDTH, width),
+            (TIFF_TAG_HEIGHT, height),
+            (TIFF_TAG_BITS_PER_SAMPLE, 8),
+            (TIFF_TAG_PHOTOMETRIC, 1),
+            (TIFF_TAG_STRIP_OFFSETS, 0), // 下で画素データの位置に書き換える
+            (TIFF_TAG_SAMPLES_PER_PIXEL, 1),
+            (TIFF_TAG_STRIP_BYTE_COUNTS, pixels.len() as u32),
+        ];
+        let data_at = 8 + 2 + entries.len() as u32 * 12 + 4;
+        let mut b = b"II*\0".to_vec();
+        b.extend(8u32.to_le_bytes());
+        b.extend((entries.len() as u16).to_le_bytes());
+        for (tag, value) in entries {
+            let value = if tag == TIFF_TAG_STRIP_OFFSETS {
+                data_at
+            } else {
+                value
+            };
+            b.extend(tag.to_le_bytes());
+            b.extend(4u16.to_le_bytes()); // LONG
+            b.extend(1u32.to_le_bytes());
+            b.extend(value.to_le_bytes());
+        }
+        b.extend(0u32.to_le_bytes());
+        b.extend(pixels);
+        b
+    }
+
+    #[test]
+    fn tiff_fallback_rejects_oversized_header() {
+        let img = decode_tiff_fallback(&gray_tiff(3, 2, &[0, 1, 2, 3, 4, 5])).unwrap();
+        assert_eq!(img.to_luma8().into_raw(), [0, 1, 2, 3, 4, 5]);
+        // 幅・高さだけが巨大（画素データは数バイト）→ 確保せずにエラー
+        let result = decode_tiff_fallback(&gray_tiff(100_000, 100_000, &[0; 6]));
+        assert!(matches!(result, Err(AppError::Unsupported(_))));
+    }
+
+    #[test]
+    fn isolated_pixel_dropped_only_with_ignore_antialias() {
+        let a = [0u8, 0, 0, 255].repeat(25);
+        let mut b = a.clone();
+        b[12 * 4] = 255; // 5x5 の中央だけ差分
+        let mut options = SimpleDiffOptions::default();
+        let (_, count, pixels, worst) = diff_simple_core(&a, &b, 5, 5, 0, &options, &[], true);
+        assert_eq!(worst.map(|w| (w.x, w.y, w.delta)), Some((2, 2, 255)));
+        assert_eq!(count, 1);
+        assert_eq!((pixels[0].x, pixels[0].y), (2, 2));
+        options.ignore_antialias = true;
+        let (buf, count, _, worst) = diff_simple_core(&a, &b, 5, 5, 0, &options, &[], true);
+        assert!(worst.is_none());
+        assert_eq!(count, 0);
+        assert_eq!(buf[12 * 4..12 * 4 + 4], [0, 0, 0, 255]);
+        // 件数だけ求める場合はバッファを作らない
+        options.ignore_antialias = false;
+        let (buf, count, _, _) = diff_simple_core(&a, &b, 5, 5, 0, &options, &[], false);
+        assert!(buf.is_empty());
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn classify_markers_uses_cell_index() {
+        let marker = |left: f64, right: f64| DiffMarker {
+            x: (left + right) / 2.0,
+            y: 5.0,
+            radius: 80.0,
+            count: 1,
+            bbox: MarkerBounds {
+                left,
+                top: 0.0,
+                right,
+                bottom: 10.0,
+            },
+            id: 0,
+            kind: MarkerKind::Changed,
+        };
+        // 1つ目はセル境界 (x = 256) をまたぐ。2つ目は離れたセル
+        let markers = vec![marker(250.0, 300.0), marker(600.0, 610.0)];
+        let mut img_a = RgbaImage::from_pixel(700, 10, Rgba([255, 255, 255, 255]));
+        let mut img_b = img_a.clone();
+        let mut pixels = Vec::new();
+        for x in 250..300 {
+            img_b.put_pixel(x, 5, Rgba([0, 0, 0, 255])); // B にだけ描かれた → 追加
+            pixels.push(DiffPixel { x, y: 5 });
+        }
+        for x in 600..610 {
+            img_a.put_pixel(x, 5, Rgba([0, 0, 0, 255])); // A にだけあった → 欠落
+            pixels.push(DiffPixel { x, y: 5 });
+        }
+        let kinds: Vec<_> = classify_markers(markers, &pixels, &img_a, &img_b)
+            .iter()
+            .map(|m| m.kind.as_str())
+            .collect();
+        assert_eq!(kinds, ["added", "removed"]);
+    }
+
+    #[test]
+    fn worst_delta_uses_compared_scale() {
+        let a = [0u8, 0, 0, 255, 0, 0, 0, 0];
+        let b = [255u8, 255, 255, 255, 40, 0, 0, 0];
+        let worst = |options: &SimpleDiffOptions| {
+            let (_, _, _, worst) = diff_simple_core(&a, &b, 2, 1, 0, options, &[], true);
+            worst.map(|w| (w.x, w.delta))
+        };
+        let mut options = SimpleDiffOptions::default();
+        assert_eq!(worst(&options), Some((0, 255)));
+        // 白地に合成すると透明な2画素目は差がなくなる
+        options.background = Some([255, 255, 255]);
+        assert_eq!(worst(&options), Some((0, 255)));
+        let (_, count, _, _) = diff_simple_core(&a, &b, 2, 1, 0, &options, &[], true);
+        assert_eq!(count, 1);
+        // deltae では黒と白の ΔE（= 100）
+        options.mode = DiffColorMode::DeltaE;
+        assert_eq!(worst(&options), Some((0, 100)));
+    }
+
+    #[test]
+    fn natural_sort_groups_by_folder() {
+        let mut files: Vec<String> = ["b/p2.tif", "a/p10.tif", "b/p1.tif", "a/p9.tif"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        sort_paths_natural(&mut files);
+        assert_eq!(files, ["a/p9.tif", "a/p10.tif", "b/p1.tif", "b/p2.tif"]);
+    }
+
+    #[test]
+    fn natural_sort_handles_full_width_digits() {
+        assert_eq!(normalize_for_natural_sort("㌻１０"), "ページ10");
+        let mut files: Vec<String> = [
+            "ページ１０.tif",
+            "page_１０.tif",
+            "㌻3.tif",
+            "page_2.tif",
+            "ページ9.tif",
+            "page_１.tif",
+        ]
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+        sort_paths_natural(&mut files);
+        assert_eq!(
+            files,
+            [
+                "page_１.tif",
+                "page_2.tif",
+                "page_１０.tif",
+                "㌻3.tif",
+                "ページ9.tif",
+                "ページ１０.tif",
+            ]
+        );
+    }
+
+    #[test]
+    fn cached_preview_keeps_original_size() {
+        let path =
+            std::env::temp_dir().join(format!("kenban_test_{}_preview.png", std::process::id()));
+        DynamicImage::new_rgb8(200, 100).save(&path).unwrap();
+        let path = path.to_str().unwrap();
+        let cache = RwLock::new(ImageCache::new(10, u64::MAX));
+        let preview = |cache: &RwLock<ImageCache>, max: u32| {
+            let r = resize_preview(cache, false, path, max, max, ResizeFilter::Triangle).unwrap();
+            (r.decoder_used, r.width, r.original_width, r.original_height)
+        };
+        let fresh = preview(&cache, 50);
+        assert_ne!(fresh.0, "cache");
+        assert_eq!((fresh.1, fresh.2, fresh.3), (50, 200, 100));
+        // メモリキャッシュ → 大きいプレビューから縮小 → temp ファイルのどれでも元サイズは同じ
+        assert_eq!(preview(&cache, 50), ("cache", 50, 200, 100));
+        assert_eq!(preview(&cache, 20), ("cache", 20, 200, 100));
+        let empty = RwLock::new(ImageCache::new(10, u64::MAX));
+        assert_eq!(preview(&empty, 50), ("cache", 50, 200, 100));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn exif_rotated_jpeg_is_upright() {
+        // 16x8（左半分が黒・右半分が白）に EXIF Orientation = 6（時計回りに 90° 回転して表示）を付ける
+        let img = RgbImage::from_fn(16, 8, |x, _| {
+            if x < 8 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        });
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+        let mut tiff = b"MM\0*".to_vec();
+        tiff.extend(8u32.to_be_bytes());
+        tiff.extend(1u16.to_be_bytes());
+        tiff.extend([0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]); // Orientation (SHORT) = 6
+        tiff.extend(0u32.to_be_bytes());
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend((2 + 6 + tiff.len() as u16).to_be_bytes());
+        app1.extend(b"Exif\0\0");
+        app1.extend(tiff);
+        jpeg.splice(2..2, app1);
+
+        // 元の左端が上に来る
+        let decoded = open_image_oriented(&jpeg).unwrap().to_luma8();
+        assert_eq!(decoded.dimensions(), (8, 16));
+        assert!(decoded.get_pixel(4, 2).0[0] < 64);
+        assert!(decoded.get_pixel(4, 13).0[0] > 192);
+        let path =
+            std::env::temp_dir().join(format!("kenban_test_{}_exif.jpg", std::process::id()));
+        fs::write(&path, &jpeg).unwrap();
+        let dims = image_dimensions_oriented(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(dims.unwrap(), (8, 16));
+    }
+
+    #[test]
+    fn crop_bounds_validation() {
+        let crop = |left, top, right, bottom| CropBounds {
+            left,
+            top,
+            right,
+            bottom,
+        };
+        let invalid = |c: CropBounds| matches!(c.clamp_to(100, 50), Err(AppError::InvalidCrop(_)));
+        assert!(invalid(crop(60, 0, 40, 50))); // 反転
+        assert!(invalid(crop(0, 30, 100, 10)));
+        assert!(invalid(crop(20, 0, 20, 50))); // 面積ゼロ
+        assert!(invalid(crop(0, 10, 100, 10)));
+        assert!(invalid(crop(100, 0, 200, 50))); // 画像の完全な外側
+        assert!(invalid(crop(0, 60, 100, 90)));
+        // はみ出した分だけ画像サイズに収める
+        assert!(crop(10, 5, 300, 400).clamp_to(100, 50).ok() == Some(crop(10, 5, 100, 50)));
+        assert!(crop(0, 0, 100, 50).clamp_to(100, 50).ok() == Some(crop(0, 0, 100, 50)));
+    }
+
+    #[test]
+    fn degenerate_image_sizes() {
+        for (w, h) in [(0, 5), (5, 0), (0, 0)] {
+            let img = DynamicImage::new_rgba8(w, h);
+            assert!(!is_image_valid(&img));
+            assert!(matches!(
+                ensure_non_empty(img, "x"),
+                Err(AppError::EmptyImage(_))
+            ));
+        }
+        // 極端に細長い画像も縮小後に 0px にならない
+        assert_eq!(fit_within(1, 10_000, 100, 100), (1, 100));
+        assert_eq!(fit_within(10_000, 1, 100, 100), (100, 1));
+
+        // 1xN / Nx1 の差分がパニックせず、差分ピクセルを数える
+        for (w, h) in [(1, 7), (7, 1)] {
+            let img = ensure_non_empty(DynamicImage::new_rgba8(w, h), "x").unwrap();
+            let a = img.to_rgba8().into_raw();
+            let mut b = a.clone();
+            b[0..4].copy_from_slice(&[255, 255, 255, 255]);
+            let options = SimpleDiffOptions::default();
+            let (buf, count, _, _) = diff_simple_core(&a, &b, w, h, 0, &options, &[], true);
+            assert_eq!((buf.len(), count), (a.len(), 1));
+            let (_, count, _, _) =
+                diff_heatmap_core(&a, &b, w, h, 0, &[], &HeatmapParams::default());
+            assert_eq!(count, 1);
+        }
+    }
+
+    #[test]
+    fn downscaled_diff_keeps_single_pixel() {
+        // 黒地 10x10 に 1px だけ赤。5x 縮小しても赤が残り、差分のないブロックは背景のまま
+        let mut buf = [0, 0, 0, 255].repeat(100);
+        let (x, y) = (7, 3);
+        buf[(y * 10 + x) * 4..(y * 10 + x) * 4 + 4].copy_from_slice(&[255, 0, 0, 255]);
+        let pixels = [DiffPixel {
+            x: x as u32,
+            y: y as u32,
+        }];
+        let out = downscale_diff_buf(&buf, 10, 10, &pixels, 2, 2).unwrap();
+        assert_eq!(out[4..8], [255, 0, 0, 255]); // (1, 0)
+        for o in [0, 2, 3] {
+            assert_eq!(out[o * 4..o * 4 + 4], [0, 0, 0, 255]);
+        }
+        assert!(downscale_diff_buf(&buf, 10, 9, &pixels, 2, 2).is_err());
+    }
+
+    #[test]
+    fn ignored_markers_leave_no_id_gaps() {
+        let marker = |x: f64, count: u32| DiffMarker {
+            x,
+            y: 50.0,
+            radius: 10.0,
+            count,
+            bbox: MarkerBounds::default(),
+            id: 0,
+            kind: MarkerKind::Changed,
+        };
+        let mut markers = vec![marker(50.0, 3), marker(150.0, 2), marker(250.0, 1)];
+        sort_and_number_markers(&mut markers);
+        // 2番目のマーカーだけが除外領域に収まる
+        let ignore = [CropBounds {
+            left: 100,
+            top: 0,
+            right: 200,
+            bottom: 100,
+        }];
+        let kept = limit_markers(drop_ignored_markers(markers, &ignore), None, None);
+        let ids: Vec<_> = kept.iter().map(|m| (m.id, m.x)).collect();
+        assert_eq!(ids, [(1, 50.0), (2, 250.0)]);
+    }
+}
@@ -18,6 +18,7 @@ import TextVerifyViewer from './components/TextVerifyViewer';
 import { normalizeTextForComparison, computeLineSetDiff, computeSharedGroupDiff, findBestMemoSection, preNormalizeSections } from './utils/textExtract';
 import { parseMemo, matchPageToFile, getUniqueMemoSections, replaceMemoSection } from './utils/memoParser';
 import { useTextExtractWorker } from './hooks/useTextExtractWorker';
+import { errorMessage, isAppError } from './utils/error';
 import type { CompareMode, AppMode, FileWithPath, CropBounds, DiffMarker, DiffPart, FilePair, PageCache, ParallelFileEntry, ParallelImageCache, TextVerifyPage } from './types';
 
 
@@ -91,7 +92,7 @@ export default function MangaDiffDetector() {
     try {
       await invoke('open_file_in_photoshop', { path, photoshopPath });
     } catch (err) {
-      const message = typeof err === 'string' ? err : 'Photoshopの起動に失敗しました。';
+      const message = isAppError(err) || typeof err === 'string' ? errorMessage(err) : 'Photoshopの起動に失敗しました。';
       console.error('Failed to open in Photoshop:', err);
       window.alert(message);
     }
@@ -3459,7 +3460,7 @@ export default function MangaDiffDetector() {
                 invoke('open_pdf_in_mojiq', { pdfPath: file.path, page: file.pdfPage || 1 })
                   .catch((err: unknown) => {
                     console.error('[MojiQ] Error:', err);
-                    alert(`MojiQの起動に失敗しました:\n${err}`);
+                    alert(`MojiQの起動に失敗しました:\n${errorMessage(err)}`);
                   });
               }, 100);
             }
@@ -3556,7 +3557,7 @@ export default function MangaDiffDetector() {
             invoke('open_pdf_in_mojiq', { pdfPath: pdfFile.filePath, page: currentPage })
               .catch((err: unknown) => {
                 console.error('[MojiQ] Error:', err);
-                alert(`MojiQの起動に失敗しました:\n${err}`);
+                alert(`MojiQの起動に失敗しました:\n${errorMessage(err)}`);
               });
           }, 100);
         } else if (pdfFile) {
@@ -6,6 +6,7 @@ import {
 } from 'lucide-react';
 import { invoke } from '@tauri-apps/api/core';
 import type { CompareMode, AppMode, ViewMode, FileWithPath, FilePair, CropBounds, DiffMarker } from '../types';
+import { errorMessage } from '../utils/error';
 
 interface DiffViewerProps {
   isFullscreen: boolean;
@@ -216,7 +217,7 @@ const DiffViewer: React.FC<DiffViewerProps> = (props) => {
                         invoke('open_pdf_in_mojiq', { pdfPath: pdfFile.filePath, page: currentPage })
                           .catch((err: unknown) => {
                             console.error('[MojiQ] Error:', err);
-                            alert(`MojiQの起動に失敗しました:\n${err}`);
+                            alert(`MojiQの起動に失敗しました:\n${errorMessage(err)}`);
                           });
                       }, 100);
                     } else {
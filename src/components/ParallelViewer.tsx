@@ -17,6 +17,7 @@ import {
   EyeOff,
 } from 'lucide-react';
 import type { ParallelFileEntry } from '../types';
+import { errorMessage } from '../utils/error';
 
 interface ParallelViewerProps {
   isFullscreen: boolean;
@@ -389,7 +390,7 @@ const ParallelViewer: React.FC<ParallelViewerProps> = (props) => {
                               invoke('open_pdf_in_mojiq', { pdfPath: file.path, page: file.pdfPage || 1 })
                                 .catch((err: unknown) => {
                                   console.error('[MojiQ] Error:', err);
-                                  alert(`MojiQの起動に失敗しました:\n${err}`);
+                                  alert(`MojiQの起動に失敗しました:\n${errorMessage(err)}`);
                                 });
                             }, 100);
                           }
@@ -418,7 +419,7 @@ const ParallelViewer: React.FC<ParallelViewerProps> = (props) => {
                                   invoke('open_pdf_in_mojiq', { pdfPath: currentFileA.path, page: currentFileA.pdfPage || 1 })
                                     .catch((err: unknown) => {
                                       console.error('[MojiQ] Error:', err);
-                                      alert(`MojiQの起動に失敗しました:\n${err}`);
+                                      alert(`MojiQの起動に失敗しました:\n${errorMessage(err)}`);
                                     });
                                 }, 100);
                               }
@@ -438,7 +439,7 @@ const ParallelViewer: React.FC<ParallelViewerProps> = (props) => {
                                   invoke('open_pdf_in_mojiq', { pdfPath: currentFileB.path, page: currentFileB.pdfPage || 1 })
                                     .catch((err: unknown) => {
                                       console.error('[MojiQ] Error:', err);
-                                      alert(`MojiQの起動に失敗しました:\n${err}`);
+                                      alert(`MojiQの起動に失敗しました:\n${errorMessage(err)}`);
                                     });
                                 }, 100);
                               }
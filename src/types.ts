@@ -21,6 +21,9 @@ export interface DiffMarker {
   y: number;
   radius: number;
   count: number;
+  bbox?: CropBounds; // 差分ピクセルの外接矩形（right / bottom は含まない）
+  id?: number; // 1始まりの安定した番号（レポートの番号と一致）
+  kind?: 'added' | 'removed' | 'changed'; // A = 修正前・B = 修正後として、removed は B で要素が欠落
 }
 
 export interface FilePair {
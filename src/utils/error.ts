@@ -0,0 +1,22 @@
+// Rust コマンドが返すエラー（src-tauri の AppError と対応）
+export interface AppError {
+  code: 'io' | 'decode' | 'unsupported_format' | 'encode' | 'not_found' | 'size_mismatch' | 'timeout' | 'truncated' | 'invalid_crop' | 'empty_image' | 'other';
+  message: string;
+  // size_mismatch のときのみ [幅, 高さ]
+  sizeA?: [number, number];
+  sizeB?: [number, number];
+  // truncated のときのみ（途中までのファイル。時間をおいて再試行できる）
+  bytesRead?: number;
+  expected?: number;
+}
+
+export function isAppError(err: unknown): err is AppError {
+  return typeof err === 'object' && err !== null && 'code' in err && 'message' in err;
+}
+
+// invoke のエラーから表示用メッセージを取り出す
+export function errorMessage(err: unknown): string {
+  if (isAppError(err)) return err.message;
+  if (err instanceof Error) return err.message;
+  return String(err);
+}